@@ -1,17 +1,23 @@
 // TODO: use a cfg 3rd party crate
+use std::collections::HashMap;
 use std::fs;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
 use email_address::EmailAddress;
-use log::info;
+use log::{info, warn};
+use serde::de::{Deserialize, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use toml;
+use xshell::{cmd, Shell};
 
 use crate::utils::EmailAddressList;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cfg {
-    pub imap: ImapCfg,
+    #[serde(deserialize_with = "one_or_many")]
+    pub imap: Vec<ImapCfg>, // IMAP accounts, a single table is also accepted
+
     pub archive: ArchiveCfg,
     pub runtime: RuntimeCfg,
 }
@@ -24,6 +30,16 @@ impl Cfg {
         info!("loaded");
         Ok(cfg)
     }
+
+    /// Serialize effective configuration (defaults resolved) to TOML, with
+    /// passwords redacted.
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        let mut cfg = self.clone();
+        for imap in cfg.imap.iter_mut() {
+            imap.password = "***".to_string();
+        }
+        Ok(toml::to_string(&cfg)?)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,7 +47,32 @@ pub struct ImapCfg {
     pub host: String,
     pub port: u16,
     pub username: EmailAddress,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
+    pub password_command: Option<String>, // shell command whose stdout is used as password
+    #[serde(default)]
+    pub max_total_bytes: Option<u64>, // max total size of mails fetched per cycle, unlimited by default
+    #[serde(default)]
+    pub state_file: Option<String>, // file to persist UID state of mailbox
+    #[serde(default)]
+    pub expunge: ExpungePolicy, // when to expunge moved mails, see cfg::ExpungePolicy
+}
+
+impl ImapCfg {
+    /// Get password, run `password_command` if it is set.
+    pub fn password(&self) -> Result<String> {
+        let Some(command) = &self.password_command else {
+            return Ok(self.password.clone());
+        };
+        info!("reading password from command {:?}...", command);
+        let sh = Shell::new()?;
+        // Trailing newline is trimmed by xshell.
+        let password = cmd!(sh, "sh -c {command}").quiet().read()
+            .with_context(|| format!("failed to run password command {:?}", command))?;
+        info!("read");
+        Ok(password)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,32 +80,283 @@ pub struct ArchiveCfg {
     // Data directories.
     pub letter_dir: String,        // dir of structured love letters
     pub rstdoc_dir: String,        // dir of generated reStructuredText docs
+    #[serde(default)]
+    pub base_url: Option<String>, // URL of website built from rstdoc_dir, for linking letters and generating links.txt
     #[serde(default = "yes")]
     pub create_dirs: bool, // whether to create data dirs automaticlly, true by default
+    #[serde(default = "letter_filename_pattern")]
+    pub letter_filename_pattern: String, // see letter::FilenamePattern
+    #[serde(default = "index_filename")]
+    pub index_filename: String, // master_doc of Sphinx project, relative to rstdoc_dir
+    #[serde(default)]
+    pub index_recent: usize, // list N most recent letters on index, above the toctree
+    #[serde(default = "yes")]
+    pub generate_docs: bool, // whether to generate rstdoc, rstdoc_dir is not touched at all if false
+    #[serde(default = "outputs")]
+    pub outputs: Vec<OutputFormat>, // formats generated into rstdoc_dir, committed at once
+    #[serde(default = "yes")]
+    pub stamp_generated: bool, // prepend a comment of generation time to generated .rst/.md files, true by default
+    #[serde(default = "no")]
+    pub store_plaintext: bool, // store plaintext rendering of letter as sibling .txt file
+    #[serde(default)]
+    pub keep_versions: usize, // keep N previous versions of overwritten letter as untracked NAME.toml.bak.N, removed by git_pre_cleanup
+    #[serde(default = "i32_3")]
+    pub fs_retry: i32, // times to try filesystem writes, for network filesystems
+    #[serde(default)]
+    pub max_sections_per_page: Option<usize>, // split year page into parts when it has more letters
+    #[serde(default)]
+    pub frozen_years: Option<u32>, // pages of years older than N years ago are kept as is, only re-generated by --generate-rstdoc --full
+    #[serde(default)]
+    pub meimei_title_prefix: Option<String>, // prefix (e.g. emoji) of section title of letters from 妹妹
+    #[serde(default)]
+    pub gege_title_prefix: Option<String>, // prefix (e.g. emoji) of section title of letters from 哥哥
+    #[serde(default)]
+    pub untitled_title_template: Option<String>, // section title of untitled letters, "{date}" and "{author}" are replaced, the date alone by default
+    #[serde(default)]
+    pub content_class: Option<String>, // wrap letter content in div of this CSS class
+    #[serde(default)]
+    pub default_lang: Option<String>, // language tag of letters whose mails have no Content-Language header, like "zh-CN"
+    #[serde(default = "directive_name")]
+    pub directive_name: String, // name of the Sphinx directive each letter is rendered as
+    #[serde(default = "no")]
+    pub render_source: bool, // render IMAP account and folder of letter as :source:, off as the site may be public
+    #[serde(default)]
+    pub display_names: HashMap<String, String>, // canonical display names for rendering (and author of nameless allowed addresses), keyed by display name or email address
 
     // Git integration.
     #[serde(default = "yes")]
     pub git_no_push: bool, // whether to push changes to remote
     #[serde(default = "no")]
     pub git_pre_cleanup: bool, // clean up repo before any operation
+    #[serde(default = "no")]
+    pub git_dry_run: bool, // log git commands that mutate repo instead of running them
     #[serde(default = "i32_3")]
     pub git_retry: i32,
+    #[serde(default = "no")]
+    pub squash_import: bool, // commit letters imported by --import at once, authorship of letters is kept in commit body
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>, // changes are committed but not pushed during the window, pushed in the next cycle after it
+    #[serde(default)]
+    pub lock_file: Option<String>, // lock preventing multiple instances, "loveletter.lock" in .git dir of letter_dir by default
+
+    // Mail parsing.
+    #[serde(default = "yes")]
+    pub strict: bool, // whether to error on malformed subject, otherwise the mail is skipped
+    #[serde(default = "no")]
+    pub date_from_mail_fallback: bool, // take date from Date header if subject has none, the whole subject is title then
+    #[serde(default = "allowed_actions")]
+    pub allowed_actions: Vec<String>, // actions accepted in subject or action header, case-insensitive, "private" is always accepted
+    #[serde(default)]
+    pub action_header: Option<String>, // read action from this header rather than subject if present
+    #[serde(default)]
+    pub ignore_subjects: Vec<String>, // regexes (or plain substrings) of subjects to skip silently
+    #[serde(default = "no")]
+    pub skip_spam: bool, // skip mails flagged by X-Spam-Flag, X-Spam-Status or X-Spam header
+    #[serde(default = "no")]
+    pub interpret_markdown: bool, // render plain text body as Markdown if mail has no HTML body
+    #[serde(default = "text_part_separator")]
+    pub text_part_separator: String, // separator between parts of plain text body
+    #[serde(default = "no")]
+    pub trim_empty_html: bool, // shorthand of appending "trim_empty_html" to content_transforms
+    #[serde(default)]
+    pub content_transforms: Vec<String>, // applied to content in order, see transform::builtin
+    #[serde(default)]
+    pub prefer_body: HashMap<String, BodyKind>, // body used as content, keyed by sender's email address, the other is fallback
+    #[serde(default)]
+    pub body_content_id: Option<String>, // take content from MIME part of this Content-ID (e.g. "letter") if mail has it, precedes prefer_body
+    #[serde(default = "no")]
+    pub strip_signature: bool, // cut content from the "-- " signature delimiter
+    #[serde(default)]
+    pub signature_delimiters: HashMap<String, String>, // regex of line (or HTML block) starting signature, keyed by sender's email address, applied regardless of strip_signature
 
     // Permssion control.
     pub allowed_from_addrs: EmailAddressList,
     pub allowed_to_addrs: EmailAddressList,
+    #[serde(default = "no")]
+    pub accept_bcc: bool, // also check Delivered-To and friends against allowed_to_addrs
+    #[serde(default)]
+    pub unknown_author: Option<String>, // "妹妹" or "哥哥", author of allowed senders named otherwise, who are rejected by default
+    #[serde(default)]
+    pub on_disallowed: DisallowedPolicy, // what to do with mails from senders not in allowed_from_addrs
+    #[serde(default)]
+    pub quarantine_dir: Option<String>, // where raw mails are copied to by "quarantine" policy
+
+    // Per-recipient archives.
+    #[serde(default)]
+    pub routes: Vec<RouteCfg>, // letters to these recipients go to their own archives
+}
+
+impl ArchiveCfg {
+    /// Configuration of sub-archive of route, other options are inherited.
+    pub fn route_cfg(&self, route: &RouteCfg) -> ArchiveCfg {
+        ArchiveCfg {
+            letter_dir: route.letter_dir.clone(),
+            rstdoc_dir: route.rstdoc_dir.clone(),
+            base_url: route.base_url.clone().or(self.base_url.clone()),
+            lock_file: None,
+            routes: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Override data directories, for example from command line.
+    pub fn override_dirs(&mut self, letter_dir: Option<&str>, rstdoc_dir: Option<&str>) {
+        if let Some(x) = letter_dir {
+            info!("letter_dir is overridden: {} -> {}", self.letter_dir, x);
+            self.letter_dir = x.to_owned();
+        }
+        if let Some(x) = rstdoc_dir {
+            info!("rstdoc_dir is overridden: {} -> {}", self.rstdoc_dir, x);
+            self.rstdoc_dir = x.to_owned();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: NaiveTime, // like "23:00"
+    pub end: NaiveTime, // exclusive, wraps around midnight if earlier than start
+    #[serde(default = "utc", with = "fixed_offset")]
+    pub utc_offset: FixedOffset, // timezone of start and end, like "+08:00"
+}
+
+impl QuietHours {
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let t = now.with_timezone(&self.utc_offset).time();
+        match self.start <= self.end {
+            true => self.start <= t && t < self.end,
+            false => self.start <= t || t < self.end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisallowedPolicy {
+    #[default]
+    Error, // fail the upsert
+    Skip, // skip the mail silently
+    Quarantine, // copy raw mail to quarantine_dir and skip it
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpungePolicy {
+    #[default]
+    Immediate, // expunge mails right after they are moved
+    OnClose, // flag them as \Deleted and expunge when mailbox is closed
+    Never, // flag them as \Deleted only, so that they are still recoverable
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Rst, // Sphinx project: index, year pages and links.txt
+    Markdown, // a page per year in "markdown" sub-directory
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyKind {
+    Text, // plain text part, rendered as Markdown if interpret_markdown is true
+    Html, // HTML part
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteCfg {
+    pub to: EmailAddress, // must be in allowed_to_addrs
+    pub letter_dir: String,
+    pub rstdoc_dir: String,
+    #[serde(default)]
+    pub base_url: Option<String>, // base_url of archive is used if absent
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "RawRuntimeCfg")]
 pub struct RuntimeCfg {
-    #[serde(default = "u64_60")]
-    pub interval: u64, // interval for checking new mails, in seconds
+    pub fetch_interval: u64, // interval for checking new mails, in seconds
+    pub reconnect_interval: u64, // interval for reconnecting to IMAP servers, in seconds
+    pub jitter_ratio: f64, // randomize both intervals by ±ratio of them, in [0, 1]
+    pub regenerate_on_startup: bool, // re-generate rstdoc once before fetching, for letters added out-of-band
+    pub max_letters_per_cycle: Option<usize>, // upsert at most N mails per cycle, the rest are deferred to the next cycle
+}
+
+#[derive(Deserialize)]
+struct RawRuntimeCfg {
+    #[serde(default)]
+    interval: Option<u64>, // deprecated alias of both fetch_interval and reconnect_interval
+    #[serde(default)]
+    fetch_interval: Option<u64>,
+    #[serde(default)]
+    reconnect_interval: Option<u64>,
+    #[serde(default)]
+    jitter_ratio: f64,
+    #[serde(default = "no")]
+    regenerate_on_startup: bool,
+    #[serde(default)]
+    max_letters_per_cycle: Option<usize>,
+}
+
+impl From<RawRuntimeCfg> for RuntimeCfg {
+    fn from(raw: RawRuntimeCfg) -> RuntimeCfg {
+        if raw.interval.is_some() {
+            warn!("runtime.interval is deprecated, use runtime.fetch_interval and runtime.reconnect_interval instead");
+        }
+        if !(0.0..=1.0).contains(&raw.jitter_ratio) {
+            warn!("runtime.jitter_ratio {} is out of [0, 1], clamped", raw.jitter_ratio);
+        }
+        RuntimeCfg {
+            jitter_ratio: raw.jitter_ratio.clamp(0.0, 1.0),
+            fetch_interval: raw.fetch_interval.or(raw.interval).unwrap_or(60),
+            reconnect_interval: raw.reconnect_interval.or(raw.interval).unwrap_or(10),
+            regenerate_on_startup: raw.regenerate_on_startup,
+            max_letters_per_cycle: raw.max_letters_per_cycle,
+        }
+    }
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(x) => vec![x],
+        OneOrMany::Many(x) => x,
+    })
+}
+
+mod fixed_offset {
+    use chrono::FixedOffset;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(x: &FixedOffset, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(x)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FixedOffset, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
 }
 
 fn yes() -> bool { true }
 fn no() -> bool { false }
 fn i32_3() -> i32 { 3 }
-fn u64_60() -> u64 { 60 }
+fn allowed_actions() -> Vec<String> { vec!["edit".to_string()] }
+fn letter_filename_pattern() -> String { "{date}[_{title}]".to_string() }
+fn outputs() -> Vec<OutputFormat> { vec![OutputFormat::Rst] }
+fn index_filename() -> String { "index.rst".to_string() }
+fn directive_name() -> String { "loveletter".to_string() }
+fn text_part_separator() -> String { "\n".to_string() }
+fn utc() -> FixedOffset { FixedOffset::east_opt(0).unwrap() }
 
 #[cfg(test)]
 mod tests {
@@ -72,6 +364,96 @@ mod tests {
 
     #[test]
     fn test_cfg_load() {
-        let _ = Cfg::load("./test_data/config.toml").unwrap();
+        let cfg = Cfg::load("./test_data/config.toml").unwrap();
+        assert_eq!(cfg.imap.len(), 1);
+    }
+
+    #[test]
+    fn test_archive_cfg_override_dirs() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.override_dirs(Some("/tmp/x"), None);
+        assert_eq!((cfg.letter_dir.as_str(), cfg.rstdoc_dir.as_str()), ("/tmp/x", "./rst/"));
+        cfg.override_dirs(None, Some("/tmp/y"));
+        assert_eq!((cfg.letter_dir.as_str(), cfg.rstdoc_dir.as_str()), ("/tmp/x", "/tmp/y"));
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let q: QuietHours = toml::from_str("start = \"23:00\"\nend = \"07:00\"\nutc_offset = \"+08:00\"").unwrap();
+        assert!(q.contains(at("2025-04-03T23:00:00+08:00")));
+        assert!(q.contains(at("2025-04-03T16:00:00Z"))); // 00:00 +08:00
+        assert!(q.contains(at("2025-04-04T06:59:59+08:00")));
+        assert!(!q.contains(at("2025-04-04T07:00:00+08:00")));
+        assert!(!q.contains(at("2025-04-03T22:59:59+08:00")));
+        assert!(!q.contains(at("2025-04-03T23:30:00Z"))); // 07:30 +08:00
+
+        let q: QuietHours = toml::from_str("start = \"01:00\"\nend = \"05:30\"").unwrap();
+        assert_eq!(q.utc_offset, utc());
+        assert!(q.contains(at("2025-04-03T01:00:00Z")));
+        assert!(!q.contains(at("2025-04-03T05:30:00Z")));
+        assert!(!q.contains(at("2025-04-03T00:59:00Z")));
+        assert_eq!(toml::to_string(&q).unwrap(), "start = \"01:00:00\"\nend = \"05:30:00\"\nutc_offset = \"+00:00\"\n");
+        assert!(toml::from_str::<QuietHours>("start = \"01:00\"\nend = \"05:30\"\nutc_offset = \"CST\"").is_err());
+    }
+
+    #[test]
+    fn test_cfg_to_redacted_toml() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap();
+        let data = cfg.to_redacted_toml().unwrap();
+        assert!(!data.contains("p_a_s_s_w_o_r_d"));
+        assert!(data.contains("password = \"***\""));
+        assert!(data.contains("index_filename = \"index.rst\"")); // defaults are resolved
+
+        // Output is a valid configuration too.
+        let cfg2: Cfg = toml::from_str(&data).unwrap();
+        assert_eq!(cfg2.imap[0].password, "***");
+        assert_eq!(cfg2.imap[0].host, cfg.imap[0].host);
+    }
+
+    #[test]
+    fn test_cfg_password_command() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().imap.remove(0);
+        assert_eq!(cfg.password().unwrap(), "p_a_s_s_w_o_r_d");
+        cfg.password_command = Some("echo s3cret".to_string());
+        assert_eq!(cfg.password().unwrap(), "s3cret");
+        cfg.password_command = Some("echo s3cret && false".to_string());
+        assert!(cfg.password().is_err());
+    }
+
+    #[test]
+    fn test_cfg_runtime_interval() {
+        let cfg: RuntimeCfg = toml::from_str("").unwrap();
+        assert_eq!((cfg.fetch_interval, cfg.reconnect_interval), (60, 10));
+        assert!(!cfg.regenerate_on_startup);
+        let cfg: RuntimeCfg = toml::from_str("fetch_interval = 30\nreconnect_interval = 5").unwrap();
+        assert_eq!((cfg.fetch_interval, cfg.reconnect_interval), (30, 5));
+
+        // Deprecated alias.
+        let cfg: RuntimeCfg = toml::from_str("interval = 120").unwrap();
+        assert_eq!((cfg.fetch_interval, cfg.reconnect_interval), (120, 120));
+        let cfg: RuntimeCfg = toml::from_str("interval = 120\nreconnect_interval = 5").unwrap();
+        assert_eq!((cfg.fetch_interval, cfg.reconnect_interval), (120, 5));
+
+        assert_eq!(cfg.jitter_ratio, 0.0);
+        let cfg: RuntimeCfg = toml::from_str("jitter_ratio = 2.0").unwrap();
+        assert_eq!(cfg.jitter_ratio, 1.0);
+    }
+
+    #[test]
+    fn test_cfg_multiple_imap() {
+        let cfg_data = fs::read_to_string("./test_data/config.toml").unwrap();
+        let cfg_data = cfg_data.replacen("\n[imap]\n", "\n[[imap]]\n", 1).replacen("[archive]", "
+[[imap]]
+host = \"imap.example.org\"
+port = 993
+username = \"loveletter@example.org\"
+password = \"p_a_s_s_w_o_r_d\"
+
+[archive]", 1);
+        let cfg: Cfg = toml::from_str(&cfg_data).unwrap();
+        assert_eq!(cfg.imap.len(), 2);
+        assert_eq!(cfg.imap[0].host, "imap.example.com");
+        assert_eq!(cfg.imap[1].host, "imap.example.org");
     }
 }
@@ -0,0 +1,276 @@
+//! A single fetch → upsert → generate cycle, for embedders that drive
+//! loveletter from their own scheduler.
+
+use std::fmt;
+use std::ops::AddAssign;
+use std::process::ExitCode;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn, error};
+
+use crate::error::{Error, Result};
+use crate::letter::{Archive, Upserted};
+use crate::mail::MailSource;
+
+/// Counts of mails handled in a cycle.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CycleReport {
+    pub fetched: usize,
+    pub upserted: usize,
+    pub skipped: usize, // mails skipped, quarantined, or of unchanged letters
+    pub failed: usize, // mails failed to be parsed or upserted
+    pub last_error: Option<String>, // message of the last failure in cycle
+}
+
+impl CycleReport {
+    /// Failure if any mail failed, even though the others succeeded.
+    pub fn exit_code(&self) -> ExitCode {
+        match self.failed {
+            0 => ExitCode::SUCCESS,
+            _ => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl AddAssign for CycleReport {
+    fn add_assign(&mut self, other: CycleReport) {
+        self.fetched += other.fetched;
+        self.upserted += other.upserted;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.last_error = other.last_error.or(self.last_error.take());
+    }
+}
+
+impl fmt::Display for CycleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fetched={} upserted={} skipped={} failed={}",
+            self.fetched, self.upserted, self.skipped, self.failed)
+    }
+}
+
+/// Health of daemon across cycles, for alerting when letters stop flowing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Status {
+    pub last_error: Option<(DateTime<Utc>, String)>, // cleared by a fully successful cycle
+    pub last_upserted_at: Option<DateTime<Utc>>,
+}
+
+impl Status {
+    /// Update status with report of a cycle finished just now.
+    pub fn update(&mut self, report: &CycleReport) {
+        let now = Utc::now();
+        if report.upserted > 0 {
+            self.last_upserted_at = Some(now);
+        }
+        self.last_error = report.last_error.clone().map(|e| (now, e));
+    }
+}
+
+/// Fetch at most `limit` unseen mails from mailbox, upsert them to archive and
+/// re-generate rstdoc if any letter is upserted. Mails that are upserted or
+/// skipped are marked as seen, failed mails stay unseen and are retried, mails
+/// exceeding the limit are deferred to the next cycle.
+///
+/// Failure of fetching is returned so that caller can decide whether to
+/// reconnect, failures of individual mails are logged and counted.
+pub fn run_once(archive: &Archive, mailbox: &mut dyn MailSource, limit: Option<usize>) -> Result<CycleReport> {
+    if let Err(e) = archive.push_deferred() {
+        warn!("failed to push changes deferred by quiet hours: {:#}", e);
+    }
+    if limit == Some(0) {
+        info!("limit of mails per cycle is reached, deferred");
+        return Ok(CycleReport::default());
+    }
+    let raw_mails = mailbox.fetch_unseen(limit)?;
+    let mut report = CycleReport { fetched: raw_mails.len(), ..CycleReport::default() };
+
+    let mut seen = Vec::new();
+    for raw_mail in raw_mails.iter() {
+        match raw_mail.parse() {
+            Ok(parsed_mail) => match archive.upsert_letter(&parsed_mail) {
+                Ok(Upserted::Written(letter)) => {
+                    if let Some(url) = archive.letter_url(&letter) {
+                        info!("letter {} will be available at {}", letter, url);
+                    }
+                    seen.extend(raw_mail.uid);
+                    report.upserted += 1
+                },
+                Ok(Upserted::Skipped | Upserted::Quarantined(_)) => {
+                    seen.extend(raw_mail.uid);
+                    report.skipped += 1
+                },
+                Err(Error::Duplicate(p)) => {
+                    info!("letter {} is unchanged, skipped", p.display());
+                    seen.extend(raw_mail.uid);
+                    report.skipped += 1
+                },
+                Err(e) => {
+                    error!("failed to upsert letter: {}", e);
+                    report.failed += 1;
+                    report.last_error = Some(format!("failed to upsert letter: {}", e));
+                },
+            },
+            Err(e) => {
+                error!("failed to parse raw mail: {}", e);
+                report.failed += 1;
+                report.last_error = Some(format!("failed to parse raw mail: {}", e));
+            },
+        };
+    }
+    mailbox.mark_seen(&seen)?;
+    info!("cycle summary: {}", report);
+    if report.upserted == 0 {
+        info!("no letter upserted, skip rst generation");
+        return Ok(report);
+    }
+
+    match archive.generate_all() {
+        Ok(_) => (),
+        Err(e) => error!("failed to generate rstdoc: {}", e),
+    }
+    Ok(report)
+}
+
+/// Fetch seen mails from mailbox and upsert them again, for example, after a
+/// parsing bug is fixed. Letters are overwritten if their content changes,
+/// mails of the same letter are merged as [`Archive::import_letters`] does,
+/// so the latest edit wins regardless of fetching order.
+pub fn reprocess_seen(archive: &Archive, mailbox: &mut dyn MailSource) -> Result<CycleReport> {
+    let raw_mails = mailbox.fetch_seen()?;
+    let mut report = CycleReport { fetched: raw_mails.len(), ..CycleReport::default() };
+    let parsed_mails: Vec<_> = raw_mails
+        .iter()
+        .filter_map(|m| m.parse().map_err(|e| error!("failed to parse raw mail: {}", e)).ok())
+        .collect();
+    report.failed = raw_mails.len() - parsed_mails.len();
+    report.upserted = archive.import_letters(&parsed_mails)?.len();
+    report.skipped = parsed_mails.len() - report.upserted;
+    info!("reprocessed {} mails, {} letters updated", report.fetched, report.upserted);
+    if report.upserted > 0 {
+        archive.generate_all()?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use xshell::{cmd, Shell};
+    use crate::cfg::Cfg;
+    use crate::letter::tests::{mail_with_subject, tmp_archive};
+    use crate::mail::{RawMail, VecMailSource};
+
+    #[test]
+    fn test_run_once() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.strict = false; // skip mails with malformed subject
+        let (archive, _tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg);
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let ignored = mail_with_subject("Out of office");
+        let mut mailbox = VecMailSource::new(vec![
+            RawMail::new(&data),
+            RawMail::new(&data), // duplicate
+            RawMail::new(""),
+            RawMail::new(&ignored),
+        ]);
+        let report = run_once(&archive, &mut mailbox, None).unwrap();
+        assert_eq!(report, CycleReport{
+            fetched: 4, upserted: 1, skipped: 2, failed: 1,
+            last_error: Some("failed to parse raw mail: parse failed".to_string()),
+        });
+        assert_eq!(report.to_string(), "fetched=4 upserted=1 skipped=2 failed=1");
+        assert_eq!(report.exit_code(), ExitCode::FAILURE);
+        assert_eq!(mailbox.seen, vec![1, 2, 4]);
+        assert!(tmp_rstdoc_dir.path().join("2025.rst").exists());
+        assert!(fs::read_to_string(tmp_rstdoc_dir.path().join("2025.rst")).unwrap().contains("测试数据"));
+
+        // Only the failed mail is fetched again.
+        let report = run_once(&archive, &mut mailbox, None).unwrap();
+        assert_eq!((report.fetched, report.failed), (1, 1));
+        assert_eq!(mailbox.seen, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn test_run_once_limit() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let mails = ["2025/04/03", "2025/04/04", "2025/04/05"]
+            .iter()
+            .map(|d| RawMail::new(&mail_with_subject(d)))
+            .collect();
+        let mut mailbox = VecMailSource::new(mails);
+        let letters = || fs::read_dir(tmp_letter_dir.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "toml"))
+            .count();
+
+        let report = run_once(&archive, &mut mailbox, Some(2)).unwrap();
+        assert_eq!(report, CycleReport{ fetched: 2, upserted: 2, skipped: 0, failed: 0, ..CycleReport::default() });
+        assert_eq!(mailbox.seen, vec![1, 2]); // the deferred one stays unseen
+        assert_eq!(letters(), 2);
+        let report = run_once(&archive, &mut mailbox, Some(2)).unwrap();
+        assert_eq!(report, CycleReport{ fetched: 1, upserted: 1, skipped: 0, failed: 0, ..CycleReport::default() });
+        assert_eq!(mailbox.seen, vec![1, 2, 3]);
+        assert_eq!(letters(), 3);
+    }
+
+    #[test]
+    fn test_cycle_report_add() {
+        let mut report = CycleReport{ fetched: 2, upserted: 2, skipped: 0, failed: 0, ..CycleReport::default() };
+        assert_eq!(report.exit_code(), ExitCode::SUCCESS);
+        report += CycleReport{ fetched: 3, upserted: 1, skipped: 1, failed: 1, ..CycleReport::default() };
+        assert_eq!(report, CycleReport{ fetched: 5, upserted: 3, skipped: 1, failed: 1, ..CycleReport::default() });
+        assert_eq!(report.exit_code(), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn test_status() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let mut status = Status::default();
+
+        let mut mailbox = VecMailSource::new(vec![RawMail::new(&data), RawMail::new("")]);
+        status.update(&run_once(&archive, &mut mailbox, None).unwrap());
+        let (failed_at, msg) = status.last_error.clone().unwrap();
+        assert!(msg.starts_with("failed to parse raw mail"));
+        let upserted_at = status.last_upserted_at.unwrap();
+        assert!(upserted_at <= failed_at);
+
+        // Nothing new, but nothing failed either.
+        let mut mailbox = VecMailSource::new(vec![RawMail::new(&data)]);
+        status.update(&run_once(&archive, &mut mailbox, None).unwrap());
+        assert_eq!(status, Status{ last_error: None, last_upserted_at: Some(upserted_at) });
+    }
+
+    #[test]
+    fn test_reprocess_seen() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let mut mailbox = VecMailSource::new(vec![RawMail::new(&data)]);
+        run_once(&archive, &mut mailbox, None).unwrap();
+        let letter = fs::read_dir(tmp_letter_dir.path()).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|x| x == "toml"))
+            .unwrap();
+
+        // Simulate a letter archived by a buggy version.
+        let archived = fs::read_to_string(&letter).unwrap();
+        fs::write(&letter, archived.replace("张同学", "???")).unwrap();
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_letter_dir.path());
+        cmd!(sh, "git commit --all --message buggy").run().unwrap();
+        assert_eq!(run_once(&archive, &mut mailbox, None).unwrap(), CycleReport::default());
+
+        let report = reprocess_seen(&archive, &mut mailbox).unwrap();
+        assert_eq!(report, CycleReport{ fetched: 1, upserted: 1, skipped: 0, failed: 0, ..CycleReport::default() });
+        assert_eq!(fs::read_to_string(&letter).unwrap(), archived);
+
+        // Unchanged letters are left untouched.
+        let report = reprocess_seen(&archive, &mut mailbox).unwrap();
+        assert_eq!(report, CycleReport{ fetched: 1, upserted: 0, skipped: 1, failed: 0, ..CycleReport::default() });
+    }
+}
@@ -0,0 +1,97 @@
+//! Typed errors of the library surface, so that embedders can match on
+//! specific failures. Internal code still uses [`anyhow`], errors are
+//! classified where they happened and recovered by [`From<anyhow::Error>`].
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to authenticate with IMAP server.
+    Auth(imap::Error),
+    /// Other IMAP failures.
+    Imap(imap::Error),
+    /// Malformed mail, subject or letter file.
+    Parse(String),
+    /// Failed to run git command.
+    Git(Box<dyn StdError + Send + Sync>),
+    /// Sender, recipient or action is not allowed.
+    NotAllowed(String),
+    /// Letter to be written is identical to the existing one.
+    Duplicate(PathBuf),
+    Io(io::Error),
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Auth(e) => write!(f, "failed to authenticate: {}", e),
+            Error::Imap(e) => write!(f, "IMAP error: {}", e),
+            Error::Parse(msg) => write!(f, "{}", msg),
+            Error::Git(e) => write!(f, "git error: {}", e),
+            Error::NotAllowed(msg) => write!(f, "{}", msg),
+            Error::Duplicate(p) => write!(f, "letter {} is unchanged", p.display()),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Auth(e) | Error::Imap(e) => Some(e),
+            Error::Git(e) => Some(e.as_ref()),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<imap::Error> for Error {
+    fn from(e: imap::Error) -> Error {
+        Error::Imap(e)
+    }
+}
+
+impl From<xshell::Error> for Error {
+    fn from(e: xshell::Error) -> Error {
+        Error::Git(Box::new(e))
+    }
+}
+
+/// Recover the typed error if any, context of anyhow is dropped then.
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Error {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_from_anyhow() {
+        let e: anyhow::Error = Err::<(), _>(Error::NotAllowed("sender foo not in allowed list".to_string()))
+            .context("failed to upsert letter")
+            .unwrap_err();
+        assert!(matches!(Error::from(e), Error::NotAllowed(x) if x == "sender foo not in allowed list"));
+        assert!(matches!(Error::from(anyhow::anyhow!("oops")), Error::Other(_)));
+    }
+}
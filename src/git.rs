@@ -1,14 +1,16 @@
 use std::fs;
 use std::path::{Path, PathBuf};
-use log::{debug, warn};
+use log::{debug, info, warn};
 
-use anyhow::{Result, bail};
-use xshell::{cmd, Shell};
+use xshell::{cmd, Cmd, Shell};
 use email_address::EmailAddress;
 
+use crate::error::{Error, Result};
+
 pub struct Repo {
     prefix: PathBuf,
     sh: Shell,
+    dry_run: bool, // log mutating commands instead of running them
 }
 
 impl Repo {
@@ -17,48 +19,114 @@ impl Repo {
         let sh = Shell::new()?;
         sh.change_dir(&prefix);
         cmd!(sh, "git init").run()?;
-        Ok(Repo { prefix, sh })
+        Ok(Repo { prefix, sh, dry_run: false })
     }
 
     pub fn load<P: AsRef<Path>>(prefix: P) -> Result<Repo> {
         debug!("loading git repository from {}...", prefix.as_ref().display());
         let sh = Shell::new()?;
         sh.change_dir(&prefix);
+        cmd!(sh, "git rev-parse --is-inside-work-tree").quiet().ignore_stdout().run()
+            .map_err(|e| Error::Git(format!("{} is not a git repository: {}", prefix.as_ref().display(), e).into()))?;
         debug!("git repository {} loaded", fs::canonicalize(&prefix)?.display());
         Ok(Repo { 
             prefix: prefix.as_ref().to_path_buf(),
             sh,
+            dry_run: false,
         })
     }
 
+    /// In dry-run mode, commands that mutate the repository (add, rm, commit,
+    /// push and cleanup) are logged without being executed.
+    pub fn dry_run(mut self, dry_run: bool) -> Repo {
+        self.dry_run = dry_run;
+        self
+    }
+
+    fn run(&self, cmd: Cmd) -> Result<()> {
+        if self.dry_run {
+            info!("[dry-run] {}", cmd);
+            return Ok(());
+        }
+        cmd.run()?;
+        Ok(())
+    }
+
     pub fn add<P: AsRef<Path>>(&self, spec: P) -> Result<()> {
-        let spec = spec.as_ref();
+        let spec = self.relative_spec(spec.as_ref())?;
+        self.run(cmd!(self.sh, "git add {spec}"))
+    }
+
+    /// Remove file from both index and work tree, untracked file is removed too.
+    pub fn rm<P: AsRef<Path>>(&self, spec: P) -> Result<()> {
+        let path = spec.as_ref();
+        let spec = self.relative_spec(path)?;
+        self.run(cmd!(self.sh, "git rm --quiet --ignore-unmatch -- {spec}"))?;
+        if self.dry_run {
+            return Ok(());
+        }
+        let path = match path.is_relative() && !path.starts_with(&self.prefix) {
+            true => self.prefix.join(path),
+            false => path.to_path_buf(),
+        };
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Remove file from index only, the work tree is left untouched.
+    pub fn unstage<P: AsRef<Path>>(&self, spec: P) -> Result<()> {
+        let spec = self.relative_spec(spec.as_ref())?;
+        self.run(cmd!(self.sh, "git rm --cached --quiet --ignore-unmatch -- {spec}"))
+    }
+
+    fn relative_spec(&self, spec: &Path) -> Result<String> {
         let spec = match spec.starts_with(&self.prefix) {
-            true => spec.strip_prefix(&self.prefix)?.to_path_buf(),
+            true => spec.strip_prefix(&self.prefix).unwrap().to_path_buf(),
             false => spec.to_path_buf(),
         };
 
-        let spec = spec
+        Ok(spec
             .into_os_string()
             .into_string()
-            .unwrap();
-        cmd!(self.sh, "git add {spec}").run()?;
-        Ok(())
+            .unwrap())
     }
 
     pub fn commit(&self, msg: &str, author: Option<EmailAddress>) -> Result<()> {
         match author {
             Some(author) => {
                 let author = author.to_string();
-                cmd!(self.sh, "git commit --message {msg} --author {author}").run()?;
+                self.run(cmd!(self.sh, "git commit --message {msg} --author {author}"))
             },
-            None => cmd!(self.sh, "git commit --message {msg}").run()?,
+            None => self.run(cmd!(self.sh, "git commit --message {msg}")),
+        }
+    }
+
+    /// Absolute path of the `.git` directory.
+    pub fn git_dir(&self) -> Result<PathBuf> {
+        let dir = cmd!(self.sh, "git rev-parse --absolute-git-dir").quiet().read()?;
+        Ok(PathBuf::from(dir))
+    }
+
+    /// Whether there are changes staged to be committed.
+    pub fn has_staged_changes(&self) -> Result<bool> {
+        let output = cmd!(self.sh, "git diff --cached --quiet").ignore_status().output()?;
+        match output.status.code() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(Error::Git(format!(
+                "failed to check staged changes: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ).into())),
         }
-        
-        Ok(())
     }
 
     pub fn push(&self, retry: i32) -> Result<()> {
+        if self.dry_run {
+            self.run(cmd!(self.sh, "git pull --rebase"))?;
+            return self.run(cmd!(self.sh, "git push"));
+        }
         for i in 0..retry {
             match cmd!(self.sh, "git pull --rebase").run() {
                 Ok(_) => break,
@@ -66,7 +134,7 @@ impl Repo {
                     let msg = "failed to pull from remote";
                     warn!("{}: {} ({}/{})", msg, e, i+1, retry);
                     if i == retry - 1 {
-                        bail!(msg);
+                        return Err(Error::Git(msg.into()));
                     }
                 }
             }
@@ -78,7 +146,7 @@ impl Repo {
                     let msg = "failed to push to remote";
                     warn!("{}: {} ({}/{})", msg, e, i+1, retry);
                     if i == retry - 1 {
-                        bail!(msg);
+                        return Err(Error::Git(msg.into()));
                     }
                 }
             }
@@ -88,8 +156,7 @@ impl Repo {
 
     /// Ensure the repository is clean and up-to-date that can be pushed changes.
     pub fn cleanup(&self) -> Result<()> {
-        cmd!(self.sh, "git clean -d --force").run()?;
-        cmd!(self.sh, "git reset --hard HEAD").run()?;
-        Ok(())
+        self.run(cmd!(self.sh, "git clean -d --force"))?;
+        self.run(cmd!(self.sh, "git reset --hard HEAD"))
     }
 }
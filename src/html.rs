@@ -0,0 +1,408 @@
+//! Minimal HTML utilities for mail bodies, which are usually simple enough
+//! that a full-featured HTML parser is overkill.
+
+/// Elements that start a new line when converting to text.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address", "article", "blockquote", "br", "dd", "div", "dl", "dt", "footer",
+    "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "li", "ol", "p", "pre",
+    "section", "table", "tr", "ul",
+];
+
+/// Elements without closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta",
+    "source", "track", "wbr",
+];
+
+/// Elements that are visible even without any text.
+const MEDIA_ELEMENTS: &[&str] = &[
+    "audio", "embed", "hr", "iframe", "img", "object", "picture", "svg", "video",
+];
+
+/// Elements whose content is not visible.
+const INVISIBLE_ELEMENTS: &[&str] = &["head", "script", "style", "title"];
+
+/// Convert HTML to plain text, block elements are converted to line breaks,
+/// and link targets are appended to link texts like `text <URL>`.
+pub fn to_text(html: &str) -> String {
+    let mut buf = String::new();
+    let mut invisible: Option<String> = None;
+    let mut link: Option<(String, usize)> = None; // (href, start of link text in buf)
+    let mut rest = html;
+    while !rest.is_empty() {
+        let (text, tag) = match rest.find('<') {
+            Some(i) => {
+                let end = rest[i..].find('>').map(|j| i + j + 1).unwrap_or(rest.len());
+                let tag = &rest[i..end];
+                let text = &rest[..i];
+                rest = &rest[end..];
+                (text, Some(tag))
+            },
+            None => {
+                let text = rest;
+                rest = "";
+                (text, None)
+            },
+        };
+
+        if invisible.is_none() {
+            // Newlines in HTML source are just whitespaces.
+            let text = decode_entities(text).replace(['\r', '\n'], " ");
+            match buf.is_empty() || buf.ends_with('\n') {
+                true => buf.push_str(text.trim_start_matches(' ')),
+                false => buf.push_str(&text),
+            }
+        }
+
+        let Some(tag) = tag else { continue };
+        let name = tag_name(tag);
+        let closing = tag.starts_with("</");
+        match &invisible {
+            Some(x) => {
+                if closing && *x == name {
+                    invisible = None;
+                }
+            },
+            None => {
+                if INVISIBLE_ELEMENTS.contains(&name.as_str()) && !closing && !tag.ends_with("/>") {
+                    invisible = Some(name);
+                } else if name == "a" {
+                    if !closing {
+                        link = attr(tag, "href").map(|x| (x, buf.len()));
+                    } else if let Some((href, start)) = link.take() {
+                        if buf[start..].trim() != href && !href.starts_with('#') {
+                            buf.push_str(&format!(" <{}>", href));
+                        }
+                    }
+                } else if name == "br"
+                    || (BLOCK_ELEMENTS.contains(&name.as_str()) && !buf.is_empty() && !buf.ends_with('\n')) {
+                    buf.push('\n');
+                }
+            },
+        }
+    }
+    buf
+}
+
+/// Remove leading and trailing top-level nodes without visible content from
+/// HTML fragment, like the `<div><br></div>` padding added by mail clients.
+/// Empty nodes between visible ones are kept as they are.
+pub fn trim_empty_blocks(html: &str) -> String {
+    let nodes = top_level_nodes(html);
+    let is_empty = |node: &&str| {
+        let mut rest = *node;
+        while let Some(i) = rest.find('<') {
+            rest = &rest[i + 1..];
+            if MEDIA_ELEMENTS.contains(&tag_name(rest).as_str()) {
+                return false;
+            }
+        }
+        to_text(node).trim_matches(|c: char| c.is_whitespace()).is_empty()
+    };
+    let start = nodes.iter().position(|x| !is_empty(x)).unwrap_or(nodes.len());
+    let end = nodes.iter().rposition(|x| !is_empty(x)).map_or(start, |i| i + 1);
+    nodes[start..end].concat()
+}
+
+/// Cut signature from the first top-level node whose text is a signature
+/// delimiter, content of `<body>` is cut if html is a full document.
+pub fn strip_signature<F: Fn(&str) -> bool>(html: &str, is_delimiter: F) -> String {
+    let inner = body_inner(html);
+    let start = inner.as_ptr() as usize - html.as_ptr() as usize;
+    let nodes = top_level_nodes(inner);
+    let end = nodes.iter().position(|x| is_delimiter(&to_text(x))).unwrap_or(nodes.len());
+    html[..start].to_owned() + &nodes[..end].concat() + &html[start + inner.len()..]
+}
+
+/// Split HTML fragment into top-level text and element nodes, an unclosed
+/// element takes the rest.
+fn top_level_nodes(html: &str) -> Vec<&str> {
+    let mut nodes = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut i = 0;
+    while i < html.len() {
+        let Some(j) = html[i..].find('<').map(|j| i + j) else { break };
+        if depth == 0 && j > start {
+            nodes.push(&html[start..j]);
+            start = j;
+        }
+        let end = html[j..].find('>').map(|k| j + k + 1).unwrap_or(html.len());
+        let tag = &html[j..end];
+        if tag.starts_with("</") {
+            depth = (depth - 1).max(0);
+        } else if !tag.ends_with("/>") && !tag.starts_with("<!") && !VOID_ELEMENTS.contains(&tag_name(tag).as_str()) {
+            depth += 1;
+        }
+        if depth == 0 {
+            nodes.push(&html[start..end]);
+            start = end;
+        }
+        i = end;
+    }
+    if start < html.len() {
+        nodes.push(&html[start..]);
+    }
+    nodes
+}
+
+/// Remove elements of given names along with their content.
+pub fn remove_elements(html: &str, names: &[&str]) -> String {
+    let mut buf = String::new();
+    let mut removing: Option<(String, usize)> = None; // (name, depth)
+    let mut rest = html;
+    while let Some(i) = rest.find('<') {
+        let end = rest[i..].find('>').map(|j| i + j + 1).unwrap_or(rest.len());
+        let tag = &rest[i..end];
+        let name = tag_name(tag);
+        let closing = tag.starts_with("</");
+        let void = tag.ends_with("/>") || VOID_ELEMENTS.contains(&name.as_str());
+        match &mut removing {
+            Some((x, depth)) => if *x == name && !void {
+                match closing {
+                    true => *depth -= 1,
+                    false => *depth += 1,
+                }
+                if *depth == 0 {
+                    removing = None;
+                }
+            },
+            None => {
+                buf.push_str(&rest[..i]);
+                if !names.contains(&name.as_str()) {
+                    buf.push_str(tag);
+                } else if !closing && !void {
+                    removing = Some((name, 1));
+                }
+            },
+        }
+        rest = &rest[end..];
+    }
+    if removing.is_none() {
+        buf.push_str(rest);
+    }
+    buf
+}
+
+/// Remove active content: scripts, styles, embedded objects, event handler
+/// attributes and `javascript:` URLs.
+pub fn sanitize(html: &str) -> String {
+    let html = remove_elements(html, &["script", "style", "iframe", "object", "embed"]);
+    let mut buf = String::new();
+    let mut rest = html.as_str();
+    while let Some(i) = rest.find('<') {
+        let end = rest[i..].find('>').map(|j| i + j + 1).unwrap_or(rest.len());
+        buf.push_str(&rest[..i]);
+        buf.push_str(&sanitize_tag(&rest[i..end]));
+        rest = &rest[end..];
+    }
+    buf.push_str(rest);
+    buf
+}
+
+/// Drop unsafe attributes of tag like `<a href="..." onclick="...">`.
+fn sanitize_tag(tag: &str) -> String {
+    if tag.starts_with("</") || tag.starts_with("<!") || !tag.ends_with('>') {
+        return tag.to_owned();
+    }
+    let inner = &tag[1..tag.len() - 1];
+    let name_len = inner.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(inner.len());
+    let mut buf = format!("<{}", &inner[..name_len]);
+    let mut rest = &inner[name_len..];
+    let mut dropped = false;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() || rest == "/" {
+            break;
+        }
+        let name_end = rest.find(|c: char| c.is_whitespace() || c == '=' || c == '/').unwrap_or(rest.len()).max(1);
+        let name = rest[..name_end].to_ascii_lowercase();
+        let mut end = name_end;
+        let mut value = "";
+        if rest[end..].trim_start().starts_with('=') {
+            let after = rest[end..].trim_start()[1..].trim_start();
+            let start = rest.len() - after.len();
+            end = match after.chars().next() {
+                Some(q @ ('"' | '\'')) => after[1..].find(q).map(|j| start + j + 2).unwrap_or(rest.len()),
+                _ => after.find(char::is_whitespace).map(|j| start + j).unwrap_or(rest.len()),
+            };
+            value = rest[start..end].trim_matches(['"', '\'']);
+        }
+        let unsafe_value = decode_entities(value).trim().to_ascii_lowercase().starts_with("javascript:");
+        match name.starts_with("on") || unsafe_value {
+            true => dropped = true,
+            false => {
+                buf.push(' ');
+                buf.push_str(&rest[..end]);
+            },
+        }
+        rest = &rest[end..];
+    }
+    if !dropped {
+        return tag.to_owned();
+    }
+    if rest == "/" {
+        buf.push_str(" /");
+    }
+    buf.push('>');
+    buf
+}
+
+/// Lowercased element name of tag like `<div class="foo">` or `</div>`.
+fn tag_name(tag: &str) -> String {
+    tag.trim_start_matches('<')
+        .trim_start_matches('/')
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Value of attribute in tag like `<a href="foo">`.
+fn attr(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut rest = &tag[lower.find(&format!(" {}=", name))? + name.len() + 2..];
+    let value = match rest.chars().next()? {
+        q @ ('"' | '\'') => {
+            rest = &rest[1..];
+            &rest[..rest.find(q)?]
+        },
+        _ => &rest[..rest.find([' ', '>', '/']).unwrap_or(rest.len())],
+    };
+    Some(decode_entities(value))
+}
+
+/// Convert plain text to HTML, blank lines separate paragraphs and other
+/// line breaks are kept.
+pub fn from_text(text: &str) -> String {
+    text.split("\n\n")
+        .map(|x| x.trim_matches('\n'))
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| format!("<p>{}</p>\n", escape(x).replace('\n', "<br />\n")))
+        .collect()
+}
+
+/// Content of `<body>` element if html is a full document, otherwise html
+/// itself.
+pub fn body_inner(html: &str) -> &str {
+    let lower = html.to_ascii_lowercase();
+    let Some(start) = lower.find("<body").and_then(|i| lower[i..].find('>').map(|j| i + j + 1)) else {
+        return html;
+    };
+    let end = lower.rfind("</body").filter(|&i| i >= start).unwrap_or(html.len());
+    &html[start..end]
+}
+
+/// Escape text for HTML content and attribute values.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Decode common named and numeric character references.
+pub fn decode_entities(s: &str) -> String {
+    let mut buf = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        buf.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest.find(';').and_then(|j| {
+            let c = match &rest[1..j] {
+                "nbsp" => '\u{a0}',
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                x => match x.strip_prefix("#x").or(x.strip_prefix("#X")) {
+                    Some(hex) => char::from_u32(u32::from_str_radix(hex, 16).ok()?)?,
+                    None => char::from_u32(x.strip_prefix('#')?.parse().ok()?)?,
+                },
+            };
+            Some((c, j))
+        });
+        match decoded {
+            Some((c, j)) => {
+                buf.push(c);
+                rest = &rest[j + 1..];
+            },
+            None => {
+                buf.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    buf.push_str(rest);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_text() {
+        assert_eq!(to_text("<div>foo</div><div>bar<br>baz</div>"), "foo\nbar\nbaz\n");
+        assert_eq!(to_text("<p>a &amp; b</p>\n<p>&lt;c&#62; &#x4e2d;</p>"), "a & b\n<c> 中\n");
+        assert_eq!(to_text("<style>p { color: red; }</style><p>foo</p>"), "foo\n");
+        assert_eq!(to_text("foo & bar"), "foo & bar");
+        assert_eq!(
+            to_text("<p>see <a href=\"https://example.com/?a=1&amp;b=2\">here</a></p><p><a href='https://example.com'>https://example.com</a></p>"),
+            "see here <https://example.com/?a=1&b=2>\nhttps://example.com\n");
+    }
+
+    #[test]
+    fn test_from_text() {
+        assert_eq!(from_text("foo\nbar\n\n\n<baz>\n"), "<p>foo<br />\nbar</p>\n<p>&lt;baz&gt;</p>\n");
+    }
+
+    #[test]
+    fn test_remove_elements() {
+        assert_eq!(
+            remove_elements("<p>hi</p><blockquote>a<blockquote>b</blockquote>c</blockquote><br><p>bye</p>", &["blockquote"]),
+            "<p>hi</p><br><p>bye</p>"
+        );
+        assert_eq!(remove_elements("<p>foo<script>bar", &["script"]), "<p>foo");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(
+            sanitize("<div class=\"x\" onclick=\"evil()\"><a href='javascript:evil()' title=t>foo</a><img src=\"cid:1\" onerror=evil() /></div><script>evil()</script><style>p {}</style>"),
+            "<div class=\"x\"><a title=t>foo</a><img src=\"cid:1\" /></div>"
+        );
+        assert_eq!(sanitize("<p>a &lt; b</p><br/>"), "<p>a &lt; b</p><br/>");
+    }
+
+    #[test]
+    fn test_body_inner() {
+        assert_eq!(body_inner("<HTML><head></head><Body class=\"x\"><p>foo</p></BODY></HTML>"), "<p>foo</p>");
+        assert_eq!(body_inner("<html><body><p>foo</p>"), "<p>foo</p>");
+        assert_eq!(body_inner("<p>foo</p>"), "<p>foo</p>");
+    }
+
+    #[test]
+    fn test_trim_empty_blocks() {
+        assert_eq!(
+            trim_empty_blocks("<div><br></div>\n<div>&nbsp;</div><p>foo</p><div><br /></div><p>bar</p>\n<br><div><div> </div></div>\n"),
+            "<p>foo</p><div><br /></div><p>bar</p>"
+        );
+        assert_eq!(trim_empty_blocks("<div><br></div><div><img src=\"cid:1\"></div><hr>"), "<div><img src=\"cid:1\"></div><hr>");
+        assert_eq!(trim_empty_blocks("foo <b>bar</b>"), "foo <b>bar</b>");
+        assert_eq!(trim_empty_blocks("<div><br></div>"), "");
+        assert_eq!(trim_empty_blocks("<div>foo"), "<div>foo");
+    }
+
+    #[test]
+    fn test_strip_signature() {
+        let is_delimiter = |x: &str| x.trim() == "--";
+        assert_eq!(strip_signature("<div>foo</div><div>--&nbsp;</div><div>bar</div>", is_delimiter), "<div>foo</div>");
+        assert_eq!(strip_signature("<p>foo</p>\n<p>-- <br>bar</p>", is_delimiter), "<p>foo</p>\n<p>-- <br>bar</p>");
+        assert_eq!(
+            strip_signature("<html><body><p>foo</p><p>--</p><p>bar</p></body></html>", is_delimiter),
+            "<html><body><p>foo</p></body></html>"
+        );
+    }
+}
@@ -1,25 +1,36 @@
+use std::borrow::Cow;
 use std::fs;
-use std::io;
-use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::io::{self, Write};
+use std::cell::{Cell, OnceCell};
+use std::path::{Component, Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::OsStr;
 use std::str::pattern::Pattern;
+use std::str::FromStr;
 use std::fmt;
+use std::cmp;
 
 use anyhow::{bail, Context, Result};
-use chrono::{DateTime, NaiveDate, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
 use email_address::EmailAddress;
 use log::{debug, info, warn, error};
 use serde_derive::{Deserialize, Serialize};
 use serde::ser;
 use serde::de;
 use toml;
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use regex::{Regex, RegexSet};
+use xshell::{cmd, Shell};
 
-use crate::cfg::ArchiveCfg;
-use crate::mail::ParsedMail;
+use crate::cfg::{ArchiveCfg, BodyKind, DisallowedPolicy, OutputFormat};
+use crate::error::{self, Error};
+use crate::mail::{Attachment, ParsedMail};
 use crate::git::Repo;
+use crate::html;
+use crate::markdown;
+use crate::transform::{Pipeline, TrimEmptyHtml};
+use crate::utils::{docutils_width, hash, human_size, retry_fs, slugify, write_atomic};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct LoveLetter {
@@ -27,6 +38,14 @@ pub struct LoveLetter {
     from: EmailAddress,
     to: EmailAddress,
     from_meimei_if_true_and_gege_if_false: bool,
+    // Stable ID for linking, kept when letter is edited, see LoveLetter::id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    // Where the mail is fetched from, absent for imported mails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_account: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_folder: Option<String>,
 
     created_at: Option<DateTime<Utc>>,
     updated_at: Option<DateTime<Utc>>,
@@ -34,13 +53,25 @@ pub struct LoveLetter {
     // Content.
     date: Date,
     title: Option<String>,
+    // Mail subject as it is, for tracing how date, title and action are parsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_subject: Option<String>,
     content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>, // metadata only, content of attachments is not saved
+    // Excluded from outputs unless GenerateOptions::include_private, kept when
+    // letter is edited.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    private: bool,
+    // Language tag like "zh-CN", from Content-Language header or default_lang.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lang: Option<String>,
 }
 
 impl LoveLetter {
     fn load<P: AsRef<Path>>(p: P) -> Result<LoveLetter> {
         let data = fs::read_to_string(p)?;
-        let letter: LoveLetter = toml::from_str(&data)?;
+        let letter: LoveLetter = toml::from_str(&data).map_err(|e| Error::Parse(format!("malformed letter: {}", e)))?;
         Ok(letter)
     }
 
@@ -53,58 +84,123 @@ impl LoveLetter {
         // =========================
         // ```
         let title = format!("💌  Love Letters from {}", self.date.year);
-        let delim = "=".repeat(title.width_cjk());
+        let delim = rst_underline(&title, '=');
         delim.to_string() + "\n" + &title + "\n" + &delim + "\n\n"
     }
 
-    // convert to reStructuredText.
-    fn rstdoc_section(&self) -> String {
+    fn rstdoc_page_heading(&self, page: usize, total: usize) -> String {
+        let title = format!("💌  Love Letters from {} ({}/{})", self.date.year, page, total);
+        let delim = rst_underline(&title, '=');
+        delim.to_string() + "\n" + &title + "\n" + &delim + "\n\n"
+    }
+
+    /// Render letter in given format with default options.
+    pub fn render(&self, format: RenderFormat) -> String {
+        self.render_with(format, &RenderOptions::default())
+    }
+
+    pub fn render_with(&self, format: RenderFormat, opts: &RenderOptions) -> String {
+        match format {
+            RenderFormat::Rst => self.rstdoc_section(opts),
+            RenderFormat::Markdown => self.markdown_section(opts),
+        }
+    }
+
+    /// Section title like "[PREFIX ]DATE: TITLE", untitled letter is titled
+    /// by `untitled_template` if any, where "{date}" and "{author}" are
+    /// replaced.
+    fn title_with_prefix(&self, title_prefix: Option<&str>, untitled_template: Option<&str>) -> String {
+        title_prefix.map(|x| x.to_owned() + " ").unwrap_or_default()
+            + &(match (&self.title, untitled_template) {
+                (Some(t), _) => format!("{}: {}", self.date, t),
+                (None, Some(template)) => template
+                    .replace("{date}", &self.date.to_string())
+                    .replace("{author}", self.author()),
+                (None, None) => self.date.to_string(),
+            })
+    }
+
+    fn content_with_class(&self, content_class: Option<&str>) -> String {
+        match content_class {
+            Some(class) => format!("<div class=\"{}\">\n{}\n</div>", class.replace('"', "&quot;"), self.content),
+            None => self.content.clone(),
+        }
+    }
+
+    /// Render letter as rst section, [`RenderOptions`] controls its title, fields and content.
+    fn rstdoc_section(&self, opts: &RenderOptions) -> String {
+        let RenderOptions { nick, title_prefix, untitled_title_template, content_class, directive, source } = *opts;
         let mut buf = String::new();
 
-        // Section title:
+        // Section title with explicit target for linking (see LoveLetter::anchor):
         //
         // ```rst
-        // DATE: TITLE
-        // ===========
+        // .. _ANCHOR:
+        //
+        // [PREFIX ]DATE: TITLE
+        // ====================
         // ```
-        let title = self.date.to_string()
-            + &(match &self.title {
-                Some(t) => ": ".to_string() + &t,
-                None => "".to_string(),
-            });
+        buf.push_str(&format!(".. _{}:\n\n", self.anchor()));
+        let title = self.title_with_prefix(title_prefix, untitled_title_template);
         buf.push_str(&title);
         buf.push('\n');
-        buf.push_str(&"=".repeat(title.width_cjk())); // title delim
+        buf.push_str(&rst_underline(&title, '=')); // title delim
         buf.push('\n');
 
         // Push loveletter directive.
         buf.push_str(&format!(
             "
-.. loveletter:: _
+.. {}:: _
    :date: {}
    :nick: {}
    :author: {}
    :createdat: {}
    :updatedat: {}
-
+{}{}{}{}
    .. raw:: html
 
 {}
 ",
-            self.date.to_string(),
-            self.from.display_part(),
+            directive,
+            self.date,
+            nick.unwrap_or(self.from.display_part().trim()),
             self.author(),
-            &self
+            self
                 .created_at
                 .map(|x| x.format(Date::FMT).to_string())
                 .unwrap_or("".to_string()),
-            &self
+            self
                 .updated_at
                 .map(|x| x.format(Date::FMT).to_string())
                 .unwrap_or("".to_string()),
-            self.content.
+            match self.id() {
+                Some(id) => format!("   :id: {}\n", id),
+                None => "".to_string(),
+            },
+            match (&self.source_account, &self.source_folder) {
+                _ if !source => "".to_string(),
+                (Some(account), Some(folder)) => format!("   :source: {}/{}\n", account, folder),
+                (Some(account), None) => format!("   :source: {}\n", account),
+                _ => "".to_string(),
+            },
+            match &self.lang {
+                Some(lang) => format!("   :lang: {}\n", lang),
+                None => "".to_string(),
+            },
+            match self.attachments.is_empty() {
+                true => "".to_string(),
+                false => format!("   :attachments: {}\n", self.attachments
+                    .iter()
+                    .map(|x| match &x.content_type {
+                        Some(t) => format!("{} ({}, {})", x.filename, t, human_size(x.size)),
+                        None => format!("{} ({})", x.filename, human_size(x.size)),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")),
+            },
+            self.content_with_class(content_class).
                 lines().
-                map(|l| " ".repeat(3*2).to_string() + &l).
+                map(|l| " ".repeat(3*2) + l).
                 collect::<Vec<_>>().
                 join("\n"),
         ));
@@ -113,7 +209,98 @@ impl LoveLetter {
         buf
     }
 
-    fn author(&self) -> &str {
+    /// Render letter as Markdown section, content is kept as inline HTML.
+    fn markdown_section(&self, opts: &RenderOptions) -> String {
+        // ```markdown
+        // <a id="ANCHOR"></a>
+        //
+        // ## [PREFIX ]DATE: TITLE
+        //
+        // *NICK (AUTHOR), DATE*
+        //
+        // CONTENT
+        // ```
+        format!(
+            "<a id=\"{}\"></a>\n\n## {}\n\n*{} ({}), {}*\n\n{}\n",
+            self.anchor(),
+            self.title_with_prefix(opts.title_prefix, opts.untitled_title_template),
+            opts.nick.unwrap_or(self.from.display_part().trim()),
+            self.author(),
+            self.date,
+            self.content_with_class(opts.content_class).trim_end(),
+        )
+    }
+
+    /// Plaintext rendering of letter, for accessibility and grep-ability.
+    fn plaintext(&self) -> String {
+        let mut buf = self.date.to_string();
+        if let Some(t) = &self.title {
+            buf.push_str(": ");
+            buf.push_str(t);
+        }
+        buf.push_str("\n\n");
+        buf.push_str(html::to_text(&self.content).trim_end());
+        buf.push('\n');
+        buf
+    }
+
+    /// Plain text excerpt of content for previews, truncated to at most
+    /// `max_chars` chars (plus "…"). Latin words are never split, while CJK
+    /// text can be truncated between any chars.
+    pub fn excerpt(&self, max_chars: usize) -> String {
+        let text = html::to_text(&self.content).split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.chars().count() <= max_chars {
+            return text;
+        }
+
+        let is_word_char = |c: char| !c.is_whitespace() && c.width() != Some(2);
+        let (end, _) = text.char_indices().nth(max_chars).unwrap();
+        let mut excerpt = &text[..end];
+        let splits_word = excerpt.chars().next_back().is_some_and(is_word_char)
+            && text[end..].chars().next().is_some_and(is_word_char);
+        if splits_word {
+            // Truncate at the beginning of the split word, unless it is the only word.
+            if let Some(i) = excerpt.rfind(|c: char| !is_word_char(c)) {
+                let c = excerpt[i..].chars().next().unwrap();
+                excerpt = &excerpt[..i + if c.is_whitespace() { 0 } else { c.len_utf8() }];
+            }
+        }
+        excerpt.trim_end().to_owned() + "…"
+    }
+
+    /// Stable ID of letter, derived from title when letter is created. Letters
+    /// written before ID was introduced have no ID stored, it is derived from
+    /// their current titles, as the anchors were.
+    fn id(&self) -> Option<String> {
+        self.id.clone().or_else(|| Self::derive_id(self.title.as_deref()))
+    }
+
+    fn derive_id(title: Option<&str>) -> Option<String> {
+        title.map(|x| format!("{:08x}", hash(x.as_bytes()) as u32))
+    }
+
+    /// HTML anchor of letter in generated document, unique in the year.
+    pub fn anchor(&self) -> String {
+        match self.id() {
+            Some(id) => format!("loveletter-{}-{}", self.date, id),
+            None => format!("loveletter-{}", self.date),
+        }
+    }
+
+    pub fn date(&self) -> &Date {
+        &self.date
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Content in HTML.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn author(&self) -> &str {
         if self.from_meimei_if_true_and_gege_if_false {
             "妹妹"
         } else {
@@ -121,16 +308,10 @@ impl LoveLetter {
         }
     }
 
-    fn letter_filename(&self) -> String {
-        match &self.title {
-            Some(title) => format!("{}_{}.toml", self.date, URL_SAFE.encode(&title)),
-            None => self.date.to_string() + ".toml",
-        }
+    fn letter_filename(&self, pattern: &FilenamePattern) -> String {
+        pattern.render(self) + ".toml"
     }
 
-    fn rstdoc_filename(&self) -> String {
-        return self.date.year.to_string() + ".rst"
-    }
 }
 
 impl fmt::Display for LoveLetter {
@@ -142,40 +323,160 @@ impl fmt::Display for LoveLetter {
     }
 }
 
+/// Output format of [`LoveLetter::render`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderFormat {
+    Rst,
+    Markdown,
+}
+
+/// Options of [`LoveLetter::render_with`], [`Default`] renders as is.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions<'a> {
+    /// Overrides display name of sender.
+    pub nick: Option<&'a str>,
+    pub title_prefix: Option<&'a str>,
+    /// Title of untitled letter, see [`ArchiveCfg::untitled_title_template`].
+    pub untitled_title_template: Option<&'a str>,
+    /// Wrap content in div of the class.
+    pub content_class: Option<&'a str>,
+    /// Name of Sphinx directive, only used by rst.
+    pub directive: &'a str,
+    /// Render IMAP account and folder the letter came from, only used by rst.
+    pub source: bool,
+}
+
+impl Default for RenderOptions<'_> {
+    fn default() -> Self {
+        RenderOptions {
+            nick: None,
+            title_prefix: None,
+            untitled_title_template: None,
+            content_class: None,
+            directive: "loveletter",
+            source: false,
+        }
+    }
+}
+
+/// Date of letter in granularity of year, month or day, day is present only
+/// if month is present. Formatted as "YYYY[-MM[-DD]]", which is parsed back
+/// by [`Date::from_filename`] losslessly.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Date {
     pub year: i32,
-    pub month: u32,
+    pub month: Option<u32>,
     pub day: Option<u32>,
 }
 
 impl Date {
     const FMT: &str = "%Y-%m-%d";
 
-    fn parse<P: Pattern>(s: &str, delim: P) -> Result<Date> {
-        // Extract year/month/day from "YYYY/MM/[DD]".
+    /// Parse "YYYY[/MM[/DD]]", month and day must be zero-padded if `strict`
+    /// is true, otherwise "2025/4/3" is accepted too.
+    fn parse<P: Pattern>(s: &str, delim: P, strict: bool) -> Result<Date> {
+        let component = |x: &str, width: usize| -> Result<u32> {
+            if x.is_empty() || !x.bytes().all(|b| b.is_ascii_digit()) || ((strict || width == 4) && x.len() != width) {
+                bail!("expect {} digits, found {:?}", width, x);
+            }
+            Ok(x.parse()?)
+        };
         let mut splits = s.splitn(3, delim);
-        let year: i32 = splits.next().context("expect date *YYYY*/MM/DD")?.parse()?;
-        let month = splits.next().context("expect date YYYY/*MM*/DD")?.parse()?;
-        let day = splits.next().map(|x| x.parse::<u32>()).transpose()?;
-        Ok(Date{ year, month, day })
+        let year = component(splits.next().unwrap_or_default(), 4).context("expect date *YYYY*/MM/DD")?;
+        let month = splits.next().map(|x| component(x, 2)).transpose().context("expect date YYYY/*MM*/DD")?;
+        let day = splits.next().map(|x| component(x, 2)).transpose().context("expect date YYYY/MM/*DD*")?;
+        let date = Date{ year: year as i32, month, day };
+        if NaiveDate::from_ymd_opt(date.year, month.unwrap_or(1), day.unwrap_or(1)).is_none() {
+            bail!("invalid date {:?}", s);
+        }
+        Ok(date)
     }
 
+    /// Parse "YYYY/MM[/DD]" in mail subject.
     fn from_subject(s: &str) -> Result<Date> {
-        Self::parse(s, "/")
+        let date = Self::parse(s, "/", false)?;
+        if date.month.is_none() {
+            bail!("expect date YYYY/*MM*/DD");
+        }
+        Ok(date)
     }
 
+    /// Parse "YYYY[-MM[-DD]]", the inverse of [`fmt::Display`].
     fn from_filename(s: &str) -> Result<Date> {
-        Self::parse(s, "-")
+        Self::parse(s, "-", true)
+    }
+
+    fn from_datetime(d: &DateTime<Utc>) -> Date {
+        Date { year: d.year(), month: Some(d.month()), day: Some(d.day()) }
+    }
+
+    /// The first day in granularity of date.
+    fn first_day(&self) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(self.year, self.month.unwrap_or(1), self.day.unwrap_or(1))
+    }
+
+    /// The last day in granularity of date.
+    fn last_day(&self) -> Option<NaiveDate> {
+        match (self.month, self.day) {
+            (Some(_), Some(_)) => self.first_day(),
+            (Some(m), None) => self.first_day()
+                .and_then(|d| d.checked_add_months(chrono::Months::new(1)))
+                .and_then(|d| d.pred_opt())
+                .filter(|_| m <= 12),
+            (None, _) => NaiveDate::from_ymd_opt(self.year, 12, 31),
+        }
+    }
+}
+
+/// Inclusive range of dates like "2025/04" or "2025/04/01..2025/05/15", ends
+/// are in their own granularity, so "2025..2025/02" covers the first two
+/// months of 2025.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DateRange {
+    pub from: Date,
+    pub to: Date,
+}
+
+impl DateRange {
+    /// Whether the first day of date is in range.
+    pub fn contains(&self, date: &Date) -> bool {
+        match (date.first_day(), self.from.first_day(), self.to.last_day()) {
+            (Some(d), Some(from), Some(to)) => from <= d && d <= to,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for DateRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DateRange> {
+        let (from, to) = s.split_once("..").unwrap_or((s, s));
+        let from = Date::parse(from.trim(), "/", false).context("invalid start of date range")?;
+        let to = Date::parse(to.trim(), "/", false).context("invalid end of date range")?;
+        if from.first_day() > to.last_day() {
+            bail!("start of date range {} is after its end {}", from, to);
+        }
+        Ok(DateRange { from, to })
+    }
+}
+
+impl fmt::Display for DateRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.from == self.to {
+            true => write!(f, "{}", self.from),
+            false => write!(f, "{} ~ {}", self.from, self.to),
+        }
     }
 }
 
 impl fmt::Display for Date {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let (year, month, day) = (self.year, self.month, self.day.unwrap_or(1));
-        let fmt = match self.day {
-            Some(_) => Self::FMT,
-            None => "%Y-%m",
+        let (year, month, day) = (self.year, self.month.unwrap_or(1), self.day.unwrap_or(1));
+        let fmt = match (self.month, self.day) {
+            (Some(_), Some(_)) => Self::FMT,
+            (Some(_), None) => "%Y-%m",
+            (None, _) => "%Y",
         };
         let s = match NaiveDate::from_ymd_opt(year, month, day) {
             Some(d) => d.format(fmt).to_string(),
@@ -224,62 +525,504 @@ impl<'de> de::Deserialize<'de> for Date {
     }
 }
 
+/// Pattern of letter filename (without the ".toml" extension).
+///
+/// Available placeholders:
+///
+/// - `{date}`: date of letter, such as "2025-04-03"
+/// - `{title}`: URL-safe base64 encoded title
+/// - `{slug}`: lowercased title with non-alphanumeric chars replaced by "-"
+/// - `{hash}`: short hash of title
+/// - `{author}`: author of letter, such as "妹妹"
+///
+/// Segment surrounded by square brackets is optional, it is omitted when any
+/// placeholder inside it renders to empty (for example, untitled letter has
+/// empty `{title}`, `{slug}` and `{hash}`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilenamePattern(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+    Optional(Vec<Segment>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    Date,
+    Title,
+    Slug,
+    Hash,
+    Author,
+}
+
+impl Placeholder {
+    fn render(&self, letter: &LoveLetter) -> String {
+        let title = letter.title.as_deref();
+        match self {
+            Placeholder::Date => letter.date.to_string(),
+            Placeholder::Title => title.map(|t| URL_SAFE.encode(t)).unwrap_or_default(),
+            Placeholder::Slug => title.map(slugify).unwrap_or_default(),
+            Placeholder::Hash => title
+                .map(|t| format!("{:08x}", hash(t.as_bytes()) as u32))
+                .unwrap_or_default(),
+            Placeholder::Author => letter.author().to_owned(),
+        }
+    }
+}
+
+impl FilenamePattern {
+    /// Chars that are not allowed in filename on common filesystems.
+    const UNSAFE_CHARS: &str = "/\\:*?\"<>|";
+
+    pub fn parse(s: &str) -> Result<FilenamePattern> {
+        let mut segs: Vec<Segment> = Vec::new();
+        let mut optional: Option<Vec<Segment>> = None;
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            let seg = match c {
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unmatched curly brackets"),
+                        }
+                    }
+                    Segment::Placeholder(match name.as_str() {
+                        "date" => Placeholder::Date,
+                        "title" => Placeholder::Title,
+                        "slug" => Placeholder::Slug,
+                        "hash" => Placeholder::Hash,
+                        "author" => Placeholder::Author,
+                        _ => bail!("unknown placeholder {{{}}}", name),
+                    })
+                },
+                '[' => {
+                    if optional.is_some() {
+                        bail!("nested square brackets is not allowed");
+                    }
+                    optional = Some(Vec::new());
+                    continue;
+                },
+                ']' => match optional.take() {
+                    Some(x) => Segment::Optional(x),
+                    None => bail!("unmatched square brackets"),
+                },
+                '}' => bail!("unmatched curly brackets"),
+                c if c.is_control() || Self::UNSAFE_CHARS.contains(c) => {
+                    bail!("char {:?} is not filesystem-safe", c)
+                },
+                c => Segment::Literal(c.to_string()),
+            };
+            match optional.as_mut() {
+                Some(x) => x.push(seg),
+                None => segs.push(seg),
+            }
+        }
+        if optional.is_some() {
+            bail!("unmatched square brackets");
+        }
+
+        // Letters are sorted by filename (see Archive::generate_all).
+        if segs.first() != Some(&Segment::Placeholder(Placeholder::Date)) {
+            bail!("pattern must start with {{date}}");
+        }
+        // Letters with same date but different titles must have different filenames.
+        let placeholders: Vec<_> = segs
+            .iter()
+            .flat_map(|x| match x {
+                Segment::Optional(x) => x.iter().collect(),
+                x => vec![x],
+            })
+            .filter_map(|x| match x {
+                Segment::Placeholder(p) => Some(*p),
+                _ => None,
+            })
+            .collect();
+        if !placeholders.contains(&Placeholder::Title) && !placeholders.contains(&Placeholder::Hash) {
+            bail!("pattern must contain {{title}} or {{hash}} to keep filename unique");
+        }
+
+        Ok(FilenamePattern(segs))
+    }
+
+    pub fn render(&self, letter: &LoveLetter) -> String {
+        fn render_segments(segs: &[Segment], letter: &LoveLetter, buf: &mut String) -> bool {
+            let mut complete = true;
+            for seg in segs {
+                match seg {
+                    Segment::Literal(x) => buf.push_str(x),
+                    Segment::Placeholder(p) => {
+                        let x = p.render(letter);
+                        complete &= !x.is_empty();
+                        buf.push_str(&x);
+                    },
+                    Segment::Optional(x) => {
+                        let mut opt_buf = String::new();
+                        if render_segments(x, letter, &mut opt_buf) {
+                            buf.push_str(&opt_buf);
+                        }
+                    },
+                }
+            }
+            complete
+        }
+
+        let mut buf = String::new();
+        render_segments(&self.0, letter, &mut buf);
+        buf
+    }
+}
+
+/// Options of [`Archive::generate_with`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GenerateOptions {
+    pub full: bool, // re-generate pages of frozen years too, see ArchiveCfg::frozen_years
+    pub include_private: bool, // include private letters, for a private build
+}
+
+/// Files found by Archive::prune.
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneReport {
+    pub orphaned_rstdocs: Vec<PathBuf>,
+    pub empty_letters: Vec<PathBuf>,
+}
+
+impl PruneReport {
+    pub fn is_empty(&self) -> bool {
+        self.orphaned_rstdocs.is_empty() && self.empty_letters.is_empty()
+    }
+}
+
+/// Document name of year page, or its N-th part if year is paginated.
+fn rstdoc_page_name(year: i32, page: Option<usize>) -> String {
+    match page {
+        Some(i) => format!("{}-{}", year, i),
+        None => year.to_string(),
+    }
+}
+
+/// Navigation line between parts of paginated year.
+fn rstdoc_page_nav(year: i32, page: usize, total: usize) -> String {
+    let mut nav = Vec::new();
+    if page > 1 {
+        nav.push(format!(":doc:`← Previous <{}>`", rstdoc_page_name(year, Some(page - 1))));
+    }
+    nav.push(format!(":doc:`{}`", rstdoc_page_name(year, None)));
+    if page < total {
+        nav.push(format!(":doc:`Next → <{}>`", rstdoc_page_name(year, Some(page + 1))));
+    }
+    nav.join(" | ") + "\n"
+}
+
+/// Underline of rst title, `unicode-width` may disagree with docutils on
+/// emoji sequences, in which case the underline is padded to the wider one
+/// so that Sphinx doesn't complain "Title underline too short".
+fn rst_underline(title: &str, c: char) -> String {
+    let width = title.width_cjk();
+    let expected = docutils_width(title);
+    if width < expected {
+        warn!("underline of title {:?} is padded from {} to {} chars for docutils", title, width, expected);
+    }
+    c.to_string().repeat(cmp::max(width, expected))
+}
+
+/// Comment like ".. generated: TIME by loveletter vX" to be prepended to
+/// generated file, None if it is neither rst nor markdown.
+fn generated_stamp(path: &Path, now: DateTime<Utc>) -> Option<String> {
+    let stamp = format!("generated: {} by loveletter v{}",
+        now.format("%Y-%m-%dT%H:%M:%SZ"), env!("CARGO_PKG_VERSION"));
+    match path.extension().and_then(OsStr::to_str) {
+        Some("rst") => Some(format!(".. {}\n\n", stamp)),
+        Some("md") => Some(format!("<!-- {} -->\n\n", stamp)),
+        _ => None,
+    }
+}
+
+/// Strip comment prepended by [`generated_stamp`] if any.
+fn strip_generated_stamp(content: &str) -> &str {
+    match content.starts_with(".. generated: ") || content.starts_with("<!-- generated: ") {
+        true => content.split_once("\n\n").map_or("", |(_, x)| x),
+        false => content,
+    }
+}
+
+/// Parse write time like "YYYY/MM/DD" (midnight in UTC), RFC 3339 or RFC 2822.
+fn parse_written_at(s: &str) -> Result<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(x) = DateTime::parse_from_rfc3339(s).or_else(|_| DateTime::parse_from_rfc2822(s)) {
+        return Ok(x.with_timezone(&Utc));
+    }
+    let date = Date::from_subject(s)?;
+    let (month, day) = date.month.zip(date.day).context("expect date YYYY/MM/*DD*")?;
+    NaiveDate::from_ymd_opt(date.year, month, day)
+        .and_then(|x| x.and_hms_opt(0, 0, 0))
+        .map(|x| x.and_utc())
+        .with_context(|| format!("invalid date {}", s))
+}
+
+fn min_datetime(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn max_datetime(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    a.max(b)
+}
+
+/// Outcome of [`Archive::upsert_letter`].
+#[derive(Debug)]
+pub enum Upserted {
+    Written(Box<LoveLetter>),
+    /// Mail is skipped, for example, it has ignored subject, has malformed
+    /// subject in lenient mode, or is from disallowed sender with "skip" policy.
+    Skipped,
+    /// Mail is from disallowed sender and copied to quarantine_dir.
+    Quarantined(PathBuf),
+}
+
+impl Upserted {
+    pub fn letter(self) -> Option<LoveLetter> {
+        match self {
+            Upserted::Written(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
 pub struct Archive {
     cfg: ArchiveCfg,
+    letter_filename_pattern: FilenamePattern,
+    ignore_subjects: RegexSet,
+    signature_delimiters: HashMap<String, Regex>, // compiled ArchiveCfg::signature_delimiters
+    content_transforms: Pipeline,
     letter_dir: PathBuf,
     rstdoc_dir: PathBuf,
-    letter_git_repo: Repo,
-    rstdoc_git_repo: Repo,
+    // Loaded lazily so read-only operations work on plain directories.
+    letter_git_repo: OnceCell<Repo>,
+    rstdoc_git_repo: OnceCell<Repo>,
+    // Whether pushing is deferred by quiet hours, see Archive::push_deferred.
+    letter_push_deferred: Cell<bool>,
+    rstdoc_push_deferred: Cell<bool>,
+    // Held until archive is dropped, see Archive::lock.
+    _lock: Option<fs::File>,
+    // Sub-archives routed by recipient, see ArchiveCfg::routes.
+    routes: Vec<(EmailAddress, Archive)>,
+    // Log letters that would be written instead of writing anything, see
+    // Archive::dry_run.
+    dry_run: bool,
 }
 
 impl Archive {
     pub fn load(cfg: ArchiveCfg) -> Result<Archive> {
-        fn create_dir(p: &Path, create_dirs: bool) -> Result<()> {
-            if !p.exists() && create_dirs {
+        fn create_dir(p: &Path, cfg: &ArchiveCfg) -> Result<()> {
+            if !p.exists() && cfg.create_dirs {
                 info!("creating dir {}", p.display());
-                fs::create_dir_all(p)?;
+                retry_fs(cfg.fs_retry, || fs::create_dir_all(p))?;
                 info!("created");
             }
             Ok(())
         }
 
-        fn load_repo(p: &Path, create_dirs: bool) -> Result<Repo> {
-            Repo::load(p).or_else(|e| if create_dirs {
-                    Repo::init(p)
-                }  else {
-                    Err(e)
-                })
+        create_dir(Path::new(&cfg.letter_dir), &cfg)?;
+        if cfg.generate_docs {
+            create_dir(Path::new(&cfg.rstdoc_dir), &cfg)?;
+            // Otherwise generated files may be taken as letters, or clobber them.
+            if let (Ok(l), Ok(r)) = (fs::canonicalize(&cfg.letter_dir), fs::canonicalize(&cfg.rstdoc_dir)) {
+                if l.starts_with(&r) || r.starts_with(&l) {
+                    bail!("letter_dir {} and rstdoc_dir {} must not be the same or nested", cfg.letter_dir, cfg.rstdoc_dir);
+                }
+            }
+        }
+        let routes = Archive::load_routes(&cfg, Archive::load)?;
+        let mut archive = Archive::new(cfg, routes)?;
+        // Fail early rather than after receiving mails.
+        archive.letter_git_repo()?;
+        if archive.cfg.generate_docs {
+            archive.rstdoc_git_repo()?;
+        }
+        archive._lock = Some(archive.lock()?);
+        Ok(archive)
+    }
+
+    /// Acquire exclusive lock of archive, so that another instance can not
+    /// clobber the git repositories. The lock is released by OS when the file
+    /// is closed, even if process is killed, so a stale lock file never blocks.
+    fn lock(&self) -> Result<fs::File> {
+        let path = match &self.cfg.lock_file {
+            Some(p) => PathBuf::from(p),
+            None => self.letter_git_repo()?.git_dir()?.join("loveletter.lock"),
+        };
+        debug!("acquiring lock {}...", path.display());
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open lock file {}", path.display()))?;
+        if let Err(e) = file.try_lock() {
+            let pid = fs::read_to_string(&path).unwrap_or_default();
+            return Err(anyhow::Error::from(e))
+                .with_context(|| format!("another loveletter is running (pid: {}, lock: {})", pid.trim(), path.display()));
+        }
+        // PID is informative only.
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        debug!("acquired");
+        Ok(file)
+    }
+
+    /// Load archive without touching git repositories, and without creating
+    /// any directory. Repositories are loaded on the first mutating operation.
+    pub fn load_read_only(cfg: ArchiveCfg) -> Result<Archive> {
+        let routes = Archive::load_routes(&cfg, Archive::load_read_only)?;
+        Archive::new(cfg, routes)
+    }
+
+    fn load_routes(
+        cfg: &ArchiveCfg,
+        load: fn(ArchiveCfg) -> Result<Archive>,
+    ) -> Result<Vec<(EmailAddress, Archive)>> {
+        let mut routes = Vec::new();
+        for route in cfg.routes.iter() {
+            if cfg.allowed_to_addrs.find(&route.to).is_none() {
+                bail!("route recipient {} not in allowed list {:?}", route.to, cfg.allowed_to_addrs);
+            }
+            info!("loading archive of recipient {}...", route.to);
+            let archive = load(cfg.route_cfg(route))
+                .with_context(|| format!("failed to load archive of recipient {}", route.to))?;
+            routes.push((route.to.clone(), archive));
         }
+        Ok(routes)
+    }
 
+    fn new(cfg: ArchiveCfg, routes: Vec<(EmailAddress, Archive)>) -> Result<Archive> {
+        let letter_filename_pattern = FilenamePattern::parse(&cfg.letter_filename_pattern)
+            .context("invalid letter_filename_pattern")?;
+        let ignore_subjects = RegexSet::new(&cfg.ignore_subjects)
+            .context("invalid ignore_subjects")?;
+        let signature_delimiters = cfg.signature_delimiters
+            .iter()
+            .map(|(k, v)| Ok((k.to_owned(), Regex::new(v).with_context(|| format!("invalid signature_delimiters of {}", k))?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        let mut content_transforms = Pipeline::from_names(&cfg.content_transforms)
+            .context("invalid content_transforms")?;
+        if cfg.trim_empty_html && !content_transforms.contains("trim_empty_html") {
+            content_transforms.push(Box::new(TrimEmptyHtml));
+        }
+        if cfg.max_sections_per_page == Some(0) {
+            bail!("max_sections_per_page must be greater than 0");
+        }
+        // Only reStructuredText is generated for now.
+        let index_filename = Path::new(&cfg.index_filename);
+        if index_filename.extension() != Some(OsStr::new("rst"))
+            || !index_filename.components().all(|x| matches!(x, Component::Normal(_))) {
+            bail!("index_filename must be a relative path ending in .rst without \"..\": {}", cfg.index_filename);
+        }
+        if let Some(x) = cfg.unknown_author.as_deref().filter(|&x| x != "妹妹" && x != "哥哥") {
+            bail!("unknown_author must be {} or {}: {}", "哥哥", "妹妹", x);
+        }
+        if cfg.on_disallowed == DisallowedPolicy::Quarantine && cfg.quarantine_dir.is_none() {
+            bail!("quarantine_dir is required by on_disallowed = \"quarantine\"");
+        }
         let letter_dir = PathBuf::from(cfg.letter_dir.to_owned());
-        create_dir(&letter_dir, cfg.create_dirs)?;
-        let letter_git_repo = load_repo(&letter_dir, cfg.create_dirs)?;
         let rstdoc_dir = PathBuf::from(cfg.rstdoc_dir.to_owned());
-        create_dir(&rstdoc_dir, cfg.create_dirs)?;
-        let rstdoc_git_repo = load_repo(&rstdoc_dir, cfg.create_dirs)?;
 
         Ok(Archive {
             cfg,
+            letter_filename_pattern,
+            ignore_subjects,
+            signature_delimiters,
+            content_transforms,
             letter_dir,
             rstdoc_dir,
-            letter_git_repo,
-            rstdoc_git_repo,
+            letter_git_repo: OnceCell::new(),
+            rstdoc_git_repo: OnceCell::new(),
+            letter_push_deferred: Cell::new(false),
+            rstdoc_push_deferred: Cell::new(false),
+            _lock: None,
+            routes,
+            dry_run: false,
         })
     }
 
+    /// Parse mails as usual, but log letters that would be written rather
+    /// than writing letters, generating outputs or quarantining mails. Used
+    /// with [`Archive::load_read_only`] so that nothing on disk is changed.
+    pub fn dry_run(mut self, dry_run: bool) -> Archive {
+        self.dry_run = dry_run;
+        self.routes = self.routes.into_iter().map(|(to, x)| (to, x.dry_run(dry_run))).collect();
+        self
+    }
+
+    fn load_repo<'a>(&self, cell: &'a OnceCell<Repo>, p: &Path) -> Result<&'a Repo> {
+        if let Some(repo) = cell.get() {
+            return Ok(repo);
+        }
+        let repo = Repo::load(p).or_else(|e| if self.cfg.create_dirs {
+                Repo::init(p)
+            }  else {
+                Err(e)
+            })?;
+        Ok(cell.get_or_init(|| repo.dry_run(self.cfg.git_dry_run)))
+    }
+
+    fn letter_git_repo(&self) -> Result<&Repo> {
+        self.load_repo(&self.letter_git_repo, &self.letter_dir)
+    }
+
+    fn rstdoc_git_repo(&self) -> Result<&Repo> {
+        self.load_repo(&self.rstdoc_git_repo, &self.rstdoc_dir)
+    }
+
+    const WRITTEN_HEADER: &str = "X-Loveletter-Written";
+    /// Header marking letter as private, like "X-Loveletter-Private: yes".
+    const PRIVATE_HEADER: &str = "X-Loveletter-Private";
+    /// Action marking letter as private, always accepted.
+    const PRIVATE_ACTION: &str = "private";
+    /// Max chars of excerpt of each letter in digest.
+    const DIGEST_EXCERPT_CHARS: usize = 200;
+
+    /// Split explicit write time from subject like "YYYY/MM/DD@YYYY/MM/DD: TITLE",
+    /// returns (subject without write time, write time). '@' is only taken as
+    /// write time marker right after date, so that date-less subject like
+    /// "Dinner @ 7pm" is kept intact.
+    fn split_written_at(subject: &str) -> Result<(Cow<'_, str>, Option<DateTime<Utc>>)> {
+        let end = subject.find([':', '：']).unwrap_or(subject.len());
+        let after_date = |i: usize| {
+            let date = subject[..i].rsplit_once(']').map_or(&subject[..i], |(_, x)| x);
+            Date::from_subject(date.trim()).is_ok()
+        };
+        match subject[..end].find('@').filter(|&i| after_date(i)) {
+            Some(i) => {
+                let written_at = parse_written_at(&subject[i + 1..end])
+                    .context("failed to parse write date")?;
+                Ok((Cow::Owned(subject[..i].to_owned() + &subject[end..]), Some(written_at)))
+            },
+            None => Ok((Cow::Borrowed(subject), None)),
+        }
+    }
+
     /// Parse subject like "[ACTION] YYYY/MM/DD: TITLE", returns (date, title, action).
+    ///
+    /// Action is case-insensitive and returned in lowercase, accepted actions
+    /// are configured by `allowed_actions`, "edit" by default. Action
+    /// "private" is always accepted, see [`Archive::PRIVATE_ACTION`].
     fn parse_subject(subject: &str) -> Result<(Date, Option<String>, Option<String>)> {
         let ptr: &str = subject.trim();
 
-        // Extract title from "...: TITLE".
+        // Extract title from "...: TITLE", title may contain colons too, so
+        // split on the first colon in either form ('：' for CJK chars compat).
         debug!("extracting title from {:?}...", ptr);
-        let (ptr, title) = match ptr.split_once(':') {
-            // TODO: support '：'
+        let (ptr, title) = match ptr.split_once([':', '：']) {
             Some((ptr, title)) => (ptr, Some(title)),
-            None => match ptr.split_once('：') { // CJK chars compat
-                Some((ptr, title)) => (ptr, Some(title)),
-                None => (ptr, None),
-            },
+            None => (ptr, None),
         };
         let ptr = ptr.trim();
         let title = title
@@ -305,7 +1048,7 @@ impl Archive {
         let action = action
             .map(str::trim)
             .filter(|&x| !x.is_empty())
-            .map(str::to_owned);
+            .map(str::to_lowercase);
         debug!("action: {:?}", action);
 
         // Extract year/month/day from "YYYY/MM/DD".
@@ -318,117 +1061,727 @@ impl Archive {
 
     fn is_from_meimei_or_gege(&self, addr: &EmailAddress) -> Result<bool> {
         let matched = self.cfg.allowed_from_addrs.find(addr).context("mail is not allowed: {}")?;
-        match matched.display_part() {
+        // Nameless address in allowed list may be named by display_names.
+        // Sender is still rendered with its own display name, see rstdoc_section.
+        let name = match matched.display_part() {
+            x @ ("妹妹" | "哥哥") => x,
+            x => self.display_name(matched).unwrap_or(x),
+        };
+        match name {
             "妹妹" => Ok(true),
             "哥哥" => Ok(false),
-            _ => bail!("name in address {} is unknown, only {} or {} is allowed",
-                addr.display_part(), "哥哥", "妹妹"),
+            x => match self.cfg.unknown_author.as_deref() {
+                Some("妹妹") => Ok(true),
+                Some("哥哥") => Ok(false),
+                _ => bail!("name in address {} is unknown, only {} or {} is allowed (see unknown_author)",
+                    x, "哥哥", "妹妹"),
+            },
         }
     }
 
     // TODO: dedup by Message-ID? need index.
-    pub fn upsert_letter(&self, mail: &ParsedMail) -> Result<LoveLetter> {
-        let from = mail
-            .from()
-            .context("failed to extract mail sender's address")?;
+    /// Upsert letter from mail, see [`Upserted`] for outcomes.
+    pub fn upsert_letter(&self, mail: &ParsedMail) -> error::Result<Upserted> {
+        if let Some(archive) = self.route(mail) {
+            return archive.upsert_letter(mail);
+        }
+        let sender_allowed = self.sender(mail).is_none_or(|x| self.cfg.allowed_from_addrs.find(&x).is_some());
+        match self.cfg.on_disallowed {
+            _ if sender_allowed => (),
+            DisallowedPolicy::Error => (), // reported by letter_from_mail
+            DisallowedPolicy::Skip => {
+                debug!("sender {:?} not in allowed list, skipped", mail.from_all());
+                return Ok(Upserted::Skipped);
+            },
+            DisallowedPolicy::Quarantine if self.dry_run => {
+                info!("dry run, mail from {:?} would be quarantined", mail.from_all());
+                return Ok(Upserted::Skipped);
+            },
+            DisallowedPolicy::Quarantine => {
+                let path = self.quarantine(mail)?;
+                info!("sender {:?} not in allowed list, quarantined to {}", mail.from_all(), path.display());
+                return Ok(Upserted::Quarantined(path));
+            },
+        }
+        let Some((letter, action)) = self.letter_from_mail(mail)? else {
+            return Ok(Upserted::Skipped);
+        };
+        let subject = mail.subject().unwrap_or_default();
+        Ok(self.write_letter(letter, action.as_deref(), subject).map(|x| Upserted::Written(Box::new(x)))?)
+    }
+
+    /// Copy raw mail to quarantine_dir, returns path of the copy.
+    fn quarantine(&self, mail: &ParsedMail) -> Result<PathBuf> {
+        let dir = PathBuf::from(self.cfg.quarantine_dir.as_deref().context("quarantine_dir is not set")?);
+        retry_fs(self.cfg.fs_retry, || fs::create_dir_all(&dir))
+            .with_context(|| format!("{}", dir.display()))?;
+        let path = dir.join(format!("{:016x}.eml", hash(mail.raw())));
+        self.write_file(&path, mail.raw())
+            .with_context(|| format!("{}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Import letters from bulk of mails (for example, mails from mbox and Maildir).
+    ///
+    /// Different from calling [`Archive::upsert_letter`] one by one, duplicate
+    /// mails of same letter are merged in advance regardless of their order:
+    /// the latest one provides the content, `created_at` is backfilled from the
+    /// earliest one and `updated_at` from the latest one.
+    pub fn import_letters(&self, mails: &[ParsedMail]) -> Result<Vec<LoveLetter>> {
+        let mut letters = Vec::new();
+        for (_, archive) in self.routes.iter() {
+            let mails: Vec<_> = mails
+                .iter()
+                .filter(|m| self.route(m).is_some_and(|x| std::ptr::eq(x, archive)))
+                .collect();
+            letters.extend(archive.import_own_letters(&mails)?);
+        }
+        let mails: Vec<_> = mails.iter().filter(|m| self.route(m).is_none()).collect();
+        letters.extend(self.import_own_letters(&mails)?);
+        Ok(letters)
+    }
+
+    fn import_own_letters(&self, mails: &[&ParsedMail]) -> Result<Vec<LoveLetter>> {
+        let mut letters = Vec::new();
+        let mut merged: HashMap<PathBuf, (LoveLetter, &str)>  = HashMap::new();
+        for mail in mails {
+            let (letter, action) = match self.letter_from_mail(mail) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("failed to import mail {:?}: {}", mail.subject(), e);
+                    continue;
+                },
+            };
+            debug!("importing letter {} (action: {:?})...", letter, action);
+            let subject = mail.subject().unwrap_or_default();
+            let letter_path = self.letter_path(&letter);
+            match merged.get_mut(&letter_path) {
+                Some((prev, prev_subject)) => {
+                    let created_at = min_datetime(prev.created_at, letter.created_at);
+                    let updated_at = max_datetime(prev.updated_at, letter.updated_at);
+                    if letter.updated_at > prev.updated_at {
+                        *prev = letter;
+                        *prev_subject = subject;
+                    }
+                    prev.created_at = created_at;
+                    prev.updated_at = updated_at;
+                },
+                None => {
+                    merged.insert(letter_path, (letter, subject));
+                },
+            }
+        }
+
+        // Write letters in a stable order.
+        let mut merged: Vec<_> = merged.into_iter().collect();
+        merged.sort_by(|a, b| a.0.cmp(&b.0));
+
+        if self.cfg.squash_import {
+            return self.write_letters_squashed(merged.into_iter().map(|(_, x)| x).collect());
+        }
+        for (_, (letter, subject)) in merged {
+            match self.write_letter(letter, None, subject) {
+                Ok(x) => letters.push(x),
+                Err(e) => match e.downcast_ref::<Error>() {
+                    Some(Error::Duplicate(_)) => info!("{}, skipped", e),
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(letters)
+    }
+
+    /// The first language tag of Content-Language header, like "zh-CN" of
+    /// "zh-CN, en".
+    fn lang(mail: &ParsedMail) -> Option<String> {
+        mail.header("Content-Language")?
+            .split(',')
+            .map(str::trim)
+            .find(|x| !x.is_empty())
+            .map(str::to_owned)
+    }
+
+    /// Whether mail is flagged as spam by server, like "X-Spam-Flag: YES".
+    fn is_spam(mail: &ParsedMail) -> bool {
+        ["X-Spam-Flag", "X-Spam-Status", "X-Spam"]
+            .iter()
+            .filter_map(|h| mail.header(h))
+            .any(|x| x.trim().get(..3).is_some_and(|x| x.eq_ignore_ascii_case("yes")))
+    }
+
+    /// Sender of mail, the first From address in allowed list if mail has
+    /// multiple ones, otherwise the first one.
+    fn sender(&self, mail: &ParsedMail) -> Option<EmailAddress> {
+        let from_all = mail.from_all();
+        from_all
+            .iter()
+            .find(|x| self.cfg.allowed_from_addrs.find(x).is_some())
+            .or(from_all.first())
+            .cloned()
+    }
+
+    /// Convert mail to letter, returns (letter, action), or None if the mail
+    /// should be skipped (has ignored subject, or has malformed subject in
+    /// lenient mode).
+    fn letter_from_mail(&self, mail: &ParsedMail) -> Result<Option<(LoveLetter, Option<String>)>> {
+        let from = self
+            .sender(mail)
+            .ok_or_else(|| Error::Parse("failed to extract mail sender's address".to_string()))?;
         let from = match self.cfg.allowed_from_addrs.find(&from) {
             Some(a) => if from.display_part().is_empty() {
                 a.to_owned()
             } else {
                 from
             },
-            None => bail!(
+            None => bail!(Error::NotAllowed(format!(
                 "sender {} not in allowed list {:?}",
                 from,
                 self.cfg.allowed_from_addrs
-            ),
+            ))),
         };
-        let to = mail
-            .to()
-            .context("failed to extract mail recipient's address")?;
-        let to = match self.cfg.allowed_to_addrs.find(&to) {
-            Some(a) => if to.display_part().is_empty() {
+        let to = mail.to();
+        let allowed_to = to.as_ref().and_then(|x| self.cfg.allowed_to_addrs.find(x));
+        let to = match (to, allowed_to) {
+            (Some(to), Some(a)) => if to.display_part().is_empty() {
                 a.to_owned()
             } else {
                 to
             },
-            None => bail!(
-                "recipient {} not in allowed list {:?}",
-                to,
-                self.cfg.allowed_to_addrs
-            ),
+            (to, _) => match self.bcc_recipient(mail) {
+                Some(a) => {
+                    debug!("recipient {:?} not in allowed list, but mail is Bcc'd to {}", to, a);
+                    a.to_owned()
+                },
+                None => match to {
+                    Some(to) => bail!(Error::NotAllowed(format!(
+                        "recipient {} not in allowed list {:?}",
+                        to,
+                        self.cfg.allowed_to_addrs
+                    ))),
+                    None => bail!(Error::Parse("failed to extract mail recipient's address".to_string())),
+                },
+            },
+        };
+        let subject = mail
+            .subject()
+            .ok_or_else(|| Error::Parse("failed to extract mail subject".to_string()))?;
+        if self.ignore_subjects.is_match(subject) {
+            debug!("mail subject {:?} matches ignore_subjects, skipped", subject);
+            return Ok(None);
+        }
+        if self.cfg.skip_spam && Self::is_spam(mail) {
+            debug!("mail {:?} is flagged as spam, skipped", subject);
+            return Ok(None);
+        }
+        let parsed = Self::split_written_at(subject).and_then(|(s, written_at)| match Self::parse_subject(&s) {
+            Ok(x) => Ok((x, written_at)),
+            // Casual senders may forget the date, fallback to the mail's.
+            Err(e) => match mail.date().filter(|_| self.cfg.date_from_mail_fallback) {
+                Some(d) => {
+                    debug!("no date in subject {:?} ({:#}), use mail date {}", s, e, d);
+                    let title = Some(s.trim().to_owned()).filter(|x| !x.is_empty());
+                    Ok(((Date::from_datetime(&d), title, None), written_at))
+                },
+                None => Err(e),
+            },
+        });
+        let ((date, title, action), subject_written_at) = match parsed {
+            Ok(x) => x,
+            Err(e) if !self.cfg.strict => {
+                warn!("failed to parse mail subject {:?}, skipped: {:#}", subject, e);
+                return Ok(None);
+            },
+            Err(e) => bail!(Error::Parse(format!("failed to parse mail subject {:?}: {:#}", subject, e))),
+        };
+        let action = match self.cfg.action_header.as_deref().and_then(|h| mail.header(h)) {
+            Some(x) => Some(x.trim().to_lowercase()).filter(|x| !x.is_empty()),
+            None => action,
+        };
+        // Signature rule of sender, or the global "-- " one.
+        let delimiter = self.signature_delimiters.get(from.email().as_str());
+        let strip_signature = delimiter.is_some() || self.cfg.strip_signature;
+        let is_delimiter = |x: &str| match delimiter {
+            Some(re) => re.is_match(x.trim()),
+            None => x.trim() == "--",
+        };
+        let html_body = || mail.html_body().map(|x| match strip_signature {
+            true => html::strip_signature(&x, is_delimiter),
+            false => x,
+        });
+        let text_body = || mail.text_body_joined(&self.cfg.text_part_separator).map(|x| {
+            let x = match strip_signature {
+                true => crate::mail::strip_signature(&x, is_delimiter),
+                false => x,
+            };
+            match self.cfg.interpret_markdown {
+                true => markdown::to_html(&x),
+                false => html::from_text(&x),
+            }
+        });
+        let tagged_body = || self.cfg.body_content_id.as_deref()
+            .and_then(|id| mail.body_by_content_id(id))
+            .map(|x| match strip_signature {
+                true => html::strip_signature(&x, is_delimiter),
+                false => x,
+            });
+        let content = tagged_body().or_else(|| match self.cfg.prefer_body.get(from.email().as_str()) {
+            Some(BodyKind::Html) => html_body().or_else(text_body),
+            // Text body falls back to the HTML one converted to text.
+            Some(BodyKind::Text) => text_body(),
+            None => match html_body() {
+                Some(x) => Some(x),
+                None if self.cfg.interpret_markdown => text_body(),
+                None => None,
+            },
+        }).ok_or_else(|| Error::Parse("failed to extract mail body".to_string()))?;
+        // CRLF can not be represented in TOML literal string, which is easier
+        // to read and diff than the escaped basic string, and makes no
+        // difference to HTML.
+        let content = content.replace("\r\n", "\n");
+        let content = self.content_transforms.apply(&content);
+
+        // Explicit write time for importing historical letters, precedence:
+        // header > subject > mail date.
+        let header_written_at = match mail.header(Self::WRITTEN_HEADER).map(|x| parse_written_at(&x)) {
+            Some(Ok(x)) => Some(x),
+            Some(Err(e)) if !self.cfg.strict => {
+                warn!("failed to parse {} header, ignored: {:#}", Self::WRITTEN_HEADER, e);
+                None
+            },
+            Some(Err(e)) => bail!(Error::Parse(format!("failed to parse {} header: {:#}", Self::WRITTEN_HEADER, e))),
+            None => None,
         };
-        let subject = mail.subject().context("failed to extract mail subject")?;
-        let (date, title, action) =
-            Self::parse_subject(subject).context("failed to parse mail subject:")?;
-        let content = mail.html_body().context("failed to extract mail body")?;
+        let written_at = header_written_at.or(subject_written_at).or(mail.date());
 
         // Combine the aboved fields together.
-        let mut letter = LoveLetter {
+        let letter = LoveLetter {
             from: from.clone(),
             to,
             from_meimei_if_true_and_gege_if_false: self.is_from_meimei_or_gege(&from)?,
-            created_at: mail.date(), // TODO: update for edit
-            updated_at: mail.date(),
+            id: LoveLetter::derive_id(title.as_deref()),
+            source_account: mail.account().map(str::to_owned),
+            source_folder: mail.folder().map(str::to_owned),
+            created_at: written_at, // TODO: update for edit
+            updated_at: written_at,
 
             date,
             title,
+            raw_subject: Some(subject.to_owned()),
             content,
+            attachments: mail.attachments(),
+            private: action.as_deref() == Some(Self::PRIVATE_ACTION)
+                || mail.header(Self::PRIVATE_HEADER).is_some_and(|x| matches!(x.trim().to_lowercase().as_str(), "yes" | "true" | "1")),
+            lang: Self::lang(mail).or(self.cfg.default_lang.clone()),
         };
 
-        let letter_path = self.letter_path(&letter);
-        let letter_exists = letter_path.exists();
-        info!(
-            "writing letter {} (action: {:?}) to {} (exist: {})...",
-            letter,
-            action,
-            letter_path.display(),
-            letter_exists
-        );
-
         // Premission checks.
         match action.as_deref() {
-            None => (),
-            Some("edit") => (), // TODO: drop action support?
-            Some(x) => bail!("unknown action: {}", x),
+            None | Some(Self::PRIVATE_ACTION) => (),
+            Some(x) if self.cfg.allowed_actions.iter().any(|a| a.to_lowercase() == x) => (),
+            Some(x) if !self.cfg.strict => {
+                warn!("unknown action {} in mail subject {:?}, skipped", x, subject);
+                return Ok(None);
+            },
+            Some(x) => bail!(Error::NotAllowed(format!("unknown action: {}", x))),
         }
 
-        // Cleanup repo before any change.
+        Ok(Some((letter, action)))
+    }
+
+    /// Find sub-archive of mail by its recipients, None for this archive.
+    fn route(&self, mail: &ParsedMail) -> Option<&Archive> {
+        if self.routes.is_empty() {
+            return None;
+        }
+        let recipients: Vec<_> = mail.to().into_iter().chain(self.bcc_recipient(mail).cloned()).collect();
+        self.routes
+            .iter()
+            .find(|(to, _)| recipients.iter().any(|x| x.email() == to.email()))
+            .map(|(_, archive)| archive)
+    }
+
+    /// Find allowed recipient from envelope recipient headers, for mail Bcc'd
+    /// to archive address (see ArchiveCfg::accept_bcc).
+    fn bcc_recipient(&self, mail: &ParsedMail) -> Option<&EmailAddress> {
+        if !self.cfg.accept_bcc {
+            return None;
+        }
+        ["Delivered-To", "X-Original-To", "Envelope-To"]
+            .iter()
+            .flat_map(|name| mail.header_all(name))
+            .filter_map(|x| {
+                let x = x.trim();
+                x.parse::<EmailAddress>()
+                    .or_else(|_| x.trim_start_matches('<').trim_end_matches('>').parse())
+                    .ok()
+            })
+            .find_map(|x| self.cfg.allowed_to_addrs.find(&x))
+    }
+
+    fn write_letter(&self, letter: LoveLetter, action: Option<&str>, subject: &str) -> Result<LoveLetter> {
+        if self.dry_run {
+            info!("dry run, letter {} (action: {:?}) would be written to {}", letter, action, self.letter_path(&letter).display());
+            return Ok(letter);
+        }
+        // Cleanup repo before any change.
         if self.cfg.git_pre_cleanup {
-            self.letter_git_repo.cleanup()?;
+            self.letter_git_repo()?.cleanup()?;
         }
 
+        // Writing and committing is a unit: if any step fails, written files
+        // are rolled back (new files are removed, existing ones are restored)
+        // so that the mail is upserted from scratch next time, rather than
+        // being mistaken as duplicate of the uncommitted letter. Failure of
+        // pushing is not rolled back, the commit is pushed along with the
+        // next one.
+        let mut written: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::new(); // (path, previous contents)
+        let result = self.stage_letter(letter, action, &mut written).and_then(|letter| {
+            let msg = format!("[loveletter] {}\n\n{}", subject, Self::commit_body(&letter, action));
+            self.letter_git_repo()?.commit(&msg, Some(letter.from.clone()))?;
+            Ok(letter)
+        });
+        let letter = match result {
+            Ok(x) => x,
+            Err(e) => {
+                self.rollback_written(&written, &e);
+                return Err(e);
+            },
+        };
+        self.push(self.letter_git_repo()?, &self.letter_push_deferred)?;
+
+        Ok(letter)
+    }
+
+    /// Write letter (and its plaintext) and add them to index without
+    /// committing, written files are recorded in `written` for rolling back.
+    fn stage_letter(
+        &self,
+        mut letter: LoveLetter,
+        action: Option<&str>,
+        written: &mut Vec<(PathBuf, Option<Vec<u8>>)>,
+    ) -> Result<LoveLetter> {
+        let letter_path = self.letter_path(&letter);
+        let letter_exists = letter_path.exists();
+        info!(
+            "writing letter {} (action: {:?}) to {} (exist: {})...",
+            letter,
+            action,
+            letter_path.display(),
+            letter_exists
+        );
+
+        // Existing letter is overwritten whether the action is "edit" or not,
+        // the result only differs in raw_subject, which records the subject
+        // as it is.
         if letter_exists {
             warn!("editing existing letter {}: {},", letter, letter_path.display());
-            letter.created_at = LoveLetter::load(&letter_path)?.created_at;
+            let existing = LoveLetter::load(&letter_path)?;
+            letter.created_at = min_datetime(existing.created_at, letter.created_at);
+            letter.id = existing.id();
+            letter.private |= existing.private;
+            if existing == letter {
+                bail!(Error::Duplicate(letter_path));
+            }
+            if self.cfg.keep_versions > 0 {
+                self.backup_letter(&letter_path)
+                    .with_context(|| format!("failed to back up {}", letter_path.display()))?;
+            }
         }
+
         let letter_data = toml::to_string(&letter)?;
-        fs::write(&letter_path, letter_data)
+        written.push((letter_path.clone(), fs::read(&letter_path).ok()));
+        self.write_file(&letter_path, letter_data)
             .with_context(|| format!("{}", letter_path.display()))?;
         info!("wrote");
+        self.letter_git_repo()?.add(&letter_path)?;
 
-        self.letter_git_repo.add(&letter_path)?;
-        self.letter_git_repo.commit(&("[loveletter] ".to_owned() + subject), Some(from.clone()))?;
-        if !self.cfg.git_no_push {
-            self.letter_git_repo.push(self.cfg.git_retry)?;
+        if self.cfg.store_plaintext {
+            let plaintext_path = letter_path.with_extension("txt");
+            debug!("writing plaintext of letter to {}...", plaintext_path.display());
+            written.push((plaintext_path.clone(), fs::read(&plaintext_path).ok()));
+            self.write_file(&plaintext_path, letter.plaintext())
+                .with_context(|| format!("{}", plaintext_path.display()))?;
+            self.letter_git_repo()?.add(&plaintext_path)?;
+            debug!("wrote");
         }
-
         Ok(letter)
     }
 
+    /// Roll back files written by [`Archive::stage_letter`] after `e`.
+    fn rollback_written(&self, written: &[(PathBuf, Option<Vec<u8>>)], e: &anyhow::Error) {
+        if written.is_empty() {
+            return;
+        }
+        warn!("failed to write letters, rolling back: {:#}", e);
+        for (path, previous) in written.iter().rev() {
+            if let Err(e) = self.rollback_file(path, previous.as_deref()) {
+                error!("failed to roll back {}: {:#}", path.display(), e);
+            }
+        }
+    }
+
+    /// Write letters and commit them at once, see `squash_import`. The commit
+    /// is authored by the sender if all letters have the same one, authorship
+    /// of each letter is kept in body of commit message.
+    fn write_letters_squashed(&self, letters: Vec<(LoveLetter, &str)>) -> Result<Vec<LoveLetter>> {
+        if self.dry_run {
+            return letters.into_iter().map(|(letter, subject)| self.write_letter(letter, None, subject)).collect();
+        }
+        if self.cfg.git_pre_cleanup {
+            self.letter_git_repo()?.cleanup()?;
+        }
+
+        let mut written: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::new(); // (path, previous contents)
+        let result = (|| -> Result<Vec<LoveLetter>> {
+            let mut staged = Vec::new();
+            let mut body = String::new();
+            for (letter, subject) in letters {
+                let letter = match self.stage_letter(letter, None, &mut written) {
+                    Ok(x) => x,
+                    Err(e) => match e.downcast_ref::<Error>() {
+                        Some(Error::Duplicate(_)) => {
+                            info!("{}, skipped", e);
+                            continue;
+                        },
+                        _ => return Err(e),
+                    },
+                };
+                body.push_str(&format!("\n{}\n{}From: {}\n", subject, Self::commit_body(&letter, None), letter.from));
+                staged.push(letter);
+            }
+            if staged.is_empty() {
+                return Ok(staged);
+            }
+            let author = staged
+                .iter()
+                .all(|x| x.from == staged[0].from)
+                .then(|| staged[0].from.clone());
+            let msg = format!("[loveletter] import {} letters\n{}", staged.len(), body);
+            self.letter_git_repo()?.commit(&msg, author)?;
+            Ok(staged)
+        })();
+        let letters = match result {
+            Ok(x) => x,
+            Err(e) => {
+                self.rollback_written(&written, &e);
+                return Err(e);
+            },
+        };
+        if !letters.is_empty() {
+            self.push(self.letter_git_repo()?, &self.letter_push_deferred)?;
+        }
+
+        Ok(letters)
+    }
+
+    /// Body of commit message, fields parsed from subject for `git log`.
+    fn commit_body(letter: &LoveLetter, action: Option<&str>) -> String {
+        let mut body = format!("Date: {}\n", letter.date);
+        if let Some(title) = &letter.title {
+            body.push_str(&format!("Title: {}\n", title));
+        }
+        if let Some(action) = action {
+            body.push_str(&format!("Action: {}\n", action));
+        }
+        body.push_str(&format!("Author: {}\n", letter.author()));
+        body
+    }
+
+    /// Keep previous version of letter as "NAME.toml.bak.1", older ones are
+    /// shifted to ".bak.2" and so on, at most keep_versions of them are kept.
+    fn backup_letter(&self, path: &Path) -> Result<()> {
+        let backup_path = |i: usize| {
+            let mut p = path.as_os_str().to_owned();
+            p.push(format!(".bak.{}", i));
+            PathBuf::from(p)
+        };
+        for i in (1..self.cfg.keep_versions).rev() {
+            if backup_path(i).exists() {
+                fs::rename(backup_path(i), backup_path(i + 1))?;
+            }
+        }
+        debug!("backing up {} to {}...", path.display(), backup_path(1).display());
+        fs::copy(path, backup_path(1))?;
+        Ok(())
+    }
+
+    /// Push repo to remote unless git_no_push, the push is deferred if it is
+    /// quiet hours now.
+    fn push(&self, repo: &Repo, deferred: &Cell<bool>) -> Result<()> {
+        if self.cfg.git_no_push {
+            return Ok(());
+        }
+        if self.cfg.quiet_hours.as_ref().is_some_and(|x| x.contains(Utc::now())) {
+            info!("quiet hours, push is deferred");
+            deferred.set(true);
+            return Ok(());
+        }
+        repo.push(self.cfg.git_retry)?;
+        deferred.set(false);
+        Ok(())
+    }
+
+    /// Push changes committed during quiet hours, if they are over.
+    pub fn push_deferred(&self) -> Result<()> {
+        for (_, archive) in self.routes.iter() {
+            archive.push_deferred()?;
+        }
+        if self.letter_push_deferred.get() {
+            self.push(self.letter_git_repo()?, &self.letter_push_deferred)?;
+        }
+        if self.rstdoc_push_deferred.get() {
+            self.push(self.rstdoc_git_repo()?, &self.rstdoc_push_deferred)?;
+        }
+        Ok(())
+    }
+
+    /// Restore file written by write_letter to its previous contents, or
+    /// remove it if it didn't exist.
+    fn rollback_file(&self, path: &Path, previous: Option<&[u8]>) -> Result<()> {
+        match previous {
+            Some(x) => {
+                self.write_file(path, x)?;
+                self.letter_git_repo()?.add(path)?;
+            },
+            None => {
+                self.letter_git_repo()?.unstage(path)?;
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
     pub fn letter_path(&self, letter: &LoveLetter) -> PathBuf {
         let mut p = self.letter_dir.clone();
-        p.push(letter.letter_filename());
+        p.push(letter.letter_filename(&self.letter_filename_pattern));
         p
     }
 
-    pub fn generate_rstdoc(&self) -> Result<()> {
-        // Generate index.rst
-        let index_path = self.rstdoc_index_path();
-        info!("generating love letter index {}...", index_path.display());
-        fs::write(
-            &index_path,
-            "\
+    /// Generate all outputs (see ArchiveCfg::outputs) of archive and its
+    /// sub-archives, files of all outputs are committed at once.
+    pub fn generate_all(&self) -> Result<()> {
+        self.generate_with(GenerateOptions::default())
+    }
+
+    /// Like [`Archive::generate_all`], pages of frozen years (see
+    /// `frozen_years`) are re-generated too.
+    pub fn generate_full(&self) -> Result<()> {
+        self.generate_with(GenerateOptions { full: true, ..GenerateOptions::default() })
+    }
+
+    /// Like [`Archive::generate_all`], with options.
+    pub fn generate_with(&self, opts: GenerateOptions) -> Result<()> {
+        if self.dry_run {
+            info!("dry run, skip output generation");
+            return Ok(());
+        }
+        for (to, archive) in self.routes.iter() {
+            info!("generating outputs of recipient {}...", to);
+            archive.generate_with(opts)?;
+        }
+        self.generate_own_all(opts)
+    }
+
+    fn generate_own_all(&self, opts: GenerateOptions) -> Result<()> {
+        if !self.cfg.generate_docs {
+            debug!("generate_docs is disabled, skip output generation");
+            return Ok(());
+        }
+
+        let mut years: BTreeMap<i32, Vec<LoveLetter>> = BTreeMap::new();
+        let (mut loaded, mut failed) = (0, 0);
+        let mut recent = Vec::new(); // letters are iterated from newest to oldest
+        for (entry, letter) in self.iter_letters()? {
+            let letter = match letter {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("failed to load letter {}, skipped: {:#}", entry.display(), e);
+                    failed += 1;
+                    continue;
+                },
+            };
+            loaded += 1;
+            if letter.private && !opts.include_private {
+                debug!("letter {} is private, skipped", letter);
+                continue;
+            }
+            if recent.len() < self.cfg.index_recent {
+                recent.push(letter.clone());
+            }
+            years.entry(letter.date.year).or_default().push(letter);
+        }
+        if loaded == 0 && failed != 0 {
+            bail!("none of {} letters can be loaded", failed);
+        }
+
+        let frozen = match (opts.full, self.cfg.frozen_years) {
+            (false, Some(n)) => {
+                let before = Utc::now().year() - n as i32;
+                years.keys().copied().filter(|&y| y < before).collect()
+            },
+            _ => BTreeSet::new(),
+        };
+        if !frozen.is_empty() {
+            debug!("years {:?} are frozen, their existing pages are kept as is", frozen);
+        }
+
+        let mut files: HashMap<PathBuf, String> = HashMap::new();
+        for output in self.cfg.outputs.iter() {
+            info!("generating {:?} output...", output);
+            match output {
+                OutputFormat::Rst => self.generate_rstdoc(&years, &frozen, &recent, &mut files)?,
+                OutputFormat::Markdown => self.generate_markdown(&years, &frozen, &mut files),
+            }
+        }
+
+        // Cleanup repo before any change.
+        if self.cfg.git_pre_cleanup {
+            self.letter_git_repo()?.cleanup()?;
+        }
+
+        for (file, content) in files.iter() {
+            debug!("writing letters to {}...", file.display());
+            if let Some(dir) = file.parent() {
+                retry_fs(self.cfg.fs_retry, || fs::create_dir_all(dir))
+                    .with_context(|| format!("{}", dir.display()))?;
+            }
+            self.write_rstdoc(file, content)?;
+            debug!("wrote");
+        }
+
+        if !self.rstdoc_git_repo()?.has_staged_changes()? {
+            info!("rstdoc is unchanged, skip commit");
+            return Ok(());
+        }
+        self.rstdoc_git_repo()?.commit("[loveletter] generate rstdoc", None)?;
+        self.push(self.rstdoc_git_repo()?, &self.rstdoc_push_deferred)?;
+
+        Ok(())
+    }
+
+    /// Render index, year pages and links of letters in reStructuredText.
+    /// Pages of frozen years are skipped unless they don't exist yet, their
+    /// letters are still linked.
+    fn generate_rstdoc(
+        &self,
+        years: &BTreeMap<i32, Vec<LoveLetter>>,
+        frozen: &BTreeSet<i32>,
+        recent: &[LoveLetter],
+        files: &mut HashMap<PathBuf, String>,
+    ) -> Result<()> {
+        // Index (index.rst by default), year pages are in rstdoc_dir, toctree
+        // entries are relative to index.
+        let depth = Path::new(&self.cfg.index_filename).components().count() - 1;
+        // Parts of paginated year (YEAR-N) are linked from the year page, so
+        // only year pages are globbed then.
+        let glob = match self.cfg.max_sections_per_page {
+            Some(_) => "????",
+            None => "*",
+        };
+        files.insert(self.rstdoc_index_path(), format!("\
 ===============
 💌 Love Letters
 ===============
@@ -436,16 +1789,132 @@ impl Archive {
 .. hint::
    Generated from :ghrepo:`SilverRainZ/loveletter`.
 
-.. toctree::
+{}.. toctree::
    :glob:
    :reversed:
 
-   *
-",
-        )?;
-        self.rstdoc_git_repo.add(&index_path)?;
-        info!("generated");
+   {}{}
+", self.rstdoc_recent(recent), "../".repeat(depth), glob));
+
+        let mut links: Vec<String> = Vec::new();
+        for (year, letters) in years.iter().rev() {
+            let pages = self.paginate(letters);
+            let heading = letters[0].rstdoc_heading();
+            let frozen = frozen.contains(year) && self.rstdoc_page_path(*year, None).exists();
+            if frozen {
+                for (i, page) in pages.iter().enumerate() {
+                    let i = if pages.len() == 1 { None } else { Some(i + 1) };
+                    links.extend(page.iter().filter_map(|x| self.letter_url_in(x, i)));
+                }
+                continue;
+            }
+            if pages.len() == 1 {
+                links.extend(letters.iter().filter_map(|x| self.letter_url_in(x, None)));
+                let content = letters.iter().fold(heading, |acc, x| acc + &self.rstdoc_section(x));
+                files.insert(self.rstdoc_page_path(*year, None), content);
+                continue;
+            }
+
+            // Landing page of year, from newest part to oldest part.
+            let mut landing = heading.clone() + ".. toctree::\n\n";
+            for i in (1..=pages.len()).rev() {
+                landing.push_str(&format!("   {}\n", rstdoc_page_name(*year, Some(i))));
+            }
+            files.insert(self.rstdoc_page_path(*year, None), landing);
+
+            for (i, page) in pages.iter().enumerate().rev() {
+                let i = i + 1;
+                links.extend(page.iter().filter_map(|x| self.letter_url_in(x, Some(i))));
+                let nav = rstdoc_page_nav(*year, i, pages.len());
+                let content = page.iter().fold(
+                    letters[0].rstdoc_page_heading(i, pages.len()) + &nav + "\n",
+                    |acc, x| acc + &self.rstdoc_section(x));
+                files.insert(self.rstdoc_page_path(*year, Some(i)), content + &nav);
+            }
+        }
+
+        // Links of letters for static site integration, from newest to oldest.
+        if self.cfg.base_url.is_some() {
+            debug!("{} links of letters", links.len());
+            files.insert(self.rstdoc_links_path(), links.join("\n") + "\n");
+        }
+        Ok(())
+    }
+
+    /// Render a Markdown page per year, pages are not paginated.
+    fn generate_markdown(
+        &self,
+        years: &BTreeMap<i32, Vec<LoveLetter>>,
+        frozen: &BTreeSet<i32>,
+        files: &mut HashMap<PathBuf, String>,
+    ) {
+        for (year, letters) in years.iter() {
+            if frozen.contains(year) && self.markdown_page_path(*year).exists() {
+                continue;
+            }
+            let sections: Vec<String> = letters
+                .iter()
+                .map(|x| x.render_with(RenderFormat::Markdown, &self.render_options(x)))
+                .collect();
+            let content = format!("# 💌 Love Letters from {}\n\n{}", year, sections.join("\n"));
+            files.insert(self.markdown_page_path(*year), content);
+        }
+    }
+
+    /// Render letters in date range as a single document, with title, author,
+    /// date and excerpt of each letter, from oldest to newest. It is in the
+    /// first format of `outputs`. Private letters and letters of sub-archives
+    /// are not included.
+    pub fn digest(&self, range: &DateRange) -> Result<String> {
+        let mut letters = Vec::new();
+        for (entry, letter) in self.iter_letters()? {
+            match letter {
+                Ok(x) if range.contains(&x.date) && !x.private => letters.push(x),
+                Ok(_) => (),
+                Err(e) => error!("failed to load letter {}, skipped: {:#}", entry.display(), e),
+            }
+        }
+        letters.reverse();
+        debug!("{} letters in {}", letters.len(), range);
+
+        let untitled_template = self.cfg.untitled_title_template.as_deref();
+        let title = format!("💌  Love Letters of {}", range);
+        let mut buf = match self.cfg.outputs.first() {
+            Some(OutputFormat::Markdown) => format!("# {}\n", title),
+            _ => {
+                let delim = rst_underline(&title, '=');
+                format!("{}\n{}\n{}\n", delim, title, delim)
+            },
+        };
+        for letter in letters.iter() {
+            let title = letter.title_with_prefix(None, untitled_template);
+            let excerpt = letter.excerpt(Self::DIGEST_EXCERPT_CHARS);
+            buf.push_str(&match self.cfg.outputs.first() {
+                Some(OutputFormat::Markdown) => format!(
+                    "\n## {}\n\n*{}, {}*\n\n{}\n", title, letter.author(), letter.date, excerpt),
+                _ => format!(
+                    "\n{}\n{}\n\n:author: {}\n:date: {}\n\n{}\n",
+                    title, rst_underline(&title, '-'), letter.author(), letter.date, excerpt),
+            });
+        }
+        Ok(buf)
+    }
+
+    /// List of links to recent letters on index, empty if there is none.
+    fn rstdoc_recent(&self, letters: &[LoveLetter]) -> String {
+        if letters.is_empty() {
+            return String::new();
+        }
+        letters.iter().fold(".. rubric:: Recent Letters\n\n".to_string(), |acc, x| {
+            let title = x.title_with_prefix(self.title_prefix(x), self.cfg.untitled_title_template.as_deref())
+                .replace('<', "\\<");
+            acc + &format!("- :ref:`{} <{}>`\n", title, x.anchor())
+        }) + "\n"
+    }
 
+    /// Iterate over letters in letter dir from newest to oldest, yields path
+    /// of letter file and the loaded letter.
+    pub fn iter_letters(&self) -> Result<impl Iterator<Item = (PathBuf, Result<LoveLetter>)>> {
         info!("listing letter dir {}...", self.letter_dir.display());
         let mut entries: Vec<_> = fs::read_dir(&self.letter_dir)?
             .map(|e| e.map(|e| e.path()))
@@ -460,69 +1929,450 @@ impl Archive {
             entries
         );
 
-        // Letter's filename are prefixed with YYYY-MM-DD (see LoveLetter::letter_filename)
+        // Letter's filename are prefixed with YYYY-MM-DD (see FilenamePattern::parse)
         // sort by newest to oldest.
         entries.sort();
         entries.reverse();
 
-        let mut files: HashMap<PathBuf, String> = HashMap::new();
-        for entry in entries {
-            let letter = LoveLetter::load(entry)?;
-            let file = self.rstdoc_path(&letter);
-            if let Some(content) = files.get_mut(&file) {
-                (*content).push_str(&letter.rstdoc_section());
-            } else {
-                files.insert(file, letter.rstdoc_heading() + &letter.rstdoc_section());
+        Ok(entries.into_iter().map(|e| {
+            let letter = LoveLetter::load(&e).with_context(|| format!("{}", e.display()));
+            (e, letter)
+        }))
+    }
+
+    /// Find rstdoc files with no corresponding letters and empty letter files,
+    /// remove them and re-generate rstdoc if `fix` is true.
+    pub fn prune(&self, fix: bool) -> Result<PruneReport> {
+        let mut report = PruneReport::default();
+        for (_, archive) in self.routes.iter() {
+            let x = archive.prune(fix)?;
+            report.orphaned_rstdocs.extend(x.orphaned_rstdocs);
+            report.empty_letters.extend(x.empty_letters);
+        }
+        let x = self.prune_own(fix)?;
+        report.orphaned_rstdocs.extend(x.orphaned_rstdocs);
+        report.empty_letters.extend(x.empty_letters);
+        Ok(report)
+    }
+
+    fn prune_own(&self, fix: bool) -> Result<PruneReport> {
+        let mut years: BTreeMap<i32, Vec<LoveLetter>> = BTreeMap::new();
+        let mut report = PruneReport::default();
+        for (entry, letter) in self.iter_letters()? {
+            if fs::metadata(&entry)?.len() == 0 {
+                report.empty_letters.push(entry);
+                continue;
+            }
+            if let Ok(letter) = letter {
+                years.entry(letter.date.year).or_default().push(letter);
             }
         }
 
-        // Cleanup repo before any change.
-        if self.cfg.git_pre_cleanup {
-            self.letter_git_repo.cleanup()?;
+        let rstdoc_entries = match self.cfg.generate_docs {
+            true => {
+                info!("listing rstdoc dir {}...", self.rstdoc_dir.display());
+                fs::read_dir(&self.rstdoc_dir)?.collect::<io::Result<Vec<_>>>()?
+            },
+            false => Vec::new(),
+        };
+        for entry in rstdoc_entries {
+            let entry = entry.path();
+            if !entry.is_file() || entry.extension() != Some(OsStr::new("rst")) {
+                continue;
+            }
+            let Some(name) = entry.file_stem().and_then(OsStr::to_str) else { continue };
+            // Document name is "YEAR" or "YEAR-PAGE", see rstdoc_page_name.
+            let (year, page) = match name.split_once('-') {
+                Some((year, page)) => (year, page.parse::<usize>().ok()),
+                None => (name, None),
+            };
+            let Ok(year) = year.parse::<i32>() else { continue };
+            let expected = match years.get(&year) {
+                None => false,
+                Some(letters) => {
+                    let pages = self.paginate(letters).len();
+                    page.is_none_or(|i| pages > 1 && i >= 1 && i <= pages)
+                },
+            };
+            if !expected {
+                report.orphaned_rstdocs.push(entry);
+            }
         }
+        report.orphaned_rstdocs.sort();
+        info!(
+            "found {} orphaned rstdoc files: {:?}, {} empty letter files: {:?}",
+            report.orphaned_rstdocs.len(),
+            report.orphaned_rstdocs,
+            report.empty_letters.len(),
+            report.empty_letters,
+        );
 
-        for (file, content) in files.iter() {
-            debug!("writing letters to {}...", file.display());
-            fs::write(file, content)?;
-            debug!("wrote");
-            self.rstdoc_git_repo.add(file)?;
+        if !fix || report.is_empty() {
+            return Ok(report);
         }
 
-        self.rstdoc_git_repo.commit("[loveletter] generate rstdoc", None)?;
-        if !self.cfg.git_no_push {
-            self.rstdoc_git_repo.push(self.cfg.git_retry)?;
+        info!("pruning...");
+        for entry in report.empty_letters.iter() {
+            self.letter_git_repo()?.rm(entry)?;
+        }
+        if self.letter_git_repo()?.has_staged_changes()? {
+            self.letter_git_repo()?.commit("[loveletter] prune empty letters", None)?;
+            self.push(self.letter_git_repo()?, &self.letter_push_deferred)?;
         }
+        for entry in report.orphaned_rstdocs.iter() {
+            self.rstdoc_git_repo()?.rm(entry)?;
+        }
+        // Removal is committed along with re-generated rstdoc.
+        self.generate_own_all(GenerateOptions::default())?;
+        info!("pruned");
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// Find letters with the same content (timestamps are ignored) under
+    /// different filenames, for example archived before and after changing
+    /// letter_filename_pattern. Returns (duplicate, kept) pairs, the letter
+    /// created earliest is kept. Duplicates are removed if `fix` is true.
+    pub fn dedup(&self, fix: bool) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut duplicates = Vec::new();
+        for (_, archive) in self.routes.iter() {
+            duplicates.extend(archive.dedup(fix)?);
+        }
+        duplicates.extend(self.dedup_own(fix)?);
+        Ok(duplicates)
+    }
+
+    fn dedup_own(&self, fix: bool) -> Result<Vec<(PathBuf, PathBuf)>> {
+        // Keyed by serialized letter rather than its hash, so that letters are
+        // never removed due to hash collision.
+        let mut groups: HashMap<String, Vec<(PathBuf, LoveLetter)>> = HashMap::new();
+        for (entry, letter) in self.iter_letters()? {
+            let Ok(letter) = letter else { continue };
+            let key = LoveLetter { created_at: None, updated_at: None, ..letter.clone() };
+            groups.entry(toml::to_string(&key)?).or_default().push((entry, letter));
+        }
+        let mut duplicates = Vec::new();
+        for mut group in groups.into_values().filter(|x| x.len() > 1) {
+            // Earliest created first, letters without created_at go last.
+            group.sort_by(|(p1, l1), (p2, l2)| match (l1.created_at, l2.created_at) {
+                (Some(_), None) => cmp::Ordering::Less,
+                (None, Some(_)) => cmp::Ordering::Greater,
+                (t1, t2) => t1.cmp(&t2).then(p1.cmp(p2)),
+            });
+            let (kept, _) = &group[0];
+            duplicates.extend(group[1..].iter().map(|(p, _)| (p.clone(), kept.clone())));
+        }
+        duplicates.sort();
+        info!("found {} duplicate letters: {:?}", duplicates.len(), duplicates);
+
+        if !fix || duplicates.is_empty() {
+            return Ok(duplicates);
+        }
+
+        info!("removing duplicate letters...");
+        for (entry, _) in duplicates.iter() {
+            self.letter_git_repo()?.rm(entry)?;
+            let plaintext_path = entry.with_extension("txt");
+            if plaintext_path.exists() {
+                self.letter_git_repo()?.rm(&plaintext_path)?;
+            }
+        }
+        if self.letter_git_repo()?.has_staged_changes()? {
+            self.letter_git_repo()?.commit("[loveletter] remove duplicate letters", None)?;
+            self.push(self.letter_git_repo()?, &self.letter_push_deferred)?;
+        }
+        self.generate_own_all(GenerateOptions::default())?;
+        info!("removed");
+
+        Ok(duplicates)
+    }
+
+    /// Export letter files of letter dir (routes are not included) to a
+    /// gzip-compressed tarball, returns the number of exported letters.
+    /// Files are streamed by tar as they are, so that restoring reproduces
+    /// them byte-for-byte.
+    pub fn export(&self, path: &Path) -> Result<usize> {
+        info!("exporting letters of {} to {}...", self.letter_dir.display(), path.display());
+        let mut filenames = String::new();
+        let mut n = 0;
+        for (entry, _) in self.iter_letters()? {
+            let filename = entry.file_name().and_then(OsStr::to_str)
+                .with_context(|| format!("non UTF-8 filename {}", entry.display()))?;
+            filenames.push_str(filename);
+            filenames.push('\n');
+            n += 1;
+        }
+        let sh = Shell::new()?;
+        let dir = &self.letter_dir;
+        cmd!(sh, "tar --create --gzip --file {path} --directory {dir} --files-from -")
+            .stdin(filenames)
+            .run()?;
+        info!("exported {} letters", n);
+        Ok(n)
+    }
+
+    /// Restore letter files from tarball written by [`Archive::export`], and
+    /// commit them at once. The tarball is extracted to a staging directory
+    /// and all letters are loaded before any of them is written, so that a
+    /// corrupted tarball leaves the archive untouched.
+    pub fn restore(&self, path: &Path) -> Result<usize> {
+        info!("restoring letters from {} to {}...", path.display(), self.letter_dir.display());
+        let sh = Shell::new()?;
+        let filenames = cmd!(sh, "tar --list --gzip --file {path}").read()?;
+        let filenames: Vec<&str> = filenames.lines().collect();
+        for filename in filenames.iter() {
+            if Path::new(filename).components().count() != 1 || !filename.ends_with(".toml") {
+                bail!(Error::Parse(format!("unexpected letter filename {:?} in {}", filename, path.display())));
+            }
+        }
+
+        // Staged in git dir, which is never shown as untracked.
+        let staging_dir = self.letter_git_repo()?.git_dir()?.join("loveletter-restore");
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir_all(&staging_dir)?;
+        let result = (|| -> Result<bool> {
+            cmd!(sh, "tar --extract --gzip --file {path} --directory {staging_dir}").run()?;
+            for filename in filenames.iter() {
+                LoveLetter::load(staging_dir.join(filename))
+                    .with_context(|| format!("malformed letter {} in {}", filename, path.display()))?;
+            }
+            if self.dry_run {
+                info!("dry run, {} letters would be restored", filenames.len());
+                return Ok(false);
+            }
+
+            let mut written: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::new(); // (path, previous contents)
+            let result = (|| -> Result<bool> {
+                for filename in filenames.iter() {
+                    let path = self.letter_dir.join(filename);
+                    debug!("restoring letter {}...", path.display());
+                    written.push((path.clone(), fs::read(&path).ok()));
+                    let data = fs::read(staging_dir.join(filename))?;
+                    self.write_file(&path, data).with_context(|| format!("{}", path.display()))?;
+                    self.letter_git_repo()?.add(&path)?;
+                }
+                if self.letter_git_repo()?.has_staged_changes()? {
+                    let msg = format!("[loveletter] restore {} letters", filenames.len());
+                    self.letter_git_repo()?.commit(&msg, None)?;
+                    return Ok(true);
+                }
+                Ok(false)
+            })();
+            if let Err(e) = &result {
+                self.rollback_written(&written, e);
+            }
+            result
+        })();
+        if let Err(e) = fs::remove_dir_all(&staging_dir) {
+            warn!("failed to remove {}: {}", staging_dir.display(), e);
+        }
+        if result? {
+            self.push(self.letter_git_repo()?, &self.letter_push_deferred)?;
+        }
+        info!("restored {} letters", filenames.len());
+        Ok(filenames.len())
+    }
+
+    /// Write and stage the rstdoc file, returns false if its content is unchanged.
+    /// The generation stamp (see ArchiveCfg::stamp_generated) is not compared,
+    /// so it is the time when content last changed.
+    fn write_rstdoc(&self, path: &Path, content: &str) -> Result<bool> {
+        let unchanged = fs::read_to_string(path).is_ok_and(|x| match self.cfg.stamp_generated {
+            true => strip_generated_stamp(&x) == content,
+            false => x == content,
+        });
+        if unchanged {
+            debug!("{} is unchanged, skipped", path.display());
+            return Ok(false);
+        }
+        let content = match self.cfg.stamp_generated {
+            true => generated_stamp(path, Utc::now()).unwrap_or_default() + content,
+            false => content.to_owned(),
+        };
+        self.write_file(path, content)
+            .with_context(|| format!("{}", path.display()))?;
+        self.rstdoc_git_repo()?.add(path)?;
+        Ok(true)
+    }
+
+    /// Write file atomically, retry on transient failure (see ArchiveCfg::fs_retry).
+    fn write_file<C: AsRef<[u8]>>(&self, path: &Path, contents: C) -> io::Result<()> {
+        retry_fs(self.cfg.fs_retry, || write_atomic(path, &contents))
+    }
+
+    /// URL of letter in the website built from rstdoc, None if `base_url` is unset.
+    pub fn letter_url(&self, letter: &LoveLetter) -> Option<String> {
+        if let Some((_, archive)) = self.routes.iter().find(|(to, _)| to.email() == letter.to.email()) {
+            return archive.letter_url(letter);
+        }
+        let mut page = None;
+        if self.cfg.max_sections_per_page.is_some() {
+            // Find out the page by listing all letters in the same year.
+            let letters: Vec<_> = self.iter_letters().ok()?
+                .filter_map(|(_, x)| x.ok())
+                .filter(|x| x.date.year == letter.date.year)
+                .collect();
+            let pages = self.paginate(&letters);
+            if pages.len() > 1 {
+                page = pages.iter().position(|p| p.iter().any(|x| x.anchor() == letter.anchor())).map(|i| i + 1);
+            }
+        }
+        self.letter_url_in(letter, page)
+    }
+
+    /// URL of letter in given page of year, see Archive::paginate.
+    fn letter_url_in(&self, letter: &LoveLetter, page: Option<usize>) -> Option<String> {
+        let base_url = self.cfg.base_url.as_deref()?.trim_end_matches('/');
+        let page = rstdoc_page_name(letter.date.year, page) + ".html";
+        Some(format!("{}/{}#{}", base_url, page, letter.anchor()))
+    }
+
+    fn rstdoc_section(&self, letter: &LoveLetter) -> String {
+        letter.render_with(RenderFormat::Rst, &self.render_options(letter))
+    }
+
+    fn render_options<'a>(&'a self, letter: &LoveLetter) -> RenderOptions<'a> {
+        RenderOptions {
+            nick: self.display_name(&letter.from),
+            title_prefix: self.title_prefix(letter),
+            untitled_title_template: self.cfg.untitled_title_template.as_deref(),
+            content_class: self.cfg.content_class.as_deref(),
+            directive: &self.cfg.directive_name,
+            source: self.cfg.render_source,
+        }
+    }
+
+    /// Canonical display name of address for rendering, see `ArchiveCfg::display_names`.
+    fn display_name(&self, addr: &EmailAddress) -> Option<&str> {
+        let names = &self.cfg.display_names;
+        names.get(addr.email().as_str())
+            .or_else(|| names.get(addr.display_part().trim()))
+            .map(String::as_str)
+    }
+
+    fn title_prefix(&self, letter: &LoveLetter) -> Option<&str> {
+        match letter.from_meimei_if_true_and_gege_if_false {
+            true => self.cfg.meimei_title_prefix.as_deref(),
+            false => self.cfg.gege_title_prefix.as_deref(),
+        }
+    }
+
+    /// Split letters of a year (from newest to oldest) into pages when it
+    /// exceeds `max_sections_per_page`. Pages are counted from the oldest
+    /// letter, so that existing pages (and letter URLs) are stable when new
+    /// letters arrive.
+    fn paginate<'a>(&self, letters: &'a [LoveLetter]) -> Vec<&'a [LoveLetter]> {
+        match self.cfg.max_sections_per_page {
+            Some(n) if letters.len() > n => letters.rchunks(n).collect(),
+            _ => vec![letters],
+        }
     }
 
     pub fn rstdoc_path(&self, letter: &LoveLetter) -> PathBuf {
+        self.rstdoc_page_path(letter.date.year, None)
+    }
+
+    /// Path of year page, or path of its N-th part if year is paginated.
+    pub fn rstdoc_page_path(&self, year: i32, page: Option<usize>) -> PathBuf {
+        let mut p = self.rstdoc_dir.clone();
+        p.push(rstdoc_page_name(year, page) + ".rst");
+        p
+    }
+
+    /// Markdown page of year, in "markdown" sub-directory so that its document
+    /// name doesn't collide with the rst one when Sphinx reads Markdown too.
+    pub fn markdown_page_path(&self, year: i32) -> PathBuf {
+        let mut p = self.rstdoc_dir.clone();
+        p.push("markdown");
+        p.push(format!("{}.md", year));
+        p
+    }
+
+    pub fn rstdoc_links_path(&self) -> PathBuf {
         let mut p = self.rstdoc_dir.clone();
-        p.push(letter.rstdoc_filename());
+        p.push("links.txt");
         p
     }
 
     pub fn rstdoc_index_path(&self) -> PathBuf {
         let mut p = self.rstdoc_dir.clone();
         // https://www.sphinx-doc.org/en/master/usage/configuration.html#confval-master_doc
-        p.push("index.rst");
+        p.push(&self.cfg.index_filename);
         p
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::cfg::Cfg;
     use crate::mail::RawMail;
     use tempfile::{tempdir, TempDir};
 
+    #[test]
+    fn test_date_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Wrapper {
+            date: Date,
+        }
+
+        for year in [1, 998, 2025, 9999] {
+            for month in [None, Some(1), Some(2), Some(12)] {
+                let days = match month {
+                    Some(2) => vec![None, Some(1), Some(28)],
+                    Some(_) => vec![None, Some(1), Some(9), Some(31)],
+                    None => vec![None],
+                };
+                for day in days {
+                    let date = Date { year, month, day };
+                    let s = date.to_string();
+                    assert_eq!(Date::from_filename(&s).unwrap(), date, "{}", s);
+                    let w = Wrapper { date: date.clone() };
+                    let data = toml::to_string(&w).unwrap();
+                    assert_eq!(data, format!("date = \"{}\"\n", s));
+                    assert_eq!(toml::from_str::<Wrapper>(&data).unwrap(), w);
+                    if month.is_some() {
+                        assert_eq!(Date::from_subject(&s.replace('-', "/")).unwrap(), date);
+                    }
+                }
+            }
+        }
+        assert_eq!(Date { year: 2025, month: None, day: None }.to_string(), "2025");
+        assert_eq!(Date { year: 998, month: Some(1), day: None }.to_string(), "0998-01");
+
+        // Only the canonical form is parsed from filename, so that it round-trips too.
+        for s in ["", "2025-", "2025-4-3", "2025-04-3", "+2025-04", "25-04-03", "2025-13", "2025-02-30", "2025-04-03-01", "2025/04/03"] {
+            assert!(Date::from_filename(s).is_err(), "{}", s);
+        }
+        assert_eq!(Date::from_subject("2025/4/3").unwrap(), Date { year: 2025, month: Some(4), day: Some(3) });
+        assert!(Date::from_subject("2025").is_err());
+        assert!(Date::from_subject("2025/02/30").is_err());
+    }
+
     #[test]
     fn test_archive_parse_subject() {
+        assert_eq!(
+            Archive::parse_subject("2025/04/03: Re: your note").unwrap(),
+            (Date{ year: 2025, month: Some(4), day: Some(3) }, Some("Re: your note".to_string()), None)
+        );
+        assert_eq!(
+            Archive::parse_subject("[edit] 2025/04/03：回复: 你的纸条").unwrap(),
+            (
+                Date{ year: 2025, month: Some(4), day: Some(3) },
+                Some("回复: 你的纸条".to_string()),
+                Some("edit".to_string())
+            )
+        );
+        assert_eq!(
+            Archive::parse_subject("2025/04/03: 回复：你的纸条: 1").unwrap().1,
+            Some("回复：你的纸条: 1".to_string())
+        );
         assert_eq!(
             Archive::parse_subject("[edit] 1998/01/28: 妹妹生日快乐").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 Some("妹妹生日快乐".to_string()),
                 Some("edit".to_string())
             )
@@ -530,7 +2380,7 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("[edit] 1998/01/28:妹妹生日快乐").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 Some("妹妹生日快乐".to_string()),
                 Some("edit".to_string())
             )
@@ -538,7 +2388,7 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("[edit]1998/01/28:妹妹生日快乐").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 Some("妹妹生日快乐".to_string()),
                 Some("edit".to_string())
             )
@@ -546,7 +2396,7 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("[edit] 1998/01/28").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 None,
                 Some("edit".to_string())
             )
@@ -554,7 +2404,7 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("[edit]1998/01/28").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 None,
                 Some("edit".to_string())
             )
@@ -562,7 +2412,7 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("[edit] 1998/01/28:").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 None,
                 Some("edit".to_string())
             )
@@ -570,7 +2420,7 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("1998/01/28: 妹妹生日快乐").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 Some("妹妹生日快乐".to_string()),
                 None
             )
@@ -578,44 +2428,130 @@ mod tests {
         assert_eq!(
             Archive::parse_subject("1998/01/28:妹妹生日快乐").unwrap(),
             (
-                Date{ year: 1998, month: 1, day: Some(28) },
+                Date{ year: 1998, month: Some(1), day: Some(28) },
                 Some("妹妹生日快乐".to_string()),
                 None
             )
         );
         assert_eq!(
             Archive::parse_subject("1998/01/28:").unwrap(),
-            (Date{ year: 1998, month: 1, day: Some(28) }, None, None)
+            (Date{ year: 1998, month: Some(1), day: Some(28) }, None, None)
         );
         assert_eq!(
             Archive::parse_subject("1998/01/28").unwrap(),
-            (Date{ year: 1998, month: 1, day: Some(28) }, None, None)
+            (Date{ year: 1998, month: Some(1), day: Some(28) }, None, None)
         );
-    }
 
-    #[test]
-    fn test_archive_upsert_letter() {
+        // Mixed-case actions.
+        assert_eq!(
+            Archive::parse_subject("[Edit] 1998/01/28: 妹妹生日快乐").unwrap(),
+            (
+                Date{ year: 1998, month: Some(1), day: Some(28) },
+                Some("妹妹生日快乐".to_string()),
+                Some("edit".to_string())
+            )
+        );
+        assert_eq!(
+            Archive::parse_subject("[EDIT]1998/01/28").unwrap(),
+            (Date{ year: 1998, month: Some(1), day: Some(28) }, None, Some("edit".to_string()))
+        );
+        assert_eq!(
+            Archive::parse_subject("[ eDiT ] 1998/01/28:").unwrap(),
+            (Date{ year: 1998, month: Some(1), day: Some(28) }, None, Some("edit".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_filename_pattern() {
+        let mut letter = LoveLetter {
+            from: EmailAddress::new_unchecked("妹妹 <meimei@example.com>"),
+            to: EmailAddress::new_unchecked("Love Letter <loveletter@example.com>"),
+            from_meimei_if_true_and_gege_if_false: true,
+            id: None,
+            source_account: None,
+            source_folder: None,
+            created_at: None,
+            updated_at: None,
+            date: Date{ year: 1998, month: Some(1), day: Some(28) },
+            title: Some("Happy Birthday!".to_string()),
+            raw_subject: None,
+            content: "".to_string(),
+            attachments: Vec::new(),
+            private: false,
+            lang: None,
+        };
+
+        let default = FilenamePattern::parse("{date}[_{title}]").unwrap();
+        assert_eq!(letter.letter_filename(&default), "1998-01-28_SGFwcHkgQmlydGhkYXkh.toml");
+        let custom = FilenamePattern::parse("{date}-{author}[-{slug}][-{hash}]").unwrap();
+        assert_eq!(letter.letter_filename(&custom), "1998-01-28-妹妹-happy-birthday-75748bd9.toml");
+
+        letter.title = None;
+        assert_eq!(letter.letter_filename(&default), "1998-01-28.toml");
+        assert_eq!(letter.letter_filename(&custom), "1998-01-28-妹妹.toml");
+
+        assert!(FilenamePattern::parse("{slug}_{date}").is_err()); // not start with date
+        assert!(FilenamePattern::parse("{date}_{slug}").is_err()); // not unique
+        assert!(FilenamePattern::parse("{date}/{title}").is_err()); // not filesystem-safe
+        assert!(FilenamePattern::parse("{date}_{name}").is_err()); // unknown placeholder
+        assert!(FilenamePattern::parse("{date}[_[{title}]]").is_err()); // nested brackets
+        assert!(FilenamePattern::parse("{date}[_{title}").is_err()); // unmatched brackets
+        assert!(FilenamePattern::parse("{date}_{title").is_err()); // unmatched curly brackets
+        assert!(FilenamePattern::parse("{date").is_err());
+    }
+
+    /// Read generated file without its generation stamp.
+    fn read_generated<P: AsRef<Path>>(p: P) -> String {
+        strip_generated_stamp(&fs::read_to_string(p).unwrap()).to_owned()
+    }
+
+    /// Mail of test_data/mail.txt with its subject replaced.
+    pub(crate) fn mail_with_subject(subject: &str) -> String {
+        fs::read_to_string("./test_data/mail.txt").unwrap()
+            .replace("Subject: =?utf-8?B?MjAyNS8wNC8wMzog5rWL6K+V5pWw5o2u?=", &format!("Subject: {}", subject))
+    }
+
+    /// Mail of test_data/mail.txt with its sender replaced.
+    pub(crate) fn mail_from(from: &str) -> String {
+        fs::read_to_string("./test_data/mail.txt").unwrap()
+            .replace("From: Shengyu Zhang <gege@example.com>", &format!("From: {}", from))
+    }
+
+    /// Create an archive whose letter and rstdoc dirs are temporary git repositories.
+    pub(crate) fn tmp_archive(mut cfg: ArchiveCfg) -> (Archive, TempDir, TempDir) {
         use xshell::{cmd, Shell};
 
         fn tmpdir_path(d: &TempDir) -> String {
             let dir = d.path();
             let sh = Shell::new().unwrap();
-            sh.change_dir(&dir);
+            sh.change_dir(dir);
             cmd!(sh, "git init").run().unwrap();
             dir.to_str().unwrap().to_owned()
         }
-        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
         let tmp_letter_dir = tempdir().unwrap();
         cfg.letter_dir = tmpdir_path(&tmp_letter_dir);
         let tmp_rstdoc_dir = tempdir().unwrap();
         cfg.rstdoc_dir = tmpdir_path(&tmp_rstdoc_dir);
-        let archive = Archive::load(cfg).unwrap();
+        for route in cfg.routes.iter() {
+            for dir in [&route.letter_dir, &route.rstdoc_dir] {
+                let sh = Shell::new().unwrap();
+                sh.change_dir(dir);
+                cmd!(sh, "git init").run().unwrap();
+            }
+        }
+        (Archive::load(cfg).unwrap(), tmp_letter_dir, tmp_rstdoc_dir)
+    }
+
+    #[test]
+    fn test_archive_upsert_letter() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
 
         let data = fs::read_to_string("./test_data/mail.txt").unwrap();
         let raw_mail = RawMail::new(&data);
         let parsed_mail = raw_mail.parse().unwrap();
 
-        let letter = archive.upsert_letter(&parsed_mail).unwrap();
+        let letter = archive.upsert_letter(&parsed_mail).unwrap().letter().unwrap();
         assert!(archive.upsert_letter(&parsed_mail).is_err()); // test duplicate writing
 
         // Test TOML.
@@ -628,14 +2564,1571 @@ mod tests {
         let letter2 = LoveLetter::load(archive.letter_path(&letter)).unwrap();
         assert_eq!(letter, letter2);
 
-        archive.generate_rstdoc().unwrap();
+        archive.generate_all().unwrap();
         assert_eq!(
-            fs::read_to_string(archive.rstdoc_index_path()).unwrap(),
+            read_generated(archive.rstdoc_index_path()),
             fs::read_to_string("./test_data/index.rst").unwrap()
         );
         assert_eq!(
-            fs::read_to_string(archive.rstdoc_path(&letter)).unwrap(),
+            read_generated(archive.rstdoc_path(&letter)),
+            fs::read_to_string("./test_data/2025.rst").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_archive_strict() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.allowed_actions.push("publish".to_string());
+        let malformed_date = mail_with_subject("2025/April/03: 测试数据");
+        let unknown_action = mail_with_subject("[delete] 2025/04/03: 测试数据");
+        let allowed_action = mail_with_subject("[publish] 2025/04/03: 测试数据");
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let letter_from_mail = |data: &str| {
+            archive.letter_from_mail(&RawMail::new(data).parse().unwrap())
+        };
+        assert!(letter_from_mail(&malformed_date).is_err());
+        assert!(letter_from_mail(&unknown_action).is_err());
+        assert!(letter_from_mail(&allowed_action).unwrap().is_some());
+
+        cfg.strict = false;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let letter_from_mail = |data: &str| {
+            archive.letter_from_mail(&RawMail::new(data).parse().unwrap())
+        };
+        assert!(letter_from_mail(&malformed_date).unwrap().is_none());
+        assert!(letter_from_mail(&unknown_action).unwrap().is_none());
+        assert!(letter_from_mail(&allowed_action).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_archive_on_disallowed() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let data = mail_from("didi@example.com");
+        let mail = RawMail::new(&data);
+        let mail = mail.parse().unwrap();
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        assert!(matches!(archive.upsert_letter(&mail), Err(Error::NotAllowed(_))));
+
+        cfg.on_disallowed = DisallowedPolicy::Skip;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        assert!(matches!(archive.upsert_letter(&mail), Ok(Upserted::Skipped)));
+        assert!(!fs::read_dir(tmp_letter_dir.path()).unwrap().any(|e| e.unwrap().path().extension().is_some_and(|x| x == "toml")));
+
+        cfg.on_disallowed = DisallowedPolicy::Quarantine;
+        let err = Archive::new(cfg.clone(), Vec::new()).err().unwrap();
+        assert!(err.to_string().contains("quarantine_dir is required"));
+        let tmp_quarantine_dir = tempdir().unwrap();
+        let quarantine_dir = tmp_quarantine_dir.path().join("quarantine");
+        cfg.quarantine_dir = Some(quarantine_dir.to_str().unwrap().to_owned());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        match archive.upsert_letter(&mail) {
+            Ok(Upserted::Quarantined(p)) => {
+                assert!(p.starts_with(&quarantine_dir));
+                assert_eq!(fs::read(p).unwrap(), data.as_bytes());
+            },
+            x => panic!("unexpected outcome: {:?}", x),
+        }
+
+        // Allowed senders are not affected.
+        let mail = fs::read_to_string("./test_data/mail.txt").unwrap();
+        assert!(matches!(archive.upsert_letter(&RawMail::new(&mail).parse().unwrap()), Ok(Upserted::Written(_))));
+    }
+
+    #[test]
+    fn test_archive_upsert_letter_errors() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let upsert_letter = |data: &str| archive.upsert_letter(&RawMail::new(data).parse().unwrap());
+
+        let not_allowed = mail_from("didi@example.com");
+        assert!(matches!(upsert_letter(&not_allowed), Err(Error::NotAllowed(_))));
+        let unknown_action = mail_with_subject("[delete] 2025/04/03");
+        assert!(matches!(upsert_letter(&unknown_action), Err(Error::NotAllowed(_))));
+        let malformed = mail_with_subject("2025/April/03");
+        assert!(matches!(upsert_letter(&malformed), Err(Error::Parse(_))));
+
+        assert!(matches!(upsert_letter(&data), Ok(Upserted::Written(_))));
+        let path = archive.letter_path(&archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap().0);
+        assert!(matches!(upsert_letter(&data), Err(Error::Duplicate(p)) if p == path));
+
+        let plain_dir = tempdir().unwrap();
+        assert!(matches!(Repo::load(plain_dir.path()), Err(Error::Git(_))));
+    }
+
+    #[test]
+    fn test_archive_interpret_markdown() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let header = &data[..data.find("\n\n").unwrap()];
+        let header = header[..header.find("Content-Type:").unwrap()].to_owned() + "Content-Type: text/plain; charset=utf-8";
+        let data = header + "\n\n# 标题\n\n**你好**，\n- 一\n- 二\n";
+        let raw_mail = RawMail::new(&data);
+        let mail = raw_mail.parse().unwrap();
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        assert!(matches!(archive.letter_from_mail(&mail).map_err(Error::from), Err(Error::Parse(_))));
+
+        cfg.interpret_markdown = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let (letter, _) = archive.letter_from_mail(&mail).unwrap().unwrap();
+        assert_eq!(letter.content, "<h1>标题</h1>\n<p><strong>你好</strong>，</p>\n<ul>\n<li>一</li>\n<li>二</li>\n</ul>\n");
+    }
+
+    #[test]
+    fn test_archive_written_at() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let written_at = |subject: &str, header: Option<&str>| {
+            let mut data = mail_with_subject(subject);
+            if let Some(h) = header {
+                data = data.replace("MIME-Version: 1.0", &format!("X-Loveletter-Written: {}\nMIME-Version: 1.0", h));
+            }
+            let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+            assert_eq!((letter.date.year, letter.title.as_deref()), (1998, Some("生日: 快乐")));
+            assert_eq!(letter.created_at, letter.updated_at);
+            letter.created_at.unwrap().to_rfc3339()
+        };
+
+        // Mail date.
+        assert_eq!(written_at("1998/01/28: 生日: 快乐", None), "2025-04-03T13:07:14+00:00");
+        // Subject.
+        assert_eq!(written_at("1998/01/28@1998/01/27: 生日: 快乐", None), "1998-01-27T00:00:00+00:00");
+        // Header, which takes precedence over subject.
+        assert_eq!(written_at("1998/01/28: 生日: 快乐", Some("Tue, 27 Jan 1998 20:00:00 +0800")), "1998-01-27T12:00:00+00:00");
+        assert_eq!(written_at("1998/01/28@1998/01/27: 生日: 快乐", Some("1998-01-26T00:00:00Z")), "1998-01-26T00:00:00+00:00");
+
+        assert!(Archive::split_written_at("1998/01/28@1998/01: 生日").is_err());
+        assert_eq!(Archive::split_written_at("1998/01/28: a@b").unwrap().1, None);
+        assert_eq!(Archive::split_written_at("[edit] 1998/01/28@1998/01/27: 生日").unwrap().0, "[edit] 1998/01/28: 生日");
+        assert_eq!(Archive::split_written_at("Dinner @ 7pm").unwrap(), (Cow::Borrowed("Dinner @ 7pm"), None));
+    }
+
+    #[test]
+    fn test_archive_ignore_subjects() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.ignore_subjects = vec!["Out of Office".to_string(), "^Undelivered".to_string()];
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter_from_mail = |subject: Option<&str>| {
+            let data = match subject {
+                Some(x) => mail_with_subject(x),
+                None => data.clone(),
+            };
+            archive.letter_from_mail(&RawMail::new(&data).parse().unwrap())
+        };
+        assert!(letter_from_mail(None).unwrap().is_some());
+        assert!(letter_from_mail(Some("Auto: Out of Office")).unwrap().is_none());
+        assert!(letter_from_mail(Some("Undelivered Mail Returned to Sender")).unwrap().is_none());
+        assert!(letter_from_mail(Some("Re: Undelivered Mail")).is_err()); // not ignored, malformed
+    }
+
+    #[test]
+    fn test_archive_date_from_mail_fallback() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.date_from_mail_fallback = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+
+        let letter_from_mail = |subject: &str| {
+            let data = mail_with_subject(subject);
+            archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap().0
+        };
+        let letter = letter_from_mail("想你了: 真的");
+        assert_eq!(letter.date, Date { year: 2025, month: Some(4), day: Some(3) });
+        assert_eq!(letter.title.as_deref(), Some("想你了: 真的"));
+        // '@' without date before it is not write time marker.
+        let letter = letter_from_mail("Lunch with bob@example.com");
+        assert_eq!(letter.date, Date { year: 2025, month: Some(4), day: Some(3) });
+        assert_eq!(letter.title.as_deref(), Some("Lunch with bob@example.com"));
+        // Date in subject still wins.
+        let letter = letter_from_mail("2024/12/25: 圣诞快乐");
+        assert_eq!(letter.date, Date { year: 2024, month: Some(12), day: Some(25) });
+        assert_eq!(letter.title.as_deref(), Some("圣诞快乐"));
+    }
+
+    #[test]
+    fn test_archive_skip_spam() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let data = fs::read_to_string("./test_data/mail6.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let spam = raw_mail.parse().unwrap();
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let ham = raw_mail.parse().unwrap();
+        assert!(Archive::is_spam(&spam));
+        assert!(!Archive::is_spam(&ham));
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        assert!(archive.letter_from_mail(&spam).unwrap().is_some()); // optional
+        cfg.skip_spam = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        assert!(archive.letter_from_mail(&spam).unwrap().is_none());
+        assert!(archive.letter_from_mail(&ham).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_archive_letter_url() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let (letter, _) = archive.letter_from_mail(&raw_mail.parse().unwrap()).unwrap().unwrap();
+        assert_eq!(archive.letter_url(&letter), None);
+
+        cfg.base_url = Some("https://example.com/loveletter/".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        assert_eq!(
+            archive.letter_url(&letter).as_deref(),
+            Some("https://example.com/loveletter/2025.html#loveletter-2025-04-03-882b1b23")
+        );
+    }
+
+    #[test]
+    fn test_archive_generate_links() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        archive.generate_all().unwrap();
+        assert!(!archive.rstdoc_links_path().exists());
+
+        cfg.base_url = Some("https://example.com".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        for f in ["./test_data/mail2.txt", "./test_data/mail.txt"] {
+            let data = fs::read_to_string(f).unwrap();
+            archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        }
+        archive.generate_all().unwrap();
+        assert_eq!(
+            fs::read_to_string(archive.rstdoc_links_path()).unwrap(),
+            "\
+https://example.com/2025.html#loveletter-2025-04-03-882b1b23
+https://example.com/2025.html#loveletter-2025-03-22-a4037063
+"
+        );
+    }
+
+    #[test]
+    fn test_archive_routes() {
+        use crate::cfg::RouteCfg;
+
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let mom: EmailAddress = "Mom <mom@example.com>".parse().unwrap();
+        cfg.allowed_to_addrs = cfg.allowed_to_addrs.into_iter().chain([mom.clone()]).collect();
+        let tmp_route_letter_dir = tempdir().unwrap();
+        let tmp_route_rstdoc_dir = tempdir().unwrap();
+        cfg.routes.push(RouteCfg {
+            to: mom,
+            letter_dir: tmp_route_letter_dir.path().to_str().unwrap().to_owned(),
+            rstdoc_dir: tmp_route_rstdoc_dir.path().to_str().unwrap().to_owned(),
+            base_url: Some("https://example.com/mom".to_string()),
+        });
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let letter_filename = archive.letter_path(&letter).file_name().unwrap().to_owned();
+        assert!(tmp_letter_dir.path().join(&letter_filename).exists());
+        let data = data.replace("To: Love Letter <loveletter@example.com>", "To: mom@example.com");
+        let routed_letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        assert!(tmp_route_letter_dir.path().join(&letter_filename).exists());
+        assert_eq!(fs::read_dir(tmp_letter_dir.path()).unwrap().count(), 2); // .git and letter
+
+        archive.generate_all().unwrap();
+        assert!(tmp_route_rstdoc_dir.path().join("2025.rst").exists());
+        assert_eq!(archive.letter_url(&letter), None);
+        assert_eq!(
+            archive.letter_url(&routed_letter).unwrap(),
+            "https://example.com/mom/2025.html#loveletter-2025-04-03-882b1b23"
+        );
+    }
+
+    #[test]
+    fn test_archive_accept_bcc() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap()
+            .replace(
+                "To: Love Letter <loveletter@example.com>",
+                "To: undisclosed-recipients:;\nDelivered-To: <loveletter@example.com>");
+        let raw_mail = RawMail::new(&data);
+        let mail = raw_mail.parse().unwrap();
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        assert!(archive.letter_from_mail(&mail).is_err());
+
+        cfg.accept_bcc = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let (letter, _) = archive.letter_from_mail(&mail).unwrap().unwrap();
+        assert_eq!(letter.to.to_string(), "Love Letter <loveletter@example.com>");
+
+        // Still rejected if archive address is not an envelope recipient either.
+        let data = data.replace("Delivered-To: <loveletter@example.com>", "Delivered-To: didi@example.com");
+        assert!(archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_archive_title_prefix() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.meimei_title_prefix = Some("🌸".to_string());
+        cfg.gege_title_prefix = Some("❤️".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let section = |letter: &LoveLetter| archive.rstdoc_section(letter);
+
+        assert!(section(&letter).contains("\n❤️ 2025-04-03: 测试数据\n=======================\n"));
+        let letter = LoveLetter {
+            from_meimei_if_true_and_gege_if_false: true,
+            title: None,
+            ..letter
+        };
+        assert!(section(&letter).contains("\n🌸 2025-04-03\n=============\n"));
+        assert!(letter.render(RenderFormat::Rst).contains("\n2025-04-03\n==========\n"));
+    }
+
+    #[test]
+    fn test_letter_emoji_title() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let letter = LoveLetter {
+            title: Some("\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467} 家庭日".to_string()),
+            ..letter
+        };
+        let title = "2025-04-03: \u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467} 家庭日";
+        assert!(title.width_cjk() < docutils_width(title));
+        let rst = letter.render(RenderFormat::Rst);
+        assert!(rst.contains(&format!("\n{}\n{}\n", title, "=".repeat(docutils_width(title)))), "{}", rst);
+
+        // Underline of titles without emoji sequences is not padded.
+        let title = "2025-04-03: 测试数据 ❤️";
+        assert_eq!(rst_underline(title, '-'), "-".repeat(title.width_cjk()));
+    }
+
+    #[test]
+    fn test_letter_toml() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap()
+            .replace("\n", "\r\n")
+            .replace("<div>张同学", "<div class=\"greeting\">张同学 \\o/ it's");
+        let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert!(!letter.content.contains('\r'));
+
+        let letter_data = toml::to_string(&letter).unwrap();
+        assert!(letter_data.ends_with(&format!("content = '''\n{}'''\n", letter.content)));
+        assert_eq!(toml::from_str::<LoveLetter>(&letter_data).unwrap(), letter);
+        let keys: Vec<_> = letter_data.lines().filter_map(|l| l.split_once(" = ")).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![
+            "from", "to", "from_meimei_if_true_and_gege_if_false", "id",
+            "created_at", "updated_at", "date", "title", "raw_subject", "content",
+        ]);
+    }
+
+    #[test]
+    fn test_letter_encoded_subject() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        assert!(data.contains("Subject: =?utf-8?B?"));
+        let (first, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert_eq!(first.title.as_deref(), Some("测试数据"));
+        assert_eq!(first.raw_subject.as_deref(), Some("2025/04/03: 测试数据"));
+
+        // Mixed Q and B encoded-words folded across lines.
+        let data = mail_with_subject("=?UTF-8?Q?=5Bedit=5D_2025/04/03:_?=\n =?UTF-8?B?5rWL6K+V5pWw5o2u?=");
+        let mail = RawMail::new(&data);
+        let mail = mail.parse().unwrap();
+        assert_eq!(mail.subject(), Some("[edit] 2025/04/03: 测试数据"));
+        let (letter, action) = archive.letter_from_mail(&mail).unwrap().unwrap();
+        assert_eq!(letter.title.as_deref(), Some("测试数据"));
+        assert_eq!(action.as_deref(), Some("edit"));
+        assert_eq!(archive.letter_path(&letter), archive.letter_path(&first));
+    }
+
+    #[test]
+    fn test_archive_index_filename() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        for invalid in ["contents.md", "index", "../index.rst", "/index.rst"] {
+            cfg.index_filename = invalid.to_string();
+            assert!(Archive::new(cfg.clone(), Vec::new()).is_err(), "{}", invalid);
+        }
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let index = fs::read_to_string("./test_data/index.rst").unwrap();
+        cfg.index_filename = "contents.rst".to_string();
+        let (archive, _tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        archive.generate_all().unwrap();
+        assert_eq!(archive.rstdoc_index_path(), tmp_rstdoc_dir.path().join("contents.rst"));
+        assert_eq!(read_generated(archive.rstdoc_index_path()), index);
+        assert!(!tmp_rstdoc_dir.path().join("index.rst").exists());
+        assert!(archive.prune(false).unwrap().is_empty());
+
+        cfg.index_filename = "letters/index.rst".to_string();
+        let (archive, _tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg);
+        archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        archive.generate_all().unwrap();
+        assert_eq!(
+            read_generated(tmp_rstdoc_dir.path().join("letters/index.rst")),
+            index.replace("   *", "   ../*"));
+    }
+
+    #[test]
+    fn test_archive_untitled_title_template() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.untitled_title_template = Some("Letter of {date} from {author}".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = mail_with_subject("2025/04/03");
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        archive.generate_all().unwrap();
+
+        let title = "Letter of 2025-04-03 from 哥哥";
+        let rstdoc = fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap();
+        assert!(rstdoc.contains(&format!("\n{}\n{}\n", title, "=".repeat(title.width_cjk()))), "{}", rstdoc);
+        // Only the rendering changes.
+        let stored = fs::read_to_string(archive.letter_path(&letter)).unwrap();
+        assert!(!stored.contains("Letter of") && !stored.contains("\ntitle ="));
+        assert_eq!(LoveLetter::load(archive.letter_path(&letter)).unwrap().title, None);
+        assert_eq!(letter.to_string(), "<2025-04-03>");
+    }
+
+    #[test]
+    fn test_archive_outputs() {
+        use xshell::{cmd, Shell};
+
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.outputs = vec![OutputFormat::Rst, OutputFormat::Markdown];
+        let (archive, tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        fs::copy("./test_data/2025-04-03.toml", tmp_letter_dir.path().join("2025-04-03.toml")).unwrap();
+        archive.generate_all().unwrap();
+
+        assert_eq!(
+            read_generated(archive.rstdoc_page_path(2025, None)),
             fs::read_to_string("./test_data/2025.rst").unwrap()
         );
+        assert_eq!(
+            read_generated(archive.markdown_page_path(2025)),
+            "# 💌 Love Letters from 2025\n\n".to_string() + &fs::read_to_string("./test_data/2025-04-03.md").unwrap()
+        );
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_rstdoc_dir.path());
+        assert_eq!(cmd!(sh, "git rev-list --count HEAD").read().unwrap(), "1");
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+
+        // Markdown only.
+        cfg.outputs = vec![OutputFormat::Markdown];
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        fs::copy("./test_data/2025-04-03.toml", tmp_letter_dir.path().join("2025-04-03.toml")).unwrap();
+        archive.generate_all().unwrap();
+        assert!(archive.markdown_page_path(2025).exists());
+        assert!(!archive.rstdoc_page_path(2025, None).exists() && !archive.rstdoc_index_path().exists());
+    }
+
+    #[test]
+    fn test_date_range() {
+        let range = |s: &str| s.parse::<DateRange>().unwrap();
+        let date = |s: &str| Date::from_filename(s).unwrap();
+        assert_eq!(range("2025/04"), DateRange { from: date("2025-04"), to: date("2025-04") });
+        assert_eq!(range("2025/04").to_string(), "2025-04");
+        assert_eq!(range("2025 .. 2025/2/3").to_string(), "2025 ~ 2025-02-03");
+        assert!(range("2025/04").contains(&date("2025-04-30")));
+        assert!(range("2025/04").contains(&date("2025-04")));
+        assert!(!range("2025/04").contains(&date("2025-05-01")));
+        assert!(!range("2025/04").contains(&date("2025")));
+        assert!(range("2024/12..2025").contains(&date("2025-12-31")));
+        assert!(range("2024/02").contains(&date("2024-02-29")));
+        assert!("2025/05..2025/04".parse::<DateRange>().is_err());
+        assert!("2025/13".parse::<DateRange>().is_err());
+    }
+
+    #[test]
+    fn test_archive_digest() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let upsert = |archive: &Archive, subject: &str| {
+            let data = mail_with_subject(subject);
+            archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        };
+        for subject in ["2025/04/03: 测试数据", "2025/05/01: 劳动节", "2025/04/30: 月末", "[private] 2025/04/10: 悄悄话"] {
+            upsert(&archive, subject);
+        }
+
+        let digest = archive.digest(&"2025/04".parse().unwrap()).unwrap();
+        let excerpt = "张同学 我们这个 I 人交朋友的项目还有效咩 -- Best regards, Shengyu Zhang https://example.com";
+        assert_eq!(digest, format!("\
+===========================
+💌  Love Letters of 2025-04
+===========================
+
+2025-04-03: 测试数据
+--------------------
+
+:author: 哥哥
+:date: 2025-04-03
+
+{excerpt}
+
+2025-04-30: 月末
+----------------
+
+:author: 哥哥
+:date: 2025-04-30
+
+{excerpt}
+"));
+
+        cfg.outputs = vec![OutputFormat::Markdown];
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        upsert(&archive, "2025/05/01: 劳动节");
+        assert_eq!(
+            archive.digest(&"2025/05/01".parse().unwrap()).unwrap(),
+            format!("# 💌  Love Letters of 2025-05-01\n\n## 2025-05-01: 劳动节\n\n*哥哥, 2025-05-01*\n\n{excerpt}\n"));
+    }
+
+    #[test]
+    fn test_archive_lang() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let letter = |cfg: &ArchiveCfg, path: &str| {
+            let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+            let raw_mail = RawMail::new(&fs::read_to_string(path).unwrap());
+            let letter = archive.letter_from_mail(&raw_mail.parse().unwrap()).unwrap().unwrap().0;
+            (letter.lang.clone(), archive.rstdoc_section(&letter))
+        };
+        let (lang, section) = letter(&cfg, "./test_data/mail8.txt");
+        assert_eq!(lang.as_deref(), Some("zh-CN"));
+        assert!(section.contains("   :id: 882b1b23\n   :lang: zh-CN\n"));
+        let (lang, section) = letter(&cfg, "./test_data/mail.txt");
+        assert_eq!(lang, None);
+        assert!(!section.contains(":lang:"));
+
+        cfg.default_lang = Some("en".to_string());
+        assert_eq!(letter(&cfg, "./test_data/mail8.txt").0.as_deref(), Some("zh-CN"));
+        assert_eq!(letter(&cfg, "./test_data/mail.txt").0.as_deref(), Some("en"));
+    }
+
+    #[test]
+    fn test_archive_multiple_from() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail9.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        assert_eq!(letter.from.to_string(), "Shengyu Zhang <gege@example.com>");
+        assert_eq!(letter.author(), "哥哥");
+
+        // None of them is allowed.
+        let data = data.replace("gege@example.com", "other@example.com");
+        let e = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap_err();
+        assert!(matches!(e, Error::NotAllowed(x) if x.starts_with("sender Stranger <stranger@example.com> not in allowed list")));
+    }
+
+    #[test]
+    fn test_archive_private() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let upsert = |subject: &str, header: &str| {
+            let data = mail_with_subject(&format!("{}{}", subject, header));
+            archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap()
+        };
+        assert!(!upsert("2025/04/03: 测试数据", "").private);
+        assert!(upsert("[Private] 2025/04/04: 悄悄话", "").private);
+        assert!(upsert("2025/04/05: 秘密", "\nX-Loveletter-Private: yes").private);
+        // Still private after editing.
+        let letter = upsert("[edit] 2025/04/04: 悄悄话", "");
+        assert!(letter.private);
+        assert!(fs::read_to_string(archive.letter_path(&letter)).unwrap().contains("private = true"));
+
+        archive.generate_all().unwrap();
+        let page = fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap();
+        assert!(page.contains("测试数据") && !page.contains("悄悄话") && !page.contains("秘密"));
+
+        archive.generate_with(GenerateOptions { include_private: true, ..GenerateOptions::default() }).unwrap();
+        let page = fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap();
+        assert!(page.contains("测试数据") && page.contains("悄悄话") && page.contains("秘密"));
+    }
+
+    #[test]
+    fn test_archive_frozen_years() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.frozen_years = Some(0); // years before this year
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let upsert = |subject: &str| {
+            let data = mail_with_subject(subject);
+            archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        };
+
+        // Missing page of frozen year is still generated.
+        upsert("2025/04/03: 测试数据");
+        archive.generate_all().unwrap();
+        let page = fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap();
+        assert!(page.contains("测试数据"));
+
+        upsert("2025/05/01: 劳动节");
+        archive.generate_all().unwrap();
+        assert_eq!(fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap(), page);
+
+        archive.generate_full().unwrap();
+        assert!(fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap().contains("劳动节"));
+    }
+
+    #[test]
+    fn test_archive_index_recent() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.index_recent = 2;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let upsert = |subject: &str| {
+            let data = mail_with_subject(subject);
+            archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap()
+        };
+        let l1 = upsert("2025/04/03: 测试数据");
+        let l2 = upsert("2024/12/25: 圣诞快乐");
+        let l3 = upsert("2025/05/01: 劳动节");
+        archive.generate_all().unwrap();
+
+        let index = fs::read_to_string(archive.rstdoc_index_path()).unwrap();
+        let recent = format!("\
+.. rubric:: Recent Letters
+
+- :ref:`2025-05-01: 劳动节 <{}>`
+- :ref:`2025-04-03: 测试数据 <{}>`
+
+.. toctree::", l3.anchor(), l1.anchor());
+        assert!(index.contains(&recent), "{}", index);
+        assert!(!index.contains(&l2.anchor()));
+        assert!(index.find("Recent Letters").unwrap() < index.find(".. toctree::").unwrap());
+    }
+
+    #[test]
+    fn test_archive_display_names() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.display_names.insert("gege".to_string(), "哥哥".to_string());
+        cfg.display_names.insert("meimei@example.com".to_string(), "妹妹".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let with_from = |from: &str| {
+            let data = mail_from(from);
+            let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+            archive.rstdoc_section(&letter)
+        };
+        assert!(with_from("gege <gege@example.com>").contains("   :nick: 哥哥\n"));
+        assert!(with_from("\"  Shengyu Zhang \" <gege@example.com>").contains("   :nick: Shengyu Zhang\n"));
+        assert!(with_from("\"some nickname\" <meimei@example.com>").contains("   :nick: 妹妹\n"));
+    }
+
+    #[test]
+    fn test_letter_attachments() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail3.txt").unwrap();
+        let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert_eq!(letter.attachments.len(), 2);
+        let letter_data = toml::to_string(&letter).unwrap();
+        assert_eq!(toml::from_str::<LoveLetter>(&letter_data).unwrap(), letter);
+        assert!(archive.rstdoc_section(&letter).contains(
+            "   :updatedat: 2025-05-20\n   :id: 44d6d698\n   :attachments: photo.png (image/png, 2.0 KiB), 笔记.pdf (application/pdf, 509 B)\n\n   .. raw:: html\n"));
+
+        // No option for letter without attachments.
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert!(!archive.rstdoc_section(&letter).contains(":attachments:"));
+        assert!(!toml::to_string(&letter).unwrap().contains("attachments"));
+    }
+
+    #[test]
+    fn test_letter_id() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        assert_eq!(letter.id.as_deref(), Some("882b1b23"));
+        let anchor = letter.anchor();
+
+        // Edit title of letter by hand.
+        let letter_path = archive.letter_path(&letter);
+        let edited = fs::read_to_string(&letter_path).unwrap().replace("title = \"测试数据\"", "title = \"新标题\"");
+        fs::write(&letter_path, edited).unwrap();
+        let edited = LoveLetter::load(&letter_path).unwrap();
+        assert_eq!(edited.title.as_deref(), Some("新标题"));
+        assert_eq!(edited.anchor(), anchor);
+        archive.generate_all().unwrap();
+        let page = fs::read_to_string(archive.rstdoc_path(&edited)).unwrap();
+        assert!(page.contains(&format!(".. _{}:\n\n2025-04-03: 新标题\n", anchor)));
+        assert!(page.contains("   :id: 882b1b23\n"));
+
+        // Letters without stored ID keep their anchors derived from title.
+        let legacy = LoveLetter { id: None, ..letter.clone() };
+        assert_eq!(legacy.anchor(), anchor);
+        assert_eq!(LoveLetter { id: None, title: None, ..letter }.anchor(), "loveletter-2025-04-03");
+    }
+
+    #[test]
+    fn test_letter_raw_subject() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = mail_with_subject("[edit]  2025/04/03:  Re: 测试数据 ");
+        let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert_eq!(letter.title.as_deref(), Some("Re: 测试数据"));
+        assert_eq!(letter.raw_subject.as_deref(), Some("[edit]  2025/04/03:  Re: 测试数据"));
+        let letter_data = toml::to_string(&letter).unwrap();
+        assert_eq!(toml::from_str::<LoveLetter>(&letter_data).unwrap(), letter);
+
+        // Letters written before raw_subject was introduced.
+        let data = fs::read_to_string("./test_data/2025-04-03.toml").unwrap()
+            .replace("raw_subject = \"2025/04/03: 测试数据\"\n", "");
+        let letter: LoveLetter = toml::from_str(&data).unwrap();
+        assert_eq!(letter.raw_subject, None);
+        assert!(!toml::to_string(&letter).unwrap().contains("raw_subject"));
+    }
+
+    #[test]
+    fn test_letter_source() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.render_source = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert_eq!((letter.source_account.as_deref(), letter.source_folder.as_deref()), (None, None));
+        assert!(!toml::to_string(&letter).unwrap().contains("source_"));
+        assert!(!archive.rstdoc_section(&letter).contains(":source:"));
+
+        let raw_mail = RawMail {
+            account: Some("loveletter@example.com".to_string()),
+            folder: Some("INBOX".to_string()),
+            ..RawMail::new(&data)
+        };
+        let letter = archive.upsert_letter(&raw_mail.parse().unwrap()).unwrap().letter().unwrap();
+        assert_eq!(letter.source_account.as_deref(), Some("loveletter@example.com"));
+        assert_eq!(letter.source_folder.as_deref(), Some("INBOX"));
+        let letter_data = fs::read_to_string(archive.letter_path(&letter)).unwrap();
+        assert!(letter_data.contains("source_account = \"loveletter@example.com\"\nsource_folder = \"INBOX\"\n"));
+        assert_eq!(LoveLetter::load(archive.letter_path(&letter)).unwrap(), letter);
+        assert!(archive.rstdoc_section(&letter).contains("   :source: loveletter@example.com/INBOX\n"));
+
+        // Not rendered by default.
+        cfg.render_source = false;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        assert!(!archive.rstdoc_section(&letter).contains(":source:"));
+        assert!(!letter.render(RenderFormat::Rst).contains(":source:"));
+    }
+
+    #[test]
+    fn test_letter_excerpt() {
+        let data = fs::read_to_string("./test_data/2025-04-03.toml").unwrap();
+        let letter: LoveLetter = toml::from_str(&data).unwrap();
+        let excerpt = |content: &str, max_chars| LoveLetter {
+            content: content.to_string(),
+            ..letter.clone()
+        }.excerpt(max_chars);
+
+        assert_eq!(excerpt("<p>Hello world</p>", 20), "Hello world");
+        assert_eq!(excerpt("<p>Hello world</p>", 11), "Hello world");
+        assert_eq!(excerpt("<p>Hello</p><p>world again</p>", 8), "Hello…");
+        assert_eq!(excerpt("Hello world again", 11), "Hello world…");
+        assert_eq!(excerpt("Hello world again", 12), "Hello world…");
+        assert_eq!(excerpt("Supercalifragilistic", 5), "Super…");
+        assert_eq!(excerpt("<p>今天天气很好</p>", 4), "今天天气…");
+        assert_eq!(excerpt("我们去Disneyland玩", 5), "我们去…");
+        assert_eq!(excerpt("我们去 Disneyland 玩", 14), "我们去 Disneyland…");
+    }
+
+    #[test]
+    fn test_letter_render() {
+        let data = fs::read_to_string("./test_data/2025-04-03.toml").unwrap();
+        let letter: LoveLetter = toml::from_str(&data).unwrap();
+
+        let rst = fs::read_to_string("./test_data/2025.rst").unwrap();
+        assert_eq!(rst, letter.rstdoc_heading() + &letter.render(RenderFormat::Rst));
+        assert_eq!(
+            fs::read_to_string("./test_data/2025-04-03.md").unwrap(),
+            letter.render(RenderFormat::Markdown)
+        );
+        let opts = RenderOptions { nick: Some("哥"), directive: "letter", ..RenderOptions::default() };
+        assert!(letter.render_with(RenderFormat::Rst, &opts).contains(".. letter:: _\n   :date: 2025-04-03\n   :nick: 哥\n"));
+        assert!(letter.render_with(RenderFormat::Markdown, &opts).contains("*哥 (哥哥), 2025-04-03*"));
+    }
+
+    #[test]
+    fn test_archive_directive_name() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        assert_eq!(cfg.directive_name, "loveletter");
+        cfg.directive_name = "sweetnote".to_string();
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let section = archive.rstdoc_section(&letter);
+        assert!(section.contains("\n.. sweetnote:: _\n   :date: 2025-04-03\n"));
+        assert!(!section.contains("loveletter::"));
+    }
+
+    #[test]
+    fn test_archive_content_class() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.content_class = Some("loveletter-body".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let letter = LoveLetter {
+            content: "<p>foo</p>\n<p>bar</p>".to_string(),
+            ..letter
+        };
+        assert!(archive.rstdoc_section(&letter).ends_with("
+   .. raw:: html
+
+      <div class=\"loveletter-body\">
+      <p>foo</p>
+      <p>bar</p>
+      </div>
+
+"));
+        assert!(letter.render(RenderFormat::Rst).ends_with("
+   .. raw:: html
+
+      <p>foo</p>
+      <p>bar</p>
+
+"));
+    }
+
+    #[test]
+    fn test_archive_paginate() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.base_url = Some("https://example.com".to_string());
+        cfg.max_sections_per_page = Some(2);
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        for day in ["01", "02", "03", "04", "05"] {
+            let data = mail_with_subject(&format!("2025/04/{}", day));
+            archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        }
+        archive.generate_all().unwrap();
+
+        let read = |page| fs::read_to_string(archive.rstdoc_page_path(2025, page)).unwrap();
+        assert!(read(None).ends_with(".. toctree::\n\n   2025-3\n   2025-2\n   2025-1\n"));
+        let index = fs::read_to_string(archive.rstdoc_index_path()).unwrap();
+        assert!(index.ends_with(":reversed:\n\n   ????\n"));
+        // Pages are counted from the oldest letter.
+        let page1 = read(Some(1));
+        assert!(page1.contains("Love Letters from 2025 (1/3)"));
+        let has = |page: &str, day| page.contains(&format!(".. _loveletter-2025-04-{}:", day));
+        assert!(has(&page1, "02") && has(&page1, "01") && !has(&page1, "03"));
+        assert!(page1.contains(":doc:`2025` | :doc:`Next → <2025-2>`\n"));
+        assert!(read(Some(2)).contains(":doc:`← Previous <2025-1>` | :doc:`2025` | :doc:`Next → <2025-3>`\n"));
+        let page3 = read(Some(3));
+        assert!(has(&page3, "05") && !has(&page3, "04"));
+        assert!(page3.contains(":doc:`← Previous <2025-2>` | :doc:`2025`\n"));
+
+        let links = fs::read_to_string(archive.rstdoc_links_path()).unwrap();
+        assert_eq!(links.lines().collect::<Vec<_>>(), vec![
+            "https://example.com/2025-3.html#loveletter-2025-04-05",
+            "https://example.com/2025-2.html#loveletter-2025-04-04",
+            "https://example.com/2025-2.html#loveletter-2025-04-03",
+            "https://example.com/2025-1.html#loveletter-2025-04-02",
+            "https://example.com/2025-1.html#loveletter-2025-04-01",
+        ]);
+        let letter = archive.iter_letters().unwrap().nth(1).unwrap().1.unwrap();
+        assert_eq!(archive.letter_url(&letter).unwrap(), "https://example.com/2025-2.html#loveletter-2025-04-04");
+    }
+
+    #[test]
+    fn test_archive_action_header() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.action_header = Some("X-Loveletter-Action".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let action = |data: String| {
+            let raw_mail = RawMail::new(&data);
+            let parsed_mail = raw_mail.parse().unwrap();
+            archive.letter_from_mail(&parsed_mail).map(|x| x.unwrap().1)
+        };
+
+        // Action from subject.
+        assert_eq!(action(data.clone()).unwrap(), None);
+        let subject_data = mail_with_subject("[edit] 2025/04/03: =?utf-8?B?5rWL6K+V5pWw5o2u?=");
+        assert_eq!(action(subject_data.clone()).unwrap(), Some("edit".to_string()));
+
+        // Action from header, which takes precedence over subject.
+        let header_data = data.replace("\nTo: ", "\nX-Loveletter-Action: edit\nTo: ");
+        assert_eq!(action(header_data).unwrap(), Some("edit".to_string()));
+        let header_data = data.replace("\nTo: ", "\nX-Loveletter-Action: Edit\nTo: ");
+        assert_eq!(action(header_data).unwrap(), Some("edit".to_string()));
+        let header_data = subject_data.replace("\nTo: ", "\nX-Loveletter-Action: publish\nTo: ");
+        assert!(action(header_data).is_err());
+    }
+
+    #[test]
+    fn test_archive_store_plaintext() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.store_plaintext = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let plaintext_path = archive.letter_path(&letter).with_extension("txt");
+        assert_eq!(fs::read_to_string(plaintext_path).unwrap(), letter.plaintext());
+
+        let letter = LoveLetter {
+            content: "<p>第一段</p><p>第二段 <a href=\"https://example.com\">链接</a></p>".to_string(),
+            ..letter
+        };
+        assert_eq!(letter.plaintext(), "2025-04-03: 测试数据\n\n第一段\n第二段 链接 <https://example.com>\n");
+    }
+
+    #[test]
+    fn test_archive_trim_empty_html() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let data = fs::read_to_string("./test_data/mail4.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let (letter, _) = archive.letter_from_mail(&parsed_mail).unwrap().unwrap();
+        assert!(letter.content.starts_with("<div><br></div>"));
+
+        cfg.trim_empty_html = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let (letter, _) = archive.letter_from_mail(&parsed_mail).unwrap().unwrap();
+        assert_eq!(
+            letter.content,
+            "<div>儿童节快乐！</div>\n<div><br></div>\n<div><br></div>\n<div>今天也要开心哦</div>"
+        );
+    }
+
+    #[test]
+    fn test_archive_prefer_body() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.prefer_body.insert("meimei@example.com".to_string(), BodyKind::Text);
+        cfg.prefer_body.insert("gege@example.com".to_string(), BodyKind::Html);
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let content = |data: &str| {
+            let raw_mail = RawMail::new(data);
+            let parsed_mail = raw_mail.parse().unwrap();
+            archive.letter_from_mail(&parsed_mail).unwrap().unwrap().0.content
+        };
+
+        let meimei_data = fs::read_to_string("./test_data/mail3.txt").unwrap()
+            .replace("<p>photo and note attached</p>", "<p><b>photo</b> and note attached</p>");
+        let gege_data = meimei_data.replace("=?utf-8?B?5aa55aa5?= <meimei@example.com>", "哥哥 <gege@example.com>");
+        assert_eq!(content(&meimei_data), "<p>photo and note attached</p>\n");
+        assert_eq!(content(&gege_data), "<p><b>photo</b> and note attached</p>\n");
+
+        // Fallback to the other body.
+        let html_only_data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let text_only_data = html_only_data.replace("Content-Type: text/html", "Content-Type: text/plain");
+        assert!(content(&html_only_data).starts_with("<div>张同学"));
+        assert!(content(&text_only_data).starts_with("<p>&lt;div&gt;张同学"));
+    }
+
+    #[test]
+    fn test_archive_body_content_id() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let content = |cfg: &ArchiveCfg, data: &str| {
+            let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+            let raw_mail = RawMail::new(data);
+            let parsed_mail = raw_mail.parse().unwrap();
+            archive.letter_from_mail(&parsed_mail).unwrap().unwrap().0.content
+        };
+        let data = fs::read_to_string("./test_data/mail7.txt").unwrap();
+        assert_eq!(content(&cfg, &data), "<div>请查收附件中的信</div>");
+
+        cfg.body_content_id = Some("letter".to_string());
+        assert_eq!(content(&cfg, &data), "<div>这才是真正的信</div>");
+        // Fallback to the default body if mail has no such part.
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        assert_eq!(content(&cfg, &data), content(&Cfg::load("./test_data/config.toml").unwrap().archive, &data));
+    }
+
+    #[test]
+    fn test_archive_signature_delimiters() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.prefer_body.insert("meimei@example.com".to_string(), BodyKind::Text);
+        let content = |cfg: &ArchiveCfg, data: &str| {
+            let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+            let raw_mail = RawMail::new(data);
+            let parsed_mail = raw_mail.parse().unwrap();
+            archive.letter_from_mail(&parsed_mail).unwrap().unwrap().0.content
+        };
+        let meimei_data = fs::read_to_string("./test_data/mail3.txt").unwrap().replace(
+            "photo and note attached\n\n--alt-boundary\nContent-Type: text/html",
+            "photo and note attached\n~~~~~\nsent from phone\n-- \n妹妹\n\n--alt-boundary\nContent-Type: text/html");
+        let gege_data = fs::read_to_string("./test_data/mail.txt").unwrap();
+
+        // Not stripped by default.
+        assert!(content(&cfg, &meimei_data).contains("sent from phone"));
+        assert!(content(&cfg, &gege_data).contains("Best regards,"));
+
+        // Global rule.
+        cfg.strip_signature = true;
+        assert_eq!(content(&cfg, &meimei_data), "<p>photo and note attached<br />\n~~~~~<br />\nsent from phone</p>\n");
+        assert!(content(&cfg, &gege_data).ends_with("<div>\u{a0}</div>"));
+
+        // Rules of senders, regardless of strip_signature.
+        cfg.strip_signature = false;
+        cfg.signature_delimiters.insert("meimei@example.com".to_string(), "^~+$".to_string());
+        cfg.signature_delimiters.insert("gege@example.com".to_string(), "^Best regards,".to_string());
+        assert_eq!(content(&cfg, &meimei_data), "<p>photo and note attached</p>\n");
+        assert!(content(&cfg, &gege_data).ends_with("<div>--\u{a0}</div>"));
+
+        cfg.signature_delimiters.insert("gege@example.com".to_string(), "(".to_string());
+        assert!(Archive::new(cfg, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_archive_resend_vs_edit() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let resent_data = data
+            .replace("我们这个 I 人交朋友的项目还有效咩", "我们这个 I 人交朋友的项目还有效吗")
+            .replace("Date: Thu, 03 Apr 2025 21:07:14 +0800", "Date: Fri, 04 Apr 2025 08:00:00 +0800");
+        let edit_data = mail_with_subject("[edit] 2025/04/03: =?utf-8?B?5rWL6K+V5pWw5o2u?=")
+            .replace("我们这个 I 人交朋友的项目还有效咩", "我们这个 I 人交朋友的项目还有效吗")
+            .replace("Date: Thu, 03 Apr 2025 21:07:14 +0800", "Date: Fri, 04 Apr 2025 08:00:00 +0800");
+
+        let upsert_twice = |second: &str| {
+            let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+            let created = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+            archive.upsert_letter(&RawMail::new(second).parse().unwrap()).unwrap();
+            let letter = LoveLetter::load(archive.letter_path(&created)).unwrap();
+            assert_eq!(letter.created_at, created.created_at);
+            assert!(letter.updated_at > created.updated_at);
+            assert!(letter.content.contains("还有效吗"));
+            letter
+        };
+        let resent = upsert_twice(&resent_data);
+        let edited = upsert_twice(&edit_data);
+        assert_eq!(edited.raw_subject.as_deref(), Some("[edit] 2025/04/03: 测试数据"));
+        assert_eq!(LoveLetter { raw_subject: None, ..resent }, LoveLetter { raw_subject: None, ..edited });
+    }
+
+    #[test]
+    fn test_archive_keep_versions() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.keep_versions = 2;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let edit = |content: &str| mail_with_subject("[edit] 2025/04/03: =?utf-8?B?5rWL6K+V5pWw5o2u?=")
+            .replace("我们这个 I 人交朋友的项目还有效咩", content);
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        let letter_path = archive.letter_path(&letter);
+        let backup_path = |i| PathBuf::from(format!("{}.bak.{}", letter_path.display(), i));
+        let first = fs::read_to_string(&letter_path).unwrap();
+        assert!(!backup_path(1).exists());
+
+        for (i, content) in ["第一次修改", "第二次修改", "第三次修改"].into_iter().enumerate() {
+            let previous = fs::read_to_string(&letter_path).unwrap();
+            archive.upsert_letter(&RawMail::new(&edit(content)).parse().unwrap()).unwrap();
+            assert_eq!(fs::read_to_string(backup_path(1)).unwrap(), previous);
+            if i == 1 {
+                assert_eq!(fs::read_to_string(backup_path(2)).unwrap(), first);
+            }
+        }
+        assert!(fs::read_to_string(backup_path(2)).unwrap().contains("第一次修改"));
+        assert!(!backup_path(3).exists());
+        // Backups are neither letters nor staged.
+        assert_eq!(archive.iter_letters().unwrap().count(), 1);
+        assert!(!archive.letter_git_repo().unwrap().has_staged_changes().unwrap());
+    }
+
+    #[test]
+    fn test_archive_nameless_sender() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.allowed_from_addrs = ["gege@example.com", "妹妹 <meimei@example.com>"]
+            .into_iter()
+            .map(EmailAddress::new_unchecked)
+            .collect();
+        let data = mail_from("gege@example.com");
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        assert!(archive.letter_from_mail(&parsed_mail).is_err());
+
+        cfg.display_names.insert("gege@example.com".to_string(), "哥哥".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let (letter, _) = archive.letter_from_mail(&parsed_mail).unwrap().unwrap();
+        assert!(!letter.from_meimei_if_true_and_gege_if_false);
+        assert_eq!(letter.from.to_string(), "gege@example.com");
+        assert!(archive.rstdoc_section(&letter).contains("   :nick: 哥哥\n   :author: 哥哥\n"));
+    }
+
+    #[test]
+    fn test_archive_unknown_author() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.allowed_from_addrs = cfg.allowed_from_addrs.into_iter()
+            .chain([EmailAddress::new_unchecked("Mom <mom@example.com>")])
+            .collect();
+        let data = mail_from("Mom <mom@example.com>");
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let err = archive.letter_from_mail(&parsed_mail).err().unwrap();
+        assert!(format!("{:#}", err).contains("name in address Mom is unknown"));
+
+        cfg.unknown_author = Some("妹妹".to_string());
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let letter = archive.upsert_letter(&parsed_mail).unwrap().letter().unwrap();
+        assert!(letter.from_meimei_if_true_and_gege_if_false);
+        let section = archive.rstdoc_section(&letter);
+        assert!(section.contains(":nick: Mom\n") && section.contains(":author: 妹妹\n"));
+
+        cfg.unknown_author = Some("Mom".to_string());
+        assert!(Archive::new(cfg, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_archive_generate_docs() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        drop(archive);
+        let rstdoc_dir = tmp_rstdoc_dir.path().join("rst");
+        cfg.letter_dir = tmp_letter_dir.path().to_str().unwrap().to_owned();
+        cfg.rstdoc_dir = rstdoc_dir.to_str().unwrap().to_owned(); // neither exists nor is a git repo
+        cfg.generate_docs = false;
+        let archive = Archive::load(cfg).unwrap();
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        assert!(archive.letter_path(&letter).exists());
+        archive.generate_all().unwrap();
+        assert!(archive.prune(true).unwrap().is_empty());
+        assert!(!rstdoc_dir.exists());
+    }
+
+    #[test]
+    fn test_archive_text_part_separator() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.prefer_body.insert("meimei@example.com".to_string(), BodyKind::Text);
+        cfg.text_part_separator = "\n\n---\n\n".to_string();
+        cfg.interpret_markdown = true;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail5.txt").unwrap();
+        let (letter, _) = archive.letter_from_mail(&RawMail::new(&data).parse().unwrap()).unwrap().unwrap();
+        assert_eq!(letter.content, "<p>第一段</p>\n<hr />\n<p>第二段</p>\n");
+    }
+
+    #[test]
+    fn test_archive_commit_body() {
+        use xshell::{cmd, Shell};
+
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_letter_dir.path());
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let edit_data = mail_with_subject("[EDIT] 2025/04/03: =?utf-8?B?5rWL6K+V5pWw5o2u?=")
+            .replace("我们这个 I 人交朋友的项目还有效咩", "我们这个 I 人交朋友的项目还有效吗");
+
+        archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        assert_eq!(
+            cmd!(sh, "git log -1 --format=%B").read().unwrap(),
+            "[loveletter] 2025/04/03: 测试数据\n\nDate: 2025-04-03\nTitle: 测试数据\nAuthor: 哥哥\n"
+        );
+        archive.upsert_letter(&RawMail::new(&edit_data).parse().unwrap()).unwrap();
+        assert_eq!(
+            cmd!(sh, "git log -1 --format=%b").read().unwrap(),
+            "Date: 2025-04-03\nTitle: 测试数据\nAction: edit\nAuthor: 哥哥\n"
+        );
+    }
+
+    #[test]
+    fn test_archive_commit_failure() {
+        use std::os::unix::fs::PermissionsExt;
+        use xshell::{cmd, Shell};
+
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.store_plaintext = true;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_letter_dir.path());
+        let hook = tmp_letter_dir.path().join(".git/hooks/pre-commit");
+        let fail_commit = |fail: bool| match fail {
+            true => {
+                fs::create_dir_all(hook.parent().unwrap()).unwrap();
+                fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+                fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+            },
+            false => fs::remove_file(&hook).unwrap(),
+        };
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let edited_data = data.replace("我们这个 I 人交朋友的项目还有效咩", "我们这个 I 人交朋友的项目还有效吗");
+        let upsert_letter = |data: &str| archive.upsert_letter(&RawMail::new(data).parse().unwrap());
+
+        // New letter is removed.
+        fail_commit(true);
+        assert!(upsert_letter(&data).is_err());
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+        assert_eq!(fs::read_dir(tmp_letter_dir.path()).unwrap().count(), 1); // .git only
+        fail_commit(false);
+        let letter = upsert_letter(&data).unwrap().letter().unwrap();
+        let letter_path = archive.letter_path(&letter);
+        let letter_data = fs::read_to_string(&letter_path).unwrap();
+
+        // Existing letter is restored.
+        fail_commit(true);
+        assert!(upsert_letter(&edited_data).is_err());
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+        assert_eq!(fs::read_to_string(&letter_path).unwrap(), letter_data);
+        fail_commit(false);
+        assert!(matches!(upsert_letter(&edited_data), Ok(Upserted::Written(_)))); // not mistaken as duplicate
+        assert!(fs::read_to_string(&letter_path).unwrap().contains("还有效吗"));
+    }
+
+    #[test]
+    fn test_archive_lock() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let mut cfg = cfg;
+        cfg.letter_dir = tmp_letter_dir.path().to_str().unwrap().to_owned();
+        cfg.rstdoc_dir = tmp_rstdoc_dir.path().to_str().unwrap().to_owned();
+
+        let err = Archive::load(cfg.clone()).err().unwrap();
+        assert!(format!("{:#}", err).contains("another loveletter is running"));
+        assert!(Archive::load_read_only(cfg.clone()).is_ok());
+        drop(archive);
+        assert!(Archive::load(cfg).is_ok());
+    }
+
+    #[test]
+    fn test_archive_git_dry_run() {
+        use xshell::{cmd, Shell};
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.git_dry_run = true;
+        cfg.git_pre_cleanup = true;
+        cfg.git_no_push = false;
+        let (archive, tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        archive.generate_all().unwrap();
+
+        // Files are written but neither staged nor committed.
+        let letter_filename = archive.letter_path(&letter).file_name().unwrap().to_str().unwrap().to_owned();
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_letter_dir.path());
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), format!("?? {}", letter_filename));
+        assert!(cmd!(sh, "git rev-parse --verify --quiet HEAD").quiet().run().is_err());
+        sh.change_dir(tmp_rstdoc_dir.path());
+        assert!(cmd!(sh, "git diff --cached --quiet").run().is_ok());
+        assert!(cmd!(sh, "git rev-parse --verify --quiet HEAD").quiet().run().is_err());
+    }
+
+    #[test]
+    fn test_archive_dry_run() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let tmp_letter_dir = tempdir().unwrap();
+        let tmp_rstdoc_dir = tempdir().unwrap();
+        cfg.letter_dir = tmp_letter_dir.path().join("letter").to_str().unwrap().to_owned();
+        cfg.rstdoc_dir = tmp_rstdoc_dir.path().join("rst").to_str().unwrap().to_owned();
+        let archive = Archive::load_read_only(cfg).unwrap().dry_run(true);
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        assert_eq!(letter.title.as_deref(), Some("测试数据"));
+        assert_eq!(archive.import_letters(&[RawMail::new(&data).parse().unwrap()]).unwrap().len(), 1);
+        archive.generate_all().unwrap();
+        // Nothing is written, not even the data dirs.
+        assert_eq!(fs::read_dir(tmp_letter_dir.path()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(tmp_rstdoc_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_archive_load_read_only() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+
+        // Copy letters to directories without .git.
+        let plain_letter_dir = tempdir().unwrap();
+        let plain_rstdoc_dir = tempdir().unwrap();
+        let letter_filename = archive.letter_path(&letter).file_name().unwrap().to_owned();
+        fs::copy(tmp_letter_dir.path().join(&letter_filename), plain_letter_dir.path().join(&letter_filename)).unwrap();
+        let mut cfg = cfg;
+        cfg.letter_dir = plain_letter_dir.path().to_str().unwrap().to_owned();
+        cfg.rstdoc_dir = plain_rstdoc_dir.path().to_str().unwrap().to_owned();
+        cfg.create_dirs = false;
+        assert!(Archive::load(cfg.clone()).is_err());
+
+        let archive = Archive::load_read_only(cfg).unwrap();
+        let letters: Vec<_> = archive.iter_letters().unwrap().map(|(_, x)| x.unwrap()).collect();
+        assert_eq!(letters, vec![letter]);
+        assert!(archive.generate_all().is_err()); // mutating operation requires git
+    }
+
+    #[test]
+    fn test_archive_dedup() {
+        use xshell::{cmd, Shell};
+
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/2025-04-03.toml").unwrap();
+        // Same letter archived under base64 name and later under slug.
+        let older = tmp_letter_dir.path().join("2025-04-03-5rWL6K-V5pWw5o2u.toml");
+        let newer = tmp_letter_dir.path().join("2025-04-03-测试数据.toml");
+        let other = tmp_letter_dir.path().join("2025-04-03.toml");
+        fs::write(&older, &data).unwrap();
+        fs::write(&newer, data.replace("2025-04-03T13:07:14Z", "2025-05-01T00:00:00Z")).unwrap();
+        fs::write(&other, data.replace("还有效咩", "还有效吗")).unwrap();
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_letter_dir.path());
+        cmd!(sh, "git add .").run().unwrap();
+        cmd!(sh, "git commit --message seed").run().unwrap();
+
+        let expected = vec![(newer.clone(), older.clone())];
+        assert_eq!(archive.dedup(false).unwrap(), expected);
+        assert!(newer.exists());
+
+        assert_eq!(archive.dedup(true).unwrap(), expected);
+        assert!(!newer.exists() && older.exists() && other.exists());
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+        assert_eq!(cmd!(sh, "git log -1 --format=%s").read().unwrap(), "[loveletter] remove duplicate letters");
+        assert!(archive.dedup(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_archive_overlapping_dirs() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let tmp_dir = tempdir().unwrap();
+        cfg.letter_dir = tmp_dir.path().to_str().unwrap().to_owned();
+        cfg.rstdoc_dir = tmp_dir.path().join("rst").to_str().unwrap().to_owned();
+        let e = Archive::load(cfg.clone()).err().unwrap();
+        assert!(format!("{}", e).contains("must not be the same or nested"));
+
+        // Same dir after canonicalization.
+        cfg.rstdoc_dir = tmp_dir.path().join("rst/..").to_str().unwrap().to_owned();
+        let e = Archive::load(cfg).err().unwrap();
+        assert!(format!("{}", e).contains("must not be the same or nested"));
+    }
+
+    #[test]
+    fn test_archive_prune() {
+        use xshell::{cmd, Shell};
+
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+        archive.generate_all().unwrap();
+
+        // Seed dirty files.
+        let empty_letter = tmp_letter_dir.path().join("2024-01-01.toml");
+        fs::write(&empty_letter, "").unwrap();
+        let orphaned_rstdoc = archive.rstdoc_page_path(2023, None);
+        fs::copy(archive.rstdoc_page_path(2025, None), &orphaned_rstdoc).unwrap();
+        let orphaned_part = archive.rstdoc_page_path(2025, Some(1));
+        fs::write(&orphaned_part, "").unwrap();
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_rstdoc_dir.path());
+        cmd!(sh, "git add 2023.rst").run().unwrap();
+        cmd!(sh, "git commit --message seed").run().unwrap();
+
+        let expected = PruneReport {
+            orphaned_rstdocs: vec![orphaned_rstdoc.clone(), orphaned_part.clone()],
+            empty_letters: vec![empty_letter.clone()],
+        };
+        assert_eq!(archive.prune(false).unwrap(), expected);
+        assert!(empty_letter.exists() && orphaned_rstdoc.exists() && orphaned_part.exists());
+
+        assert_eq!(archive.prune(true).unwrap(), expected);
+        assert!(!empty_letter.exists() && !orphaned_rstdoc.exists() && !orphaned_part.exists());
+        assert!(archive.rstdoc_page_path(2025, None).exists());
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+        assert!(archive.prune(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_archive_generate_rstdoc_out_of_band() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        // Letter added without mail, e.g. by manual edit.
+        fs::copy("./test_data/2025-04-03.toml", tmp_letter_dir.path().join("2025-04-03.toml")).unwrap();
+        archive.generate_all().unwrap();
+        assert_eq!(
+            read_generated(archive.rstdoc_page_path(2025, None)),
+            fs::read_to_string("./test_data/2025.rst").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_archive_generate_rstdoc_noop() {
+        use xshell::{cmd, Shell};
+
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg);
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap();
+
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_rstdoc_dir.path());
+        archive.generate_all().unwrap();
+        let commits = cmd!(sh, "git rev-list --count HEAD").read().unwrap();
+        assert_eq!(commits, "1");
+        archive.generate_all().unwrap();
+        let commits = cmd!(sh, "git rev-list --count HEAD").read().unwrap();
+        assert_eq!(commits, "1");
+    }
+
+    #[test]
+    fn test_archive_stamp_generated() {
+        use xshell::{cmd, Shell};
+
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.outputs = vec![OutputFormat::Rst, OutputFormat::Markdown];
+        let (archive, tmp_letter_dir, tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        fs::copy("./test_data/2025-04-03.toml", tmp_letter_dir.path().join("2025-04-03.toml")).unwrap();
+        archive.generate_all().unwrap();
+        let page_path = archive.rstdoc_page_path(2025, None);
+        let page = fs::read_to_string(&page_path).unwrap();
+        let stamp = format!(" by loveletter v{}", env!("CARGO_PKG_VERSION"));
+        assert!(page.starts_with(".. generated: ") && page.lines().next().unwrap().ends_with(&stamp), "{}", page);
+        assert!(fs::read_to_string(archive.markdown_page_path(2025)).unwrap().starts_with("<!-- generated: "));
+
+        // Stamp of an older generation is kept if content is unchanged.
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_rstdoc_dir.path());
+        let old_page = format!(".. generated: 2025-04-03T12:00:00Z{}\n\n{}", stamp, strip_generated_stamp(&page));
+        fs::write(&page_path, &old_page).unwrap();
+        cmd!(sh, "git commit -qam restamp").run().unwrap();
+        archive.generate_all().unwrap();
+        assert_eq!(fs::read_to_string(&page_path).unwrap(), old_page);
+        assert_eq!(cmd!(sh, "git rev-list --count HEAD").read().unwrap(), "2");
+
+        cfg.stamp_generated = false;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        fs::copy("./test_data/2025-04-03.toml", tmp_letter_dir.path().join("2025-04-03.toml")).unwrap();
+        archive.generate_all().unwrap();
+        assert_eq!(fs::read_to_string(archive.rstdoc_page_path(2025, None)).unwrap(), strip_generated_stamp(&page));
+    }
+
+    #[test]
+    fn test_archive_generate_rstdoc_corrupted() {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        let corrupted_path = tmp_letter_dir.path().join("2025-04-04.toml");
+        fs::write(&corrupted_path, "from = \"Shengyu Zhang <gege@ex").unwrap();
+        assert!(archive.generate_all().is_err());
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let letter = archive.upsert_letter(&RawMail::new(&data).parse().unwrap()).unwrap().letter().unwrap();
+        archive.generate_all().unwrap();
+        assert_eq!(
+            read_generated(archive.rstdoc_path(&letter)),
+            fs::read_to_string("./test_data/2025.rst").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_archive_squash_import() {
+        use xshell::{cmd, Shell};
+
+        let raw_mails: Vec<RawMail> = ["2025/04/03: 测试数据", "2025/04/04: 第二封", "2025/04/05: 第三封"]
+            .iter()
+            .map(|x| RawMail::new(&mail_with_subject(x)))
+            .collect();
+        let parsed_mails: Vec<ParsedMail> = raw_mails.iter().map(|x| x.parse().unwrap()).collect();
+
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        cfg.squash_import = true;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        assert_eq!(archive.import_letters(&parsed_mails).unwrap().len(), 3);
+
+        let sh = Shell::new().unwrap();
+        sh.change_dir(tmp_letter_dir.path());
+        assert_eq!(cmd!(sh, "git rev-list --count HEAD").read().unwrap(), "1");
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+        assert_eq!(cmd!(sh, "git log -1 --format=%an").read().unwrap(), "Shengyu Zhang");
+        let msg = cmd!(sh, "git log -1 --format=%B").read().unwrap();
+        assert!(msg.starts_with("[loveletter] import 3 letters\n\n2025/04/03: 测试数据\nDate: 2025-04-03\n"));
+        assert!(msg.contains("\n2025/04/05: 第三封\nDate: 2025-04-05\nTitle: 第三封\nAuthor: 哥哥\nFrom: Shengyu Zhang <gege@example.com>"));
+
+        // Nothing to commit if all letters are unchanged.
+        assert!(archive.import_letters(&parsed_mails).unwrap().is_empty());
+        assert_eq!(cmd!(sh, "git rev-list --count HEAD").read().unwrap(), "1");
+    }
+
+    #[test]
+    fn test_archive_export_restore() {
+        use xshell::{cmd, Shell};
+
+        let raw_mails: Vec<RawMail> = ["2025/04/03: 测试数据", "2025/04/04: 第二封"]
+            .iter()
+            .map(|x| RawMail::new(&mail_with_subject(x)))
+            .collect();
+        let parsed_mails: Vec<ParsedMail> = raw_mails.iter().map(|x| x.parse().unwrap()).collect();
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        archive.import_letters(&parsed_mails).unwrap();
+
+        let tmp_dir = TempDir::new().unwrap();
+        let tarball = tmp_dir.path().join("letters.tar.gz");
+        assert_eq!(archive.export(&tarball).unwrap(), 2);
+        assert_eq!(fs::read(&tarball).unwrap()[..2], [0x1f, 0x8b]); // gzip magic
+
+        let (restored, restored_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg);
+        assert_eq!(restored.restore(&tarball).unwrap(), 2);
+        let files = |dir: &Path| {
+            let mut files: Vec<_> = fs::read_dir(dir).unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.is_file())
+                .map(|p| (p.file_name().unwrap().to_owned(), fs::read(&p).unwrap()))
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(files(restored_letter_dir.path()), files(tmp_letter_dir.path()));
+        let sh = Shell::new().unwrap();
+        sh.change_dir(restored_letter_dir.path());
+        assert_eq!(cmd!(sh, "git log -1 --format=%s").read().unwrap(), "[loveletter] restore 2 letters");
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+
+        // A malformed second letter leaves the archive untouched.
+        let bad_dir = tmp_dir.path().join("bad");
+        fs::create_dir(&bad_dir).unwrap();
+        fs::write(bad_dir.join("2025-04-05_a.toml"), &files(tmp_letter_dir.path())[0].1).unwrap();
+        fs::write(bad_dir.join("2025-04-06_b.toml"), "from = ").unwrap();
+        let bad = tmp_dir.path().join("bad.tar.gz");
+        cmd!(sh, "tar --create --gzip --file {bad} --directory {bad_dir} 2025-04-05_a.toml 2025-04-06_b.toml").run().unwrap();
+        let before = files(restored_letter_dir.path());
+        assert!(restored.restore(&bad).is_err());
+        assert_eq!(files(restored_letter_dir.path()), before);
+        assert_eq!(cmd!(sh, "git status --porcelain").read().unwrap(), "");
+        assert_eq!(cmd!(sh, "git rev-list --count HEAD").read().unwrap(), "1");
+
+        // Unexpected path in tarball.
+        let evil = tmp_dir.path().join("evil.tar.gz");
+        let tmp_path = tmp_dir.path();
+        cmd!(sh, "tar --create --gzip --file {evil} --directory {tmp_path} bad").run().unwrap();
+        assert!(restored.restore(&evil).is_err());
+        assert_eq!(files(restored_letter_dir.path()), before);
+    }
+
+    #[test]
+    fn test_archive_import_letters() {
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let data = data
+            .replace("Date: Thu, 03 Apr 2025 21:07:14 +0800", "Date: Fri, 04 Apr 2025 21:07:14 +0800")
+            .replace("张同学", "张同学，");
+        let raw_mail2 = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+        let parsed_mail2 = raw_mail2.parse().unwrap();
+
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let (archive, _tmp_letter_dir, _tmp_rstdoc_dir) = tmp_archive(cfg.clone());
+        let letters = archive.import_letters(&[parsed_mail, parsed_mail2]).unwrap();
+        assert_eq!(letters.len(), 1);
+        let letter = &letters[0];
+        assert_eq!(letter.created_at, Some("2025-04-03T13:07:14Z".parse().unwrap()));
+        assert_eq!(letter.updated_at, Some("2025-04-04T13:07:14Z".parse().unwrap()));
+        assert!(letter.content.contains("张同学，"));
+
+        let parsed_mail = raw_mail.parse().unwrap();
+        let parsed_mail2 = raw_mail2.parse().unwrap();
+        let (archive2, _tmp_letter_dir2, _tmp_rstdoc_dir2) = tmp_archive(cfg);
+        let letters2 = archive2.import_letters(&[parsed_mail2, parsed_mail]).unwrap();
+        assert_eq!(letters, letters2);
+        assert_eq!(
+            fs::read_to_string(archive.letter_path(letter)).unwrap(),
+            fs::read_to_string(archive2.letter_path(&letters2[0])).unwrap()
+        );
     }
 }
@@ -1,7 +1,16 @@
 #![feature(pattern)]
 
 pub mod utils;
+pub mod error;
 pub mod cfg;
+pub mod html;
+pub mod markdown;
+pub mod transform;
 pub mod mail;
 pub mod letter;
 pub mod git;
+pub mod cycle;
+#[cfg(feature = "serve")]
+pub mod serve;
+
+pub use cycle::{run_once, reprocess_seen, CycleReport, Status};
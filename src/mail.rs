@@ -1,38 +1,74 @@
-use std::collections::HashSet;
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt;
 use std::iter::IntoIterator;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use log::{debug, info, error};
+use log::{trace, debug, info, warn, error};
 use chrono::{DateTime, Utc};
 use imap;
-use mail_parser::{MessageParser, Addr, Message, PartType};
+use mail_parser::{MessageParser, Addr, Header, HeaderName, HeaderValue, Message, MimeHeaders, PartType};
 use email_address::EmailAddress;
+use serde_derive::{Deserialize, Serialize};
+use toml;
 
-use crate::cfg::ImapCfg;
+use crate::cfg::{ExpungePolicy, ImapCfg};
+use crate::error::{self, Error};
+use crate::html;
+use crate::utils::{sanitize_filename, unique_filename, write_atomic};
 
 pub struct Mailbox {
+    cfg: ImapCfg,
+    state: MailboxState,
+    caps: Capabilities,
+    // Mails copied but not removed yet by COPY + STORE + EXPUNGE, so that
+    // retrying a partially failed move doesn't copy them twice.
+    copied: HashSet<(String, u32)>,
+    // Mails flagged as \Deleted by us and to be expunged on close, see
+    // ExpungePolicy::OnClose.
+    unexpunged: Vec<u32>,
+    // UIDs of the last unseen fetch and whether they are marked as seen, last_uid
+    // only goes over the leading marked ones so that failed mails are retried.
+    fetched_unseen: Vec<(u32, bool)>,
+    stats: FetchStats, // of the last fetch
+    read_only: bool, // see Mailbox::read_only
     session: imap::Session<Box<dyn imap::ImapConnection>>,
 }
 
 impl Mailbox {
     const INBOX: &str = "INBOX";
 
-    pub fn open(cfg: ImapCfg) -> imap::Result<Mailbox> {
-        info!("connecting to {}:{}...", &cfg.host, cfg.port);
+    pub fn open(cfg: ImapCfg) -> error::Result<Mailbox> {
+        info!("connecting to {}:{}...", cfg.host, cfg.port);
         let client = imap::ClientBuilder::new(&cfg.host, cfg.port).connect()?;
         info!("connected");
 
         // The client we have here is unauthenticated.
         // To do anything useful with the e-mails, we need to log in
-        info!("login with username {}, password: {})...", cfg.username, "*".repeat(cfg.password.len()));
-        let session = client
-            .login(&cfg.username, &cfg.password)
-            .map_err(|e| e.0)?;
+        let password = cfg.password()?;
+        info!("login with username {}, password: {})...", cfg.username, "*".repeat(password.len()));
+        let mut session = client
+            .login(&cfg.username, &password)
+            .map_err(|(e, _)| match e {
+                imap::Error::No(_) | imap::Error::Bad(_) => Error::Auth(e),
+                e => Error::Imap(e),
+            })?;
         info!("logined");
 
-        Ok(Mailbox{session})
+        let server_caps = session.capabilities()?;
+        let caps = Capabilities::from_names(["IDLE", "MOVE", "UIDPLUS"].into_iter().filter(|c| server_caps.has_str(c)));
+        caps.warn_missing();
+
+        let state = match &cfg.state_file {
+            Some(p) => MailboxState::load(p)?,
+            None => MailboxState::default(),
+        };
+
+        Ok(Mailbox{cfg, state, caps, copied: HashSet::new(), unexpunged: Vec::new(), fetched_unseen: Vec::new(), stats: FetchStats::default(), read_only: false, session})
     }
 
     // fn fetch_unseen() -> Result<Recipient> {
@@ -42,88 +78,583 @@ impl Mailbox {
     //     for 
     // }
 
-    fn search(&mut self, query: &str) -> imap::Result<HashSet<u32>> {
-        info!("selecting mailbox {}...", Self::INBOX);
-        let mailbox = self.session.select(Self::INBOX)?;
+    /// Open mailbox by EXAMINE rather than SELECT, and skip marking and
+    /// moving mails and saving state file. Nothing on the server or disk is
+    /// changed then.
+    pub fn read_only(mut self, read_only: bool) -> Mailbox {
+        self.read_only = read_only;
+        self
+    }
+
+    fn select(&mut self) -> imap::Result<()> {
+        info!("selecting mailbox {} (read-only: {})...", Self::INBOX, self.read_only);
+        let mailbox = match self.read_only {
+            true => self.session.examine(Self::INBOX)?,
+            false => self.session.select(Self::INBOX)?,
+        };
         info!("selected, found {} mails ({} recent, {} unread) in mailbox {} (readonly: {})",
         mailbox.exists, mailbox.recent, mailbox.unseen.unwrap_or(0), Self::INBOX, mailbox.is_read_only);
+        self.state.check_uid_validity(mailbox.uid_validity);
+        Ok(())
+    }
 
+    fn search(&mut self, query: &str) -> imap::Result<Vec<u32>> {
         debug!("searching mails that match searching criteria {}", query);
-        let seqs = self.session.search(query)?;
-        debug!("found {} mails that match searching criteria: {:?}", seqs.len(), seqs);
-        Ok(seqs)
+        let mut uids: Vec<u32> = self.session.uid_search(query)?.into_iter().collect();
+        uids.sort();
+        debug!("found {} mails that match searching criteria: {:?}", uids.len(), uids);
+        Ok(uids)
     }
 
-    // TODO: fetch size
     pub fn fetch(&mut self, query: &str) -> imap::Result<Vec<RawMail>> {
-        let seqs = self.search(query)?.
-            into_iter().
-            map(|i| i.to_string()).
-            collect::<Vec<_>>().
-            join(",");
-
-        // Fetch message numbers in this mailbox, along with its RFC822 field.
-        // RFC 822 dictates the format of the body of e-mails.
-        debug!("fetching sequence_set {}...", seqs);
-        let msgs = self.session.fetch(seqs, "RFC822")?;
+        let start = Instant::now();
+        self.select()?;
+        let uids = self.search(query)?;
+        let matched = uids.len();
+        let uids = self.limit_total_bytes(uids)?;
+        let mails = self.fetch_uids(&uids)?;
+        self.record_stats(start, matched, &mails);
+        Ok(mails)
+    }
+
+    /// Metrics of the last fetch.
+    pub fn last_fetch_stats(&self) -> FetchStats {
+        self.stats
+    }
+
+    fn record_stats(&mut self, start: Instant, matched: usize, mails: &[RawMail]) {
+        self.stats = FetchStats {
+            matched,
+            fetched: mails.len(),
+            bytes: mails.iter().map(RawMail::size).sum(),
+            elapsed: start.elapsed(),
+        };
+        info!("{}", self.stats);
+    }
+
+    /// Limit the total size of mails fetched at one time, the rest are left
+    /// untouched (unseen) and will be fetched next time.
+    fn limit_total_bytes(&mut self, uids: Vec<u32>) -> imap::Result<Vec<u32>> {
+        let Some(max_total_bytes) = self.cfg.max_total_bytes.filter(|_| !uids.is_empty()) else {
+            return Ok(uids);
+        };
+        debug!("fetching size of uid_set {:?}...", uids);
+        let sizes = self.session.uid_fetch(Self::sequence_set(&uids), "RFC822.SIZE")?;
+        let mut sizes: Vec<(u32, u64)> = sizes
+            .iter()
+            .filter_map(|m| Some((m.uid?, m.size.unwrap_or(0) as u64)))
+            .collect();
+        sizes.sort();
+        let total = uids.len();
+        let uids = Self::truncate_by_size(&sizes, max_total_bytes);
+        if uids.len() < total {
+            info!("{} of {} mails exceed max_total_bytes {}, deferred",
+                total - uids.len(), total, max_total_bytes);
+        }
+        Ok(uids)
+    }
+
+    fn fetch_uids(&mut self, uids: &[u32]) -> imap::Result<Vec<RawMail>> {
+        // Empty sequence set is a malformed command.
+        if uids.is_empty() {
+            debug!("no mail to fetch");
+            return Ok(Vec::new());
+        }
+        let uids = Self::sequence_set(uids);
+
+        // Fetch message numbers in this mailbox, along with the whole message.
+        // BODY.PEEK[] doesn't set \Seen implicitly as RFC822 does, mails are
+        // only marked as seen by MailSource::mark_seen after they are handled.
+        debug!("fetching uid_set {}...", uids);
+        let msgs = self.session.uid_fetch(uids, "BODY.PEEK[]")?;
         debug!("fetched {} mails", msgs.len());
 
         let mut mails: Vec<RawMail> = Vec::new();
-        // Extract the message's body.
+        let to_raw_mail = |uid: Option<u32>, body: &[u8]| RawMail {
+            uid,
+            account: Some(self.cfg.username.email()),
+            folder: Some(Self::INBOX.to_owned()),
+            ..RawMail::from_bytes(body)
+        };
+        // Extract the message's body, some servers occasionally return no body
+        // in a batch fetch, retry them once.
+        let mut retries = Vec::new();
         for msg in msgs.iter() {
-            match msg.body() {
-                None => {
-                    error!("failed to extract mail body from message: {:?}, skipped", msg);
-                    continue;
-                },
-                Some(body) => match std::str::from_utf8(body) {
-                    Err(e) => {
-                        error!("mail body was not valid utf-8: {}, skipped", e);
-                        continue;
+            trace!("fetched message {} (uid: {:?}, size: {:?})", msg.message, msg.uid, msg.body().map(<[u8]>::len));
+            match (msg.body(), msg.uid) {
+                (Some(body), uid) => mails.push(to_raw_mail(uid, body)),
+                (None, Some(uid)) => retries.push(uid),
+                (None, None) => error!("failed to extract mail body from message: {:?}, skipped", msg),
+            }
+        }
+        if !retries.is_empty() {
+            let uids = Self::sequence_set(&retries);
+            info!("no body is returned for uid_set {}, retrying...", uids);
+            let msgs = self.session.uid_fetch(&uids, "BODY.PEEK[]")?;
+            for msg in msgs.iter() {
+                match msg.body() {
+                    Some(body) => {
+                        debug!("fetched message {} (uid: {:?}) by retry", msg.message, msg.uid);
+                        mails.push(to_raw_mail(msg.uid, body));
                     },
-                    Ok(body) => mails.push(RawMail{data: body.to_owned()}),
-                },
+                    None => error!("failed to extract mail body from message: {:?}, skipped", msg),
+                }
             }
         }
 
         Ok(mails)
     }
 
+    fn sequence_set(seqs: &[u32]) -> String {
+        seqs.iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Take the leading mails whose total size does not exceed `max_total_bytes`
+    /// from the list of (UID, size). At least one mail is taken so
+    /// that oversized mail won't block the others forever.
+    fn truncate_by_size(sizes: &[(u32, u64)], max_total_bytes: u64) -> Vec<u32> {
+        let mut total = 0;
+        let mut seqs = Vec::new();
+        for (seq, size) in sizes {
+            total += size;
+            if total > max_total_bytes && !seqs.is_empty() {
+                break;
+            }
+            seqs.push(*seq);
+        }
+        seqs
+    }
+
     pub fn fetch_seen(&mut self) -> imap::Result<Vec<RawMail>> {
         self.fetch("SEEN")
     }
 
+    /// Fetch unseen mails after the last fetched one.
     pub fn fetch_unseen(&mut self) -> imap::Result<Vec<RawMail>> {
-        self.fetch("UNSEEN")
+        self.fetch_unseen_at_most(None)
+    }
+
+    /// Fetch at most `limit` unseen mails after the last fetched one, the rest
+    /// are left untouched (unseen) and will be fetched next time. The fetched
+    /// mails are fetched again unless they are marked by [`MailSource::mark_seen`].
+    pub fn fetch_unseen_at_most(&mut self, limit: Option<usize>) -> imap::Result<Vec<RawMail>> {
+        let start = Instant::now();
+        self.select()?;
+        let query = self.state.query("UNSEEN");
+        let last_uid = self.state.last_uid.unwrap_or(0);
+        let mut uids: Vec<u32> = self.search(&query)?
+            .into_iter()
+            .filter(|&u| u > last_uid) // "UID N:*" always matches the last mail
+            .collect();
+        let matched = uids.len();
+        if let Some(limit) = limit.filter(|&n| uids.len() > n) {
+            info!("{} of {} mails exceed limit {}, deferred", uids.len() - limit, uids.len(), limit);
+            uids.truncate(limit);
+        }
+        let uids = self.limit_total_bytes(uids)?;
+        let mails = self.fetch_uids(&uids)?;
+        self.record_stats(start, matched, &mails);
+
+        // All of the requested mails, so that the ones failed to be fetched
+        // are not skipped by last_uid.
+        self.fetched_unseen = uids.into_iter().map(|u| (u, false)).collect();
+        Ok(mails)
+    }
+
+    /// Confirm that mails of the last unseen fetch are handled, advance
+    /// `last_uid` to the last one before the first unconfirmed mail and save
+    /// the state.
+    fn confirm_fetched(&mut self, uids: &[u32]) -> imap::Result<()> {
+        for (uid, seen) in self.fetched_unseen.iter_mut() {
+            *seen |= uids.contains(uid);
+        }
+        let last_uid = self.fetched_unseen
+            .iter()
+            .take_while(|(_, seen)| *seen)
+            .map(|(uid, _)| *uid)
+            .last();
+        if last_uid <= self.state.last_uid {
+            return Ok(());
+        }
+        self.state.last_uid = last_uid;
+        if let Some(path) = self.cfg.state_file.as_ref().filter(|_| !self.read_only) {
+            self.state.save(path).map_err(|e| imap::Error::Io(io::Error::other(e)))?;
+        }
+        Ok(())
+    }
+
+    /// Capabilities of IMAP server that we care about, queried on login.
+    pub fn capabilities(&self) -> Capabilities {
+        self.caps
+    }
+
+    /// Move mails to another mailbox, fallback to COPY + STORE + EXPUNGE when
+    /// the server doesn't support MOVE. MOVE expunges implicitly, so it is
+    /// not used unless `expunge` is [`ExpungePolicy::Immediate`].
+    pub fn move_uids(&mut self, uids: &[u32], mailbox: &str) -> imap::Result<()> {
+        let uid_set = Self::sequence_set(uids);
+        if self.read_only {
+            info!("read-only, skip moving uid_set {} to mailbox {}", uid_set, mailbox);
+            return Ok(());
+        }
+        debug!("moving uid_set {} to mailbox {}...", uid_set, mailbox);
+        let method = match self.cfg.expunge {
+            ExpungePolicy::Immediate => self.caps.move_method(),
+            _ => MoveMethod::CopyExpunge { uid_expunge: self.caps.uidplus },
+        };
+        match method {
+            MoveMethod::Move => self.session.uid_mv(&uid_set, mailbox)?,
+            MoveMethod::CopyExpunge { uid_expunge } => {
+                let to_copy: Vec<u32> = uids
+                    .iter()
+                    .filter(|&&uid| !self.copied.contains(&(mailbox.to_owned(), uid)))
+                    .copied()
+                    .collect();
+                if to_copy.len() != uids.len() {
+                    debug!("{} mails are already copied to {}, skipped", uids.len() - to_copy.len(), mailbox);
+                }
+                if !to_copy.is_empty() {
+                    self.session.uid_copy(Self::sequence_set(&to_copy), mailbox)?;
+                    self.copied.extend(to_copy.into_iter().map(|uid| (mailbox.to_owned(), uid)));
+                }
+                self.session.uid_store(&uid_set, "+FLAGS.SILENT (\\Deleted)")
+                    .and_then(|_| match self.cfg.expunge {
+                        ExpungePolicy::Immediate => self.expunge(&uid_set, uid_expunge),
+                        ExpungePolicy::OnClose => {
+                            debug!("uid_set {} is flagged as deleted, to be expunged on close", uid_set);
+                            self.unexpunged.extend(uids);
+                            Ok(())
+                        },
+                        ExpungePolicy::Never => {
+                            debug!("uid_set {} is flagged as deleted, never expunged", uid_set);
+                            Ok(())
+                        },
+                    })
+                    .inspect_err(|e| warn!("uid_set {} is copied to {} but not removed from {}: {}", uid_set, mailbox, Self::INBOX, e))?;
+                self.copied.retain(|(m, uid)| m != mailbox || !uids.contains(uid));
+            },
+        }
+        Ok(())
+    }
+
+    /// Remove mails flagged as \Deleted, only the given ones if `uid_expunge`
+    /// is true, otherwise all of them.
+    fn expunge(&mut self, uid_set: &str, uid_expunge: bool) -> imap::Result<()> {
+        match uid_expunge {
+            true => self.session.uid_expunge(uid_set).map(|_| ()),
+            false => self.session.expunge().map(|_| ()),
+        }
     }
 
     pub fn close(mut self) -> imap::Result<()> {
+        if !self.unexpunged.is_empty() {
+            let uid_set = Self::sequence_set(&self.unexpunged);
+            debug!("expunging uid_set {} on close...", uid_set);
+            self.expunge(&uid_set, self.caps.uidplus)?;
+        }
         self.session.logout()?;
         Ok(())
     }
 }
 
+/// Metrics of a fetch, for spotting slow cycles.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FetchStats {
+    pub matched: usize, // mails matched by the searching criteria
+    pub fetched: usize, // mails downloaded, may be less than matched due to limits
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for FetchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fetched {} of {} matched mails ({} bytes) in {:.2?}",
+            self.fetched, self.matched, self.bytes, self.elapsed)
+    }
+}
+
+/// Where mails come from, implemented by [`Mailbox`] and [`VecMailSource`].
+pub trait MailSource {
+    /// Fetch at most `limit` mails that are not fetched yet, the rest are
+    /// left for the next time.
+    fn fetch_unseen(&mut self, limit: Option<usize>) -> imap::Result<Vec<RawMail>>;
+
+    /// Fetch mails that are already seen, for reprocessing them.
+    fn fetch_seen(&mut self) -> imap::Result<Vec<RawMail>>;
+
+    /// Mark mails of given UIDs as seen.
+    fn mark_seen(&mut self, uids: &[u32]) -> imap::Result<()>;
+}
+
+impl MailSource for Mailbox {
+    fn fetch_unseen(&mut self, limit: Option<usize>) -> imap::Result<Vec<RawMail>> {
+        Mailbox::fetch_unseen_at_most(self, limit)
+    }
+
+    fn fetch_seen(&mut self) -> imap::Result<Vec<RawMail>> {
+        Mailbox::fetch_seen(self)
+    }
+
+    fn mark_seen(&mut self, uids: &[u32]) -> imap::Result<()> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        self.confirm_fetched(uids)?;
+        if self.read_only {
+            info!("read-only, skip marking {} mails as seen", uids.len());
+            return Ok(());
+        }
+        self.session.uid_store(Self::sequence_set(uids), "+FLAGS.SILENT (\\Seen)")?;
+        Ok(())
+    }
+}
+
+/// In-memory mail source, like [`Mailbox`], mails stay unseen and are
+/// fetched again until they are marked as seen.
+#[derive(Default)]
+pub struct VecMailSource {
+    pub mails: Vec<RawMail>,
+    pub seen: Vec<u32>, // UIDs marked as seen by mark_seen
+}
+
+impl VecMailSource {
+    /// Mails without UID are assigned one by their positions, starting from 1.
+    pub fn new(mails: Vec<RawMail>) -> VecMailSource {
+        let mails = mails
+            .into_iter()
+            .enumerate()
+            .map(|(i, m)| RawMail { uid: m.uid.or(Some(i as u32 + 1)), ..m })
+            .collect();
+        VecMailSource { mails, seen: Vec::new() }
+    }
+}
+
+impl MailSource for VecMailSource {
+    fn fetch_unseen(&mut self, limit: Option<usize>) -> imap::Result<Vec<RawMail>> {
+        Ok(self.mails
+            .iter()
+            .filter(|m| !m.uid.is_some_and(|u| self.seen.contains(&u)))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    fn fetch_seen(&mut self) -> imap::Result<Vec<RawMail>> {
+        Ok(self.mails
+            .iter()
+            .filter(|m| m.uid.is_some_and(|u| self.seen.contains(&u)))
+            .cloned()
+            .collect())
+    }
+
+    fn mark_seen(&mut self, uids: &[u32]) -> imap::Result<()> {
+        self.seen.extend_from_slice(uids);
+        Ok(())
+    }
+}
+
+/// IMAP extensions that change how we operate on mailbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    pub idle: bool,    // RFC 2177
+    pub r#move: bool,  // RFC 6851
+    pub uidplus: bool, // RFC 4315
+}
+
+/// How mails are moved between mailboxes, see [`Capabilities::move_method`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MoveMethod {
+    Move,
+    /// COPY, mark as deleted, then EXPUNGE. Only the given mails are expunged
+    /// if `uid_expunge` is true, otherwise all deleted mails.
+    CopyExpunge { uid_expunge: bool },
+}
+
+impl Capabilities {
+    fn from_names<I, S>(names: I) -> Capabilities
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut caps = Capabilities::default();
+        for name in names {
+            match name.as_ref().to_ascii_uppercase().as_str() {
+                "IDLE" => caps.idle = true,
+                "MOVE" => caps.r#move = true,
+                "UIDPLUS" => caps.uidplus = true,
+                _ => (),
+            }
+        }
+        caps
+    }
+
+    fn warn_missing(&self) {
+        info!("server capabilities: {:?}", self);
+        if !self.idle {
+            warn!("server doesn't support IDLE, mails are fetched by polling");
+        }
+        if !self.r#move {
+            warn!("server doesn't support MOVE, fallback to COPY + STORE + EXPUNGE");
+        }
+        if !self.uidplus {
+            warn!("server doesn't support UIDPLUS, EXPUNGE may remove other deleted mails");
+        }
+    }
+
+    fn move_method(&self) -> MoveMethod {
+        match self.r#move {
+            true => MoveMethod::Move,
+            false => MoveMethod::CopyExpunge { uid_expunge: self.uidplus },
+        }
+    }
+}
+
+/// State of mailbox persisted across connections.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MailboxState {
+    pub uid_validity: Option<u32>,
+    pub last_uid: Option<u32>, // UID of the last handled mail, mails after it are fetched again
+}
+
+impl MailboxState {
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<MailboxState> {
+        let p = p.as_ref();
+        if !p.exists() {
+            return Ok(MailboxState::default());
+        }
+        let data = fs::read_to_string(p).with_context(|| format!("{}", p.display()))?;
+        Ok(toml::from_str(&data)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, p: P) -> Result<()> {
+        write_atomic(p, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// UIDs are only meaningful with the same UIDVALIDITY, discard UID state
+    /// when the server reports a different one (for example, folder is recreated).
+    fn check_uid_validity(&mut self, uid_validity: Option<u32>) {
+        if self.uid_validity != uid_validity {
+            if self.uid_validity.is_some() {
+                warn!("UIDVALIDITY changed from {:?} to {:?}, discard UID state",
+                    self.uid_validity, uid_validity);
+            }
+            self.uid_validity = uid_validity;
+            self.last_uid = None;
+        }
+    }
+
+    /// Restrict the searching criteria to mails after the last fetched one.
+    fn query(&self, query: &str) -> String {
+        match self.last_uid {
+            Some(uid) => format!("{} UID {}:*", query, uid + 1),
+            None => query.to_owned(),
+        }
+    }
+}
+
+/// Mail in RFC 822 format, which is not necessarily valid UTF-8.
+#[derive(Clone)]
 pub struct RawMail {
-    pub data: String,
+    pub data: Vec<u8>,
+    pub uid: Option<u32>, // UID in mailbox if the mail is fetched from IMAP server
+    pub account: Option<String>, // IMAP account (username) the mail is fetched from
+    pub folder: Option<String>, // IMAP folder the mail is fetched from
 }
 
 impl RawMail {
     pub fn new(data: &str) -> RawMail {
-        RawMail { data: data.to_owned() }
+        RawMail::from_bytes(data.as_bytes())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> RawMail {
+        RawMail { data: data.to_owned(), uid: None, account: None, folder: None }
+    }
+
+    /// Size of mail in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Load mails from a mbox file or a Maildir directory.
+    pub fn load_all<P: AsRef<Path>>(p: P) -> Result<Vec<RawMail>> {
+        let p = p.as_ref();
+        info!("loading mails from {}...", p.display());
+        let mails = if p.is_dir() {
+            Self::load_maildir(p)?
+        } else {
+            Self::load_mbox(p)?
+        };
+        info!("loaded {} mails", mails.len());
+        Ok(mails)
+    }
+
+    fn load_mbox(p: &Path) -> Result<Vec<RawMail>> {
+        let data = fs::read(p).with_context(|| format!("{}", p.display()))?;
+        let mut mails = Vec::new();
+        let mut buf = Vec::new();
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            // Each mail starts with a "From " line.
+            if line.starts_with(b"From ") {
+                if !buf.is_empty() {
+                    mails.push(RawMail::from_bytes(&std::mem::take(&mut buf)));
+                }
+                continue;
+            }
+            // Unescape ">From " lines quoted by mboxrd.
+            match line.strip_prefix(b">") {
+                Some(l) if l.iter().skip_while(|&&b| b == b'>').take(5).eq(b"From ") => {
+                    buf.extend_from_slice(l)
+                },
+                _ => buf.extend_from_slice(line),
+            }
+        }
+        if !buf.is_empty() {
+            mails.push(RawMail::from_bytes(&buf));
+        }
+        Ok(mails)
+    }
+
+    fn load_maildir(p: &Path) -> Result<Vec<RawMail>> {
+        let mut mails = Vec::new();
+        for subdir in ["cur", "new"] {
+            let dir = p.join(subdir);
+            if !dir.is_dir() {
+                continue;
+            }
+            let mut entries = fs::read_dir(&dir)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, io::Error>>()?;
+            entries.sort();
+            for entry in entries.into_iter().filter(|e| e.is_file()) {
+                let data = fs::read(&entry).with_context(|| format!("{}", entry.display()))?;
+                mails.push(RawMail::from_bytes(&data));
+            }
+        }
+        Ok(mails)
     }
 
     pub fn parse(&self) -> Result<ParsedMail<'_>> {
         info!("parsing raw mail...");
         let msg = MessageParser::default().
-            parse(self.data.as_bytes()).
+            default_header_text().
+            parse(&self.data).
             context("parse failed")?;
+        for header in msg.headers() {
+            trace!("parsed header {} ({} bytes)", header.name.as_str(), header.offset_end - header.offset_start);
+        }
         info!("parsed mail: {}", msg.subject().unwrap_or("untitled"));
-        Ok(ParsedMail{ msg })
+        Ok(ParsedMail{ msg, account: self.account.as_deref(), folder: self.folder.as_deref() })
     }
 
 }
 pub struct ParsedMail<'a> {
     msg: Message<'a>,
+    account: Option<&'a str>,
+    folder: Option<&'a str>,
 }
 
 impl ParsedMail<'_> {
@@ -141,33 +672,199 @@ impl ParsedMail<'_> {
         Self::addr_to_addr(self.msg.from().and_then(|x| x.first()))
     }
 
+    /// All addresses of all From headers (including members of groups), in
+    /// the order they appear in mail.
+    pub fn from_all(&self) -> Vec<EmailAddress> {
+        self.msg
+            .header_values(HeaderName::From)
+            .filter_map(HeaderValue::as_address)
+            .flat_map(|x| x.iter())
+            .filter_map(|x| Self::addr_to_addr(Some(x)))
+            .collect()
+    }
+
     /// NOTE: Only support single address for now.
     pub fn to(&self) -> Option<EmailAddress> {
         Self::addr_to_addr(self.msg.to().and_then(|x| x.first()))
     }
 
+    /// Mail in RFC 822 format as it is.
+    pub fn raw(&self) -> &[u8] {
+        &self.msg.raw_message
+    }
+
+    /// IMAP account the mail is fetched from, see [`RawMail::account`].
+    pub fn account(&self) -> Option<&str> {
+        self.account
+    }
+
+    /// IMAP folder the mail is fetched from, see [`RawMail::folder`].
+    pub fn folder(&self) -> Option<&str> {
+        self.folder
+    }
+
     pub fn subject(&self) -> Option<&str> {
         self.msg.subject()
     }
 
+    /// Get decoded value of the first header with given name (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.header_all(name).into_iter().next()
+    }
+
+    /// Get decoded values of all headers with given name (case-insensitive), in
+    /// the order they appear in mail.
+    pub fn header_all(&self, name: &str) -> Vec<Cow<'_, str>> {
+        self.msg
+            .headers()
+            .iter()
+            .filter(|h| h.name.as_str().eq_ignore_ascii_case(name))
+            .filter_map(|h| self.header_to_text(h))
+            .collect()
+    }
+
+    fn header_to_text<'a>(&'a self, header: &'a Header) -> Option<Cow<'a, str>> {
+        match &header.value {
+            HeaderValue::Text(x) => Some(Cow::Borrowed(x)),
+            HeaderValue::TextList(x) => Some(Cow::Owned(x.join(", "))),
+            HeaderValue::Address(x) => Some(Cow::Owned(x
+                .iter()
+                .filter_map(|a| Self::addr_to_addr(Some(a)))
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", "))),
+            HeaderValue::DateTime(x) => Some(Cow::Owned(x.to_rfc822())),
+            HeaderValue::ContentType(x) => Some(match x.subtype() {
+                Some(subtype) => Cow::Owned(format!("{}/{}", x.ctype(), subtype)),
+                None => Cow::Borrowed(x.ctype()),
+            }),
+            // Fallback to raw value with folded lines unfolded.
+            _ => self.msg.raw_message
+                .get(header.offset_start..header.offset_end)
+                .and_then(|x| std::str::from_utf8(x).ok())
+                .map(|x| Cow::Owned(x.split_whitespace().collect::<Vec<_>>().join(" "))),
+        }
+    }
+
     pub fn date(&self) -> Option<DateTime<Utc>> {
         self.msg.date().
             and_then(|x| DateTime::from_timestamp(x.to_timestamp(), 0))
     }
 
-    // TODO: deal with multipart
+    /// Plain text body with whitespaces normalized and signature stripped,
+    /// see [`normalize_text`].
+    pub fn text_body(&self) -> Option<String> {
+        self.text_body_raw().map(|x| normalize_text(&x, true))
+    }
+
+    /// Plain text body as it is, HTML is converted to text if there is no
+    /// plain text part.
+    pub fn text_body_raw(&self) -> Option<String> {
+        self.text_body_joined("\n")
+    }
+
+    /// Same as [`ParsedMail::text_body_raw`], but multiple parts are joined
+    /// with `sep`.
+    pub fn text_body_joined(&self, sep: &str) -> Option<String> {
+        let mut body: Vec<Cow<'_, str>> = Vec::new();
+        for part in self.msg.text_bodies() {
+            match &part.body {
+                PartType::Text(x) => body.push(x.clone()),
+                PartType::Html(x) => body.push(Cow::Owned(html::to_text(x))),
+                _ => (),
+            }
+        }
+        match body.is_empty() {
+            true => None,
+            false => Some(body.join(sep)),
+        }
+    }
+
+    /// Body of the first part whose Content-ID is `id` (angle brackets are
+    /// optional), plain text is converted to HTML.
+    pub fn body_by_content_id(&self, id: &str) -> Option<String> {
+        let id = id.trim_start_matches('<').trim_end_matches('>');
+        self.msg
+            .parts
+            .iter()
+            .filter(|part| part.content_id().is_some_and(|x| x.trim_start_matches('<').trim_end_matches('>') == id))
+            .find_map(|part| match &part.body {
+                PartType::Html(x) => Some(x.to_string()),
+                PartType::Text(x) => Some(html::from_text(x)),
+                _ => None,
+            })
+    }
+
+    /// Metadata of attachments, in the order they appear in mail.
+    ///
+    /// Filenames (RFC 2231 and RFC 2047 encodings are decoded by parser) are
+    /// sanitized and made unique within the mail, see [`sanitize_filename`].
+    pub fn attachments(&self) -> Vec<Attachment> {
+        let mut taken = HashSet::new();
+        self.msg
+            .attachments()
+            .map(|part| Attachment {
+                filename: unique_filename(&sanitize_filename(part.attachment_name().unwrap_or("untitled")), &mut taken),
+                size: part.len() as u64,
+                content_type: part.content_type().map(|x| match x.subtype() {
+                    Some(subtype) => format!("{}/{}", x.ctype(), subtype),
+                    None => x.ctype().to_owned(),
+                }),
+            })
+            .collect()
+    }
+
+    /// HTML body, if there are multiple parts, they are unwrapped from their
+    /// own documents and wrapped in `<div>` each so that the result is still
+    /// a valid fragment.
     pub fn html_body(&self) -> Option<String> {
         let mut body:Vec<Cow<'_, str>> = Vec::new();
         for part in self.msg.html_bodies() {
             if let PartType::Html(x) = &part.body {
-                body.push(x.to_owned())
+                body.push(x.clone())
             }
         }
-        match body.is_empty() {
-            true => None,
-            false => Some(body.join("\n")),
+        match body.len() {
+            0 => None,
+            1 => Some(body.remove(0).into_owned()),
+            _ => Some(body
+                .iter()
+                .map(|x| format!("<div>{}</div>", html::body_inner(x).trim()))
+                .collect::<Vec<_>>()
+                .join("\n")),
+        }
+    }
+}
+
+/// Attachment of mail, only metadata is kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub size: u64, // decoded size in bytes
+    pub content_type: Option<String>,
+}
+
+/// Normalize plain text: collapse runs of whitespaces (including NBSP) in each
+/// line and consecutive blank lines, then trim. If `strip_signature` is true,
+/// text after the signature delimiter "-- " is cut.
+pub fn normalize_text(s: &str, strip_signature: bool) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in s.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if strip_signature && line == "--" {
+            break;
         }
+        if line.is_empty() && lines.last().is_none_or(|l| l.is_empty()) {
+            continue;
+        }
+        lines.push(line);
     }
+    lines.join("\n").trim().to_owned()
+}
+
+/// Cut plain text from the first line that is a signature delimiter.
+pub fn strip_signature<F: Fn(&str) -> bool>(s: &str, is_delimiter: F) -> String {
+    s.lines().take_while(|x| !is_delimiter(x)).collect::<Vec<_>>().join("\n")
 }
 
 impl fmt::Display for ParsedMail<'_> {
@@ -196,7 +893,7 @@ impl fmt::Display for ParsedMail<'_> {
                             fmt_indent!("{}", line);
                         }
                     },
-                    PartType::Message(m) => recursive_fmt(f, &m, indent+1)?,
+                    PartType::Message(m) => recursive_fmt(f, m, indent+1)?,
                     _ => fmt_indent!("{:?}", p.body),
                 }
             }
@@ -211,12 +908,13 @@ impl fmt::Display for ParsedMail<'_> {
 mod tests {
     use super::*;
     use std::fs;
+    use std::sync::{Arc, Mutex};
     use crate::cfg::Cfg;
 
     #[test]
     fn test_raw_mail_parse1() {
         let data = fs::read_to_string("./test_data/mail.txt").unwrap();
-        let raw_mail = RawMail{data};
+        let raw_mail = RawMail::new(&data);
         let parsed_mail = raw_mail.parse().unwrap();
         assert_eq!(parsed_mail.from(), Some(EmailAddress::new_unchecked("Shengyu Zhang <gege@example.com>")));
         assert_eq!(parsed_mail.to(), Some(EmailAddress::new_unchecked("Love Letter <loveletter@example.com>")));
@@ -224,18 +922,448 @@ mod tests {
         assert_eq!(parsed_mail.html_body(), Some("<div>张同学 我们这个 I 人交朋友的项目还有效咩</div><div>\u{a0}</div><div>--\u{a0}</div><div>Best regards,</div><div>Shengyu Zhang</div><div>\u{a0}</div><div>https://example.com</div><div>\u{a0}</div>\n".to_string()));
     }
 
+    #[test]
+    fn test_raw_mail_from_bytes() {
+        let data = b"From: gege@example.com\r\nSubject: 2025/04/03\r\nContent-Type: text/html; charset=iso-8859-1\r\n\r\n<p>caf\xe9</p>\r\n".to_vec();
+        let raw_mail = RawMail::from_bytes(&data);
+        assert_eq!(raw_mail.data, data);
+        assert_eq!(raw_mail.size(), data.len());
+        let parsed_mail = raw_mail.parse().unwrap();
+        assert_eq!(parsed_mail.subject(), Some("2025/04/03"));
+        assert_eq!(parsed_mail.html_body(), Some("<p>café</p>\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_parsed_mail_text_body() {
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+        assert_eq!(parsed_mail.text_body_raw(), Some("张同学 我们这个 I 人交朋友的项目还有效咩\n\u{a0}\n--\u{a0}\nBest regards,\nShengyu Zhang\n\u{a0}\nhttps://example.com\n\u{a0}\n".to_string()));
+        assert_eq!(parsed_mail.text_body(), Some("张同学 我们这个 I 人交朋友的项目还有效咩".to_string()));
+        assert_eq!(normalize_text(" foo\u{a0}\u{a0}bar \n\n\n\nbaz\n-- \nsignature", false), "foo bar\n\nbaz\n--\nsignature");
+    }
+
+    #[test]
+    fn test_parsed_mail_header() {
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+        assert_eq!(parsed_mail.header("Message-ID").as_deref(), Some("150821743685460@mail.example.com"));
+        assert_eq!(parsed_mail.header("x-yandex-spam").as_deref(), Some("1"));
+        assert_eq!(parsed_mail.header("X-Mailer").as_deref(), Some("Yamail [ http://example.com ] 5.0"));
+        assert_eq!(parsed_mail.header("Subject").as_deref(), Some("2025/04/03: 测试数据"));
+        assert_eq!(parsed_mail.header("X-Not-Exist"), None);
+
+        let received = parsed_mail.header_all("Received");
+        assert_eq!(received.len(), 4);
+        assert!(received[0].starts_with("from postback21a.mail.example.com"));
+        assert!(received[3].ends_with("Thu, 03 Apr 2025 16:07:14 +0300"));
+    }
+
     #[test]
     fn test_raw_mail_parse2() {
         let data = fs::read_to_string("./test_data/mail2.txt").unwrap();
-        let raw_mail = RawMail{data};
+        let raw_mail = RawMail::new(&data);
         let parsed_mail = raw_mail.parse().unwrap();
         assert_eq!(parsed_mail.html_body(), Some("<p>foo</p>\n".to_string()));
     }
 
+    #[test]
+    fn test_parsed_mail_multipart_body() {
+        let data = fs::read_to_string("./test_data/mail5.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let mail = raw_mail.parse().unwrap();
+        assert_eq!(mail.html_body().unwrap(), "<div><p>第一段</p></div>\n<div><p>第二段</p></div>");
+        assert_eq!(mail.text_body_raw().unwrap().trim(), "第一段\n\n第二段");
+        assert_eq!(mail.text_body_joined("\n---\n").unwrap().trim(), "第一段\n\n---\n第二段");
+    }
+
+    #[test]
+    fn test_parsed_mail_body_by_content_id() {
+        let raw_mail = RawMail::new(&fs::read_to_string("./test_data/mail7.txt").unwrap());
+        let parsed_mail = raw_mail.parse().unwrap();
+        assert_eq!(parsed_mail.html_body().unwrap(), "<div>请查收附件中的信</div>");
+        assert_eq!(parsed_mail.body_by_content_id("letter").unwrap(), "<div>这才是真正的信</div>");
+        assert_eq!(parsed_mail.body_by_content_id("<letter>").unwrap(), "<div>这才是真正的信</div>");
+        assert!(parsed_mail.body_by_content_id("other").is_none());
+    }
+
+    #[test]
+    fn test_parsed_mail_from_all() {
+        let data = fs::read_to_string("./test_data/mail9.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+        assert_eq!(parsed_mail.from().unwrap().email(), "stranger@example.com");
+        let from_all: Vec<_> = parsed_mail.from_all().iter().map(EmailAddress::email).collect();
+        assert_eq!(from_all, vec!["stranger@example.com", "gege@example.com"]);
+
+        // Multiple From headers.
+        let data = data.replace(", Shengyu Zhang <gege@example.com>", "\nFrom: Shengyu Zhang <gege@example.com>");
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+        let from_all: Vec<_> = parsed_mail.from_all().iter().map(EmailAddress::email).collect();
+        assert_eq!(from_all, vec!["stranger@example.com", "gege@example.com"]);
+    }
+
+    #[test]
+    fn test_parsed_mail_attachments() {
+        let data = fs::read_to_string("./test_data/mail3.txt").unwrap();
+        let raw_mail = RawMail::new(&data);
+        let parsed_mail = raw_mail.parse().unwrap();
+        assert_eq!(parsed_mail.html_body(), Some("<p>photo and note attached</p>\n".to_string()));
+        assert_eq!(parsed_mail.attachments(), vec![
+            Attachment{ filename: "photo.png".to_string(), size: 2056, content_type: Some("image/png".to_string()) },
+            Attachment{ filename: "笔记.pdf".to_string(), size: 509, content_type: Some("application/pdf".to_string()) },
+        ]);
+
+        // RFC 2231 encoded filename.
+        let data2 = data.replace("filename=\"photo.png\"", "filename*=utf-8''%E6%88%91%E7%9A%84%20photo%3F.PNG");
+        assert_eq!(RawMail::new(&data2).parse().unwrap().attachments()[0].filename, "我的_photo.png");
+        // Colliding filenames.
+        let data2 = data.replace("filename=\"photo.png\"", "filename=\"=?utf-8?B?56yU6K6wLnBkZg==?=\"");
+        let filenames: Vec<_> = RawMail::new(&data2).parse().unwrap().attachments().into_iter().map(|x| x.filename).collect();
+        assert_eq!(filenames, vec!["笔记.pdf", "笔记-2.pdf"]);
+
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        assert_eq!(RawMail::new(&data).parse().unwrap().attachments(), vec![]);
+    }
+
+    #[test]
+    fn test_mailbox_truncate_by_size() {
+        let sizes = [(1, 100), (2, 200), (5, 300)];
+        assert_eq!(Mailbox::truncate_by_size(&sizes, 1000), vec![1, 2, 5]);
+        assert_eq!(Mailbox::truncate_by_size(&sizes, 300), vec![1, 2]);
+        assert_eq!(Mailbox::truncate_by_size(&sizes, 299), vec![1]);
+        assert_eq!(Mailbox::truncate_by_size(&sizes, 10), vec![1]);
+        assert_eq!(Mailbox::truncate_by_size(&[], 10), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::from_names(["IMAP4rev1", "IDLE", "move", "UIDPLUS"]);
+        assert_eq!(caps, Capabilities{ idle: true, r#move: true, uidplus: true });
+        assert_eq!(caps.move_method(), MoveMethod::Move);
+
+        let caps = Capabilities::from_names(["IMAP4rev1", "UIDPLUS"]);
+        assert!(!caps.idle);
+        assert_eq!(caps.move_method(), MoveMethod::CopyExpunge { uid_expunge: true });
+        assert_eq!(Capabilities::default().move_method(), MoveMethod::CopyExpunge { uid_expunge: false });
+    }
+
+    #[test]
+    fn test_mailbox_state() {
+        let mut state = MailboxState::default();
+        assert_eq!(state.query("UNSEEN"), "UNSEEN");
+        state.check_uid_validity(Some(1));
+        state.last_uid = Some(42);
+        assert_eq!(state.query("UNSEEN"), "UNSEEN UID 43:*");
+
+        // Same validity keeps UID state.
+        state.check_uid_validity(Some(1));
+        assert_eq!(state.last_uid, Some(42));
+
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        state.save(tmp.path()).unwrap();
+        let mut state = MailboxState::load(tmp.path()).unwrap();
+        assert_eq!(state, MailboxState{ uid_validity: Some(1), last_uid: Some(42) });
+
+        // Changed validity discards UID state.
+        state.check_uid_validity(Some(2));
+        assert_eq!(state, MailboxState{ uid_validity: Some(2), last_uid: None });
+        assert_eq!(state.query("UNSEEN"), "UNSEEN");
+    }
+
+    #[test]
+    fn test_raw_mail_load_mbox() {
+        let data = fs::read_to_string("./test_data/mail.txt").unwrap();
+        let mbox = format!("From gege@example.com Thu Apr  3 21:07:14 2025\n{}\nFrom meimei@example.com Thu Apr  3 21:07:14 2025\n>From here\n>>From there\n>> quoted\n", data);
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        fs::write(tmp.path(), mbox).unwrap();
+        let mails = RawMail::load_all(tmp.path()).unwrap();
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0].data, (data + "\n").as_bytes());
+        assert_eq!(mails[1].data, b"From here\n>From there\n>> quoted\n");
+    }
+
+    /// Connection replying canned responses, requests are recorded.
+    struct MockConnection {
+        responses: io::Cursor<Vec<u8>>,
+        requests: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl io::Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.responses.read(buf)
+        }
+    }
+
+    impl io::Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.requests.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl imap::extensions::idle::SetReadTimeout for MockConnection {
+        fn set_read_timeout(&mut self, _: Option<std::time::Duration>) -> imap::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Logined mailbox over [`MockConnection`], returns also the requests.
+    fn mock_mailbox(responses: &[u8], caps: Capabilities) -> (Mailbox, Arc<Mutex<Vec<u8>>>) {
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().imap.remove(0);
+        let requests = Arc::default();
+        let conn = MockConnection {
+            responses: io::Cursor::new([b"a1 OK logged in\r\n", responses].concat()),
+            requests: Arc::clone(&requests),
+        };
+        let conn: Box<dyn imap::ImapConnection> = Box::new(conn);
+        let session = imap::Client::new(conn).login("u", "p").map_err(|(e, _)| e).unwrap();
+        let mailbox = Mailbox{ cfg, state: MailboxState::default(), caps, copied: HashSet::new(), unexpunged: Vec::new(), fetched_unseen: Vec::new(), stats: FetchStats::default(), read_only: false, session };
+        (mailbox, requests)
+    }
+
+    #[test]
+    fn test_mailbox_fetch_empty() {
+        let (mut mailbox, requests) = mock_mailbox(b"\
+* 0 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a2 OK [READ-WRITE] selected\r\n\
+* SEARCH\r\n\
+a3 OK searched\r\n", Capabilities::default());
+
+        assert!(mailbox.fetch_seen().unwrap().is_empty());
+        let requests = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert!(requests.contains("a3 UID SEARCH SEEN\r\n"));
+        assert!(!requests.contains("FETCH"));
+        assert_eq!(mailbox.last_fetch_stats().matched, 0);
+    }
+
+    #[test]
+    fn test_mailbox_fetch_stats() {
+        let mail = b"Subject: hello\r\n\r\nworld\r\n";
+        let responses = [
+            format!("\
+* 2 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a2 OK [READ-WRITE] selected\r\n\
+* SEARCH 3 4 5\r\n\
+a3 OK searched\r\n\
+* 1 FETCH (UID 3 BODY[] {{{}}}\r\n", mail.len()).as_bytes(),
+            mail,
+            b")\r\na4 OK fetched\r\n",
+        ].concat();
+        let (mut mailbox, requests) = mock_mailbox(&responses, Capabilities::default());
+
+        // Only 1 mail is returned by server although 3 are matched.
+        let mails = mailbox.fetch_unseen_at_most(Some(2)).unwrap();
+        assert_eq!(mails.len(), 1);
+        let requests = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert!(requests.contains("a4 UID FETCH 3,4 BODY.PEEK[]\r\n"));
+        let stats = mailbox.last_fetch_stats();
+        assert_eq!((stats.matched, stats.fetched, stats.bytes), (3, 1, mail.len()));
+        assert_eq!(stats.to_string(), format!("fetched 1 of 3 matched mails ({} bytes) in {:.2?}", mail.len(), stats.elapsed));
+    }
+
+    #[test]
+    fn test_mailbox_fetch_body_fallback() {
+        let mail = b"Subject: hello\r\n\r\nworld\r\n";
+        let responses = [
+            b"\
+* 2 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a2 OK [READ-WRITE] selected\r\n\
+* SEARCH 3 4\r\n\
+a3 OK searched\r\n\
+* 1 FETCH (UID 3 BODY[] NIL)\r\n".as_slice(),
+            format!("* 2 FETCH (UID 4 BODY[] {{{}}}\r\n", mail.len()).as_bytes(),
+            mail,
+            b")\r\na4 OK fetched\r\n",
+            format!("* 1 FETCH (UID 3 BODY[] {{{}}}\r\n", mail.len()).as_bytes(),
+            mail,
+            b")\r\na5 OK fetched\r\n",
+            b"\
+* 4 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a6 OK [READ-WRITE] selected\r\n\
+* SEARCH 5 6\r\n\
+a7 OK searched\r\n\
+* 3 FETCH (UID 5 BODY[] NIL)\r\n",
+            format!("* 4 FETCH (UID 6 BODY[] {{{}}}\r\n", mail.len()).as_bytes(),
+            mail,
+            b")\r\na8 OK fetched\r\n\
+* 3 FETCH (UID 5 BODY[] NIL)\r\n\
+a9 OK fetched\r\n\
+a10 OK stored\r\n",
+        ].concat();
+        let (mut mailbox, requests) = mock_mailbox(&responses, Capabilities::default());
+
+        let mails = mailbox.fetch_seen().unwrap();
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![Some(4), Some(3)]);
+        assert!(mails.iter().all(|m| m.size() == mail.len()));
+        let sent = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert!(sent.contains("a4 UID FETCH 3,4 BODY.PEEK[]\r\n"));
+        assert!(sent.contains("a5 UID FETCH 3 BODY.PEEK[]\r\n"));
+
+        // Mail 5 still has no body after retry, last_uid doesn't go over it.
+        let mails = mailbox.fetch_unseen().unwrap();
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![Some(6)]);
+        mailbox.mark_seen(&[6]).unwrap();
+        assert_eq!(mailbox.fetched_unseen, vec![(5, false), (6, true)]);
+        assert_eq!(mailbox.state.last_uid, None);
+        let sent = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert!(sent.contains("a9 UID FETCH 5 BODY.PEEK[]\r\n"));
+    }
+
+    #[test]
+    fn test_mailbox_retry_unconfirmed() {
+        let mail = b"Subject: hello\r\n\r\nworld\r\n";
+        let fetched = |i, uid| [
+            format!("* {} FETCH (UID {} BODY[] {{{}}}\r\n", i, uid, mail.len()).into_bytes(),
+            mail.to_vec(),
+            b")\r\n".to_vec(),
+        ].concat();
+        let responses = [
+            b"\
+* 3 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a2 OK [READ-WRITE] selected\r\n\
+* SEARCH 3 4 5\r\n\
+a3 OK searched\r\n".to_vec(),
+            fetched(1, 3),
+            fetched(2, 4),
+            fetched(3, 5),
+            b"\
+a4 OK fetched\r\n\
+a5 OK stored\r\n\
+* 3 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a6 OK [READ-WRITE] selected\r\n\
+* SEARCH 4\r\n\
+a7 OK searched\r\n".to_vec(),
+            fetched(2, 4),
+            b"a8 OK fetched\r\n".to_vec(),
+        ].concat();
+        let (mailbox, requests) = mock_mailbox(&responses, Capabilities::default());
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_file = tmp_dir.path().join("mailbox.toml");
+        let mut mailbox = Mailbox {
+            cfg: ImapCfg { state_file: Some(state_file.to_str().unwrap().to_owned()), ..mailbox.cfg.clone() },
+            ..mailbox
+        };
+
+        // Mail 4 failed to be handled, it is fetched again but 5 is not.
+        assert_eq!(MailSource::fetch_unseen(&mut mailbox, None).unwrap().len(), 3);
+        assert!(!state_file.exists());
+        mailbox.mark_seen(&[3, 5]).unwrap();
+        assert_eq!(MailboxState::load(&state_file).unwrap().last_uid, Some(3));
+        let mails = MailSource::fetch_unseen(&mut mailbox, None).unwrap();
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![Some(4)]);
+        let requests = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert!(requests.contains("a7 UID SEARCH UNSEEN UID 4:*\r\n"));
+        // Fetching doesn't set \Seen, only the handled mails are marked.
+        assert_eq!(requests.matches("UID FETCH 3,4,5 BODY.PEEK[]\r\n").count(), 1);
+        assert!(requests.contains("a8 UID FETCH 4 BODY.PEEK[]\r\n"));
+        assert_eq!(requests.matches("STORE").count(), 1);
+        assert!(requests.contains("a5 UID STORE 3,5 +FLAGS.SILENT (\\Seen)\r\n"));
+    }
+
+    #[test]
+    fn test_mailbox_read_only() {
+        let mail = b"Subject: hello\r\n\r\nworld\r\n";
+        let responses = [
+            b"\
+* 1 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+a2 OK [READ-ONLY] examined\r\n\
+* SEARCH 3\r\n\
+a3 OK searched\r\n".as_slice(),
+            format!("* 1 FETCH (UID 3 BODY[] {{{}}}\r\n", mail.len()).as_bytes(),
+            mail,
+            b")\r\na4 OK fetched\r\n",
+        ].concat();
+        let (mailbox, requests) = mock_mailbox(&responses, Capabilities::default());
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_file = tmp_dir.path().join("mailbox.toml");
+        let mut mailbox = Mailbox {
+            cfg: ImapCfg { state_file: Some(state_file.to_str().unwrap().to_owned()), ..mailbox.cfg.clone() },
+            ..mailbox.read_only(true)
+        };
+
+        assert_eq!(MailSource::fetch_unseen(&mut mailbox, None).unwrap().len(), 1);
+        mailbox.mark_seen(&[3]).unwrap();
+        mailbox.move_uids(&[3], "Archive").unwrap();
+        let requests = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert!(requests.contains("a2 EXAMINE \"INBOX\"\r\n"));
+        assert!(!requests.contains("SELECT") && !requests.contains("STORE") && !requests.contains("MOVE"));
+        assert!(!state_file.exists());
+    }
+
+    #[test]
+    fn test_mailbox_move_fallback() {
+        let caps = Capabilities { uidplus: true, ..Capabilities::default() };
+        let (mut mailbox, requests) = mock_mailbox(b"\
+a2 OK copied\r\n\
+a3 NO store failed\r\n\
+a4 OK stored\r\n\
+a5 OK expunged\r\n", caps);
+
+        // Copied but not removed, retry doesn't copy again.
+        assert!(mailbox.move_uids(&[3, 4], "Archive").is_err());
+        mailbox.move_uids(&[3, 4], "Archive").unwrap();
+        assert!(mailbox.copied.is_empty());
+        let requests = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+        assert_eq!(requests.matches("UID COPY").count(), 1);
+        assert!(requests.contains("a2 UID COPY 3,4 Archive\r\n"));
+        assert!(requests.contains("a4 UID STORE 3,4 +FLAGS.SILENT (\\Deleted)\r\n"));
+        assert!(requests.contains("a5 UID EXPUNGE 3,4\r\n"));
+        assert!(!requests.contains("MOVE"));
+    }
+
+    #[test]
+    fn test_mailbox_expunge_policy() {
+        let caps = Capabilities { r#move: true, uidplus: true, ..Capabilities::default() };
+        let responses = b"\
+a2 OK copied\r\n\
+a3 OK stored\r\n\
+a4 OK expunged\r\n\
+* BYE logging out\r\n\
+a5 OK logged out\r\n";
+        let moved = |expunge| {
+            let (mailbox, requests) = mock_mailbox(responses, caps);
+            let mut mailbox = Mailbox { cfg: ImapCfg { expunge, ..mailbox.cfg.clone() }, ..mailbox };
+            mailbox.move_uids(&[3, 4], "Archive").unwrap();
+            let before_close = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+            mailbox.close().unwrap();
+            let after_close = String::from_utf8(requests.lock().unwrap().clone()).unwrap();
+            let on_close = after_close[before_close.len()..].to_owned();
+            (before_close, on_close)
+        };
+
+        let (before_close, on_close) = moved(ExpungePolicy::Immediate);
+        assert!(before_close.contains("a2 UID MOVE 3,4 \"Archive\"\r\n"));
+        assert!(!on_close.contains("EXPUNGE"));
+
+        let (before_close, on_close) = moved(ExpungePolicy::OnClose);
+        assert!(before_close.contains("a2 UID COPY 3,4 Archive\r\n"));
+        assert!(before_close.contains("a3 UID STORE 3,4 +FLAGS.SILENT (\\Deleted)\r\n"));
+        assert!(!before_close.contains("EXPUNGE") && !before_close.contains("MOVE"));
+        assert!(on_close.starts_with("a4 UID EXPUNGE 3,4\r\n"));
+
+        let (before_close, on_close) = moved(ExpungePolicy::Never);
+        assert!(before_close.contains("a3 UID STORE 3,4 +FLAGS.SILENT (\\Deleted)\r\n"));
+        assert!(!before_close.contains("EXPUNGE") && !on_close.contains("EXPUNGE"));
+    }
+
     #[ignore]
     #[test]
     fn test_mailbox() {
-        let cfg = Cfg::load("./test_data/config.toml").unwrap().imap;
+        let cfg = Cfg::load("./test_data/config.toml").unwrap().imap.remove(0);
         let mut mailbox = Mailbox::open(cfg).unwrap();
         let mails = mailbox.fetch_seen().unwrap();
         assert!(!mails.is_empty());
@@ -1,113 +1,308 @@
+use std::path::Path;
 use std::process::ExitCode;
-use std::time::Duration;
 use std::thread;
 
-use anyhow::Result;
-use log::{Level, info, warn, error};
-use clap::Parser;
-use imap;
+use anyhow::{Context, Result};
+use log::{Level, debug, info, warn, error};
+use clap::{ArgAction, ArgGroup, Parser};
 
-use loveletter::utils::{logger, exit};
-use loveletter::cfg::Cfg;
-use loveletter::mail::Mailbox;
-use loveletter::letter::Archive;
+use loveletter::utils::{logger, exit, jitter};
+use loveletter::cfg::{Cfg, ImapCfg};
+use loveletter::error::Error;
+use loveletter::mail::{Mailbox, RawMail};
+use loveletter::letter::{Archive, DateRange, GenerateOptions};
+use loveletter::{CycleReport, Status};
 
 /// 🐟 ← 💌 ← 📬 ← 💌 ← 🦢
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)] // Read from `Cargo.toml`
+#[command(group(ArgGroup::new("fixable").args(["prune", "dedup"]).multiple(true)))]
 struct Args {
     /// Specify the location of the configuration file
     #[arg(short, long, default_value = "./config.toml")] 
     config: String, 
 
-    /// Specify log level [avail: debug, info, warn, error]
+    /// Specify log level [avail: trace, debug, info, warn, error]
     #[arg(long)] // TODO: ValueEnum
     log_level: Option<Level>,
 
-    /// Re-generate rstdoc and exit
+    /// Increase log verbosity, can be repeated (overridden by --log-level)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Decrease log verbosity, can be repeated (overridden by --log-level)
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// Colorize terminal output [avail: always, auto, never]
+    #[arg(long, default_value = "auto")]
+    color: logger::Color,
+
+    /// Override letter_dir of configuration
+    #[arg(long)]
+    letter_dir: Option<String>,
+
+    /// Override rstdoc_dir of configuration
+    #[arg(long)]
+    rstdoc_dir: Option<String>,
+
+    /// Print the effective configuration (passwords redacted) and exit
+    #[arg(long, action)]
+    config_check: bool,
+
+    /// Re-generate configured outputs (rstdoc by default) and exit
     #[arg(long, action)] // TODO: ValueEnum
     generate_rstdoc: bool,
+
+    /// Re-generate pages of frozen years too, used with --generate-rstdoc
+    #[arg(long, action, requires = "generate_rstdoc")]
+    full: bool,
+
+    /// Include private letters, used with --generate-rstdoc (e.g. for a private build with --rstdoc-dir)
+    #[arg(long, action, requires = "generate_rstdoc")]
+    include_private: bool,
+
+    /// Print letters in date range like 2025/04 or 2025/04/01..2025/05/15 as a digest and exit
+    #[arg(long)]
+    digest: Option<DateRange>,
+
+    /// Report orphaned rstdoc files and empty letter files and exit
+    #[arg(long, action)]
+    prune: bool,
+
+    /// Report letters with the same content under different filenames and exit
+    #[arg(long, action)]
+    dedup: bool,
+
+    /// Remove files reported by --prune or --dedup and re-generate rstdoc
+    #[arg(long, action, requires = "fixable")]
+    fix: bool,
+
+    /// Import letters from mbox file or Maildir directory, re-generate rstdoc and exit
+    #[arg(long)]
+    import: Option<String>,
+
+    /// Export letters to gzip-compressed tarball for backup and exit, see --restore
+    #[arg(long)]
+    export: Option<String>,
+
+    /// Restore letters from tarball written by --export, re-generate rstdoc and exit
+    #[arg(long)]
+    restore: Option<String>,
+
+    /// Fetch and upsert unseen mails once and exit, exit code is non-zero if any mail failed
+    #[arg(long, action)]
+    once: bool,
+
+    /// Serve letters over HTTP for previewing, at address like 127.0.0.1:8000
+    #[cfg(feature = "serve")]
+    #[arg(long, num_args = 0..=1, default_missing_value = "127.0.0.1:8000")]
+    serve: Option<String>,
+
+    /// Open mailboxes read-only and log letters that would be written, nothing on server or disk is changed
+    #[arg(long, action)]
+    dry_run: bool,
+
+    /// Upsert already seen mails again (for example, after a parsing bug is fixed) and exit
+    #[arg(long, action)]
+    reprocess_seen: bool,
 }
 
-fn _main() -> Result<()> {
+fn _main() -> Result<ExitCode> {
     let args = &Args::parse();
-    logger::init(args.log_level)?;
+    logger::init(args.log_level.or(Some(logger::verbosity_to_level(args.verbose, args.quiet))), args.color)?;
     info!("🐟 ← 💌 ← 📬 ← 💌 ← 🦢");
 
-    let cfg = Cfg::load(&args.config)?;
+    let mut cfg = Cfg::load(&args.config)?;
+    cfg.archive.override_dirs(args.letter_dir.as_deref(), args.rstdoc_dir.as_deref());
+    if args.config_check {
+        print!("{}", cfg.to_redacted_toml()?);
+        return Ok(ExitCode::SUCCESS)
+    }
+
+    #[cfg(feature = "serve")]
+    if let Some(addr) = &args.serve {
+        let archive = Archive::load_read_only(cfg.archive)?;
+        loveletter::serve::serve(&archive, addr)?;
+        return Ok(ExitCode::SUCCESS)
+    }
 
-    let archive = Archive::load(cfg.archive)?;
+    let generate_docs = cfg.archive.generate_docs;
+    let archive = match args.dry_run {
+        true => Archive::load_read_only(cfg.archive)?.dry_run(true),
+        false => Archive::load(cfg.archive)?,
+    };
+    if let Some(path) = &args.import {
+        let raw_mails = RawMail::load_all(path)?;
+        let parsed_mails = raw_mails
+            .iter()
+            .filter_map(|m| m.parse().map_err(|e| error!("failed to parse raw mail: {}", e)).ok())
+            .collect::<Vec<_>>();
+        let letters = archive.import_letters(&parsed_mails)?;
+        info!("imported {} letters from {} mails", letters.len(), raw_mails.len());
+        archive.generate_all()?;
+        return Ok(ExitCode::SUCCESS)
+    }
+    if let Some(path) = &args.export {
+        archive.export(Path::new(path)).with_context(|| path.clone())?;
+        return Ok(ExitCode::SUCCESS)
+    }
+    if let Some(path) = &args.restore {
+        archive.restore(Path::new(path)).with_context(|| path.clone())?;
+        archive.generate_all()?;
+        return Ok(ExitCode::SUCCESS)
+    }
+    if args.prune {
+        let report = archive.prune(args.fix)?;
+        for p in report.orphaned_rstdocs.iter() {
+            info!("orphaned rstdoc file: {}", p.display());
+        }
+        for p in report.empty_letters.iter() {
+            info!("empty letter file: {}", p.display());
+        }
+        if !args.fix && !report.is_empty() {
+            info!("run with --fix to remove them");
+        }
+        return Ok(ExitCode::SUCCESS)
+    }
+    if args.dedup {
+        let duplicates = archive.dedup(args.fix)?;
+        for (duplicate, kept) in duplicates.iter() {
+            info!("duplicate letter: {} (same as {})", duplicate.display(), kept.display());
+        }
+        if !args.fix && !duplicates.is_empty() {
+            info!("run with --fix to remove them");
+        }
+        return Ok(ExitCode::SUCCESS)
+    }
+    if args.reprocess_seen {
+        for imap_cfg in cfg.imap.iter() {
+            let mut mailbox = Mailbox::open(imap_cfg.clone())?.read_only(args.dry_run);
+            let report = loveletter::reprocess_seen(&archive, &mut mailbox);
+            close_mailbox(mailbox, imap_cfg);
+            let report = report?;
+            info!("reprocessed mails of {}: {:?}", imap_cfg.username, report);
+        }
+        return Ok(ExitCode::SUCCESS)
+    }
+    if let Some(range) = &args.digest {
+        print!("{}", archive.digest(range)?);
+        return Ok(ExitCode::SUCCESS)
+    }
     if args.generate_rstdoc {
-        archive.generate_rstdoc()?;
-        return Ok(())
+        archive.generate_with(GenerateOptions { full: args.full, include_private: args.include_private })?;
+        return Ok(ExitCode::SUCCESS)
+    }
+    if args.once {
+        let mut report = CycleReport::default();
+        for imap_cfg in cfg.imap.iter() {
+            let mut mailbox = Mailbox::open(imap_cfg.clone())?.read_only(args.dry_run);
+            let limit = cfg.runtime.max_letters_per_cycle.map(|n| n.saturating_sub(report.fetched));
+            let result = loveletter::run_once(&archive, &mut mailbox, limit);
+            close_mailbox(mailbox, imap_cfg);
+            report += result?;
+        }
+        info!("summary: {}", report);
+        return Ok(report.exit_code())
+    }
+
+    // Catch up with letters added out-of-band (manual edits, import...).
+    if cfg.runtime.regenerate_on_startup && generate_docs {
+        info!("re-generating rstdoc on startup...");
+        match archive.generate_all() {
+            Ok(_) => info!("re-generated"),
+            Err(e) => error!("failed to generate rstdoc: {}", e),
+        }
     }
 
     let mut first_connect = true;
+    let mut status = Status::default(); // kept across reconnections
     loop {
         if first_connect {
             first_connect = false;
         } else {
-            info!("reconnect after {} seconds...", cfg.runtime.interval);
-            thread::sleep(Duration::from_secs(cfg.runtime.interval));
+            let d = jitter(cfg.runtime.reconnect_interval, cfg.runtime.jitter_ratio);
+            info!("reconnect after {:.1} seconds...", d.as_secs_f64());
+            thread::sleep(d);
         }
 
-        let mut mailbox = match Mailbox::open(cfg.imap.clone()) {
-            Ok(m) => m,
-            Err(e) => {
-                warn!("failed to open mailbox: {}", e);
-                continue;
-            },
-        };
+        // All accounts share the same archive, mails are fetched from mailboxes
+        // one by one so git operations are always serialized.
+        let mut mailboxes = Vec::new();
+        for imap_cfg in cfg.imap.iter() {
+            match Mailbox::open(imap_cfg.clone()) {
+                Ok(m) => mailboxes.push(m.read_only(args.dry_run)),
+                Err(e) => {
+                    warn!("failed to open mailbox of {}: {}", imap_cfg.username, e);
+                    let last_error = Some(format!("failed to open mailbox of {}: {}", imap_cfg.username, e));
+                    status.update(&CycleReport { last_error, ..CycleReport::default() });
+                    break;
+                },
+            };
+        }
+        if mailboxes.len() != cfg.imap.len() {
+            for (mailbox, imap_cfg) in mailboxes.into_iter().zip(cfg.imap.iter()) {
+                close_mailbox(mailbox, imap_cfg);
+            }
+            continue;
+        }
 
         let mut first_fetch = true;
-        loop {
+        'fetch: loop {
             if first_fetch {
                 first_fetch = false;
             } else {
-                info!("sleep for {} seconds...", cfg.runtime.interval);
-                thread::sleep(Duration::from_secs(cfg.runtime.interval));
+                let d = jitter(cfg.runtime.fetch_interval, cfg.runtime.jitter_ratio);
+                info!("sleep for {:.1} seconds...", d.as_secs_f64());
+                thread::sleep(d);
             }
 
-            let raw_mails = match mailbox.fetch_unseen() {
-                Ok(m) => m,
-                Err(e) => {
-                    warn!("failed to fetch unseen mails: {}", e);
-                    match e {
-                        imap::Error::ConnectionLost => break,
-                        _ => continue, // ignore for now
-                    }
-                },
-            };
-
-            let mut upserted = 0;
-            for raw_mail in raw_mails.iter() {
-                match raw_mail.parse() {
-                    Ok(parsed_mail) => match archive.upsert_letter(&parsed_mail) {
-                        Ok(_) => upserted += 1,
-                        Err(e) => error!("failed to upsert letter: {}", e),
+            // The limit is shared by all mailboxes.
+            let mut cycle = CycleReport::default();
+            for mailbox in mailboxes.iter_mut() {
+                let limit = cfg.runtime.max_letters_per_cycle.map(|n| n.saturating_sub(cycle.fetched));
+                match loveletter::run_once(&archive, mailbox, limit) {
+                    Ok(report) => {
+                        debug!("cycle done: {:?}", report);
+                        cycle += report;
+                    },
+                    Err(e) => {
+                        warn!("failed to fetch unseen mails: {}", e);
+                        cycle.last_error = Some(format!("failed to fetch unseen mails: {}", e));
+                        match e {
+                            Error::Imap(imap::Error::ConnectionLost) => {
+                                status.update(&cycle);
+                                break 'fetch
+                            },
+                            _ => continue, // ignore for now
+                        }
                     },
-                    Err(e) => error!("failed to parse raw mail: {}", e),
                 };
             }
-            if upserted == 0 {
-                info!("no letter upserted, skip rst generation");
-                continue;
-            }
+            status.update(&cycle);
+            debug!("status: {:?}", status);
+        }
 
-            match archive.generate_rstdoc() {
-                Ok(_) => (),
-                Err(e) => error!("failed to generate rstdoc: {}", e),
-            }
+        // Connection of one mailbox is lost, close all of them before reconnecting.
+        for (mailbox, imap_cfg) in mailboxes.into_iter().zip(cfg.imap.iter()) {
+            close_mailbox(mailbox, imap_cfg);
         }
     }
+}
 
-    // TODO: doesn't work
-    // info!("closing mailbox...");
-    // mailbox.close()?;
-    // info!("closed");
-    // Ok(())
+/// Close mailbox (logout and expunge pending mails), errors are logged only
+/// as the connection may have been lost.
+fn close_mailbox(mailbox: Mailbox, imap_cfg: &ImapCfg) {
+    info!("closing mailbox of {}...", imap_cfg.username);
+    match mailbox.close() {
+        Ok(_) => info!("closed"),
+        Err(e) => warn!("failed to close mailbox of {}: {}", imap_cfg.username, e),
+    }
 }
 
 fn main() -> ExitCode {
-    exit(_main())
+    match _main() {
+        Ok(code) => code,
+        r @ Err(_) => exit(r),
+    }
 }
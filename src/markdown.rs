@@ -0,0 +1,224 @@
+//! Minimal Markdown to HTML renderer for plain text mail bodies.
+//!
+//! Only the commonly used subset is supported: paragraphs, headings, block
+//! quotes, flat lists, fenced code blocks, thematic breaks, and inline
+//! emphasis, code spans and links. Everything else is rendered as text.
+
+use crate::html::escape;
+
+/// Render Markdown text to HTML.
+pub fn to_html(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut buf = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            i += 1;
+        } else if trimmed.starts_with("```") {
+            // Fenced code block, until the closing fence or the end.
+            let mut code = Vec::new();
+            i += 1;
+            while i < lines.len() && !lines[i].trim().starts_with("```") {
+                code.push(lines[i]);
+                i += 1;
+            }
+            i += 1;
+            buf.push_str(&format!("<pre><code>{}</code></pre>\n", escape(&(code.join("\n") + "\n"))));
+        } else if let Some((level, title)) = heading(trimmed) {
+            buf.push_str(&format!("<h{level}>{}</h{level}>\n", inline(title)));
+            i += 1;
+        } else if is_thematic_break(trimmed) {
+            buf.push_str("<hr />\n");
+            i += 1;
+        } else if trimmed.starts_with('>') {
+            let mut quote = Vec::new();
+            while i < lines.len() && lines[i].trim().starts_with('>') {
+                let x = lines[i].trim().trim_start_matches('>');
+                quote.push(x.strip_prefix(' ').unwrap_or(x));
+                i += 1;
+            }
+            buf.push_str(&format!("<blockquote>\n{}</blockquote>\n", to_html(&quote.join("\n"))));
+        } else if let Some(ordered) = list_item(trimmed).map(|(ordered, _)| ordered) {
+            let mut items: Vec<String> = Vec::new();
+            while i < lines.len() {
+                let line = lines[i];
+                match list_item(line.trim()) {
+                    Some((o, item)) if o == ordered => items.push(item.to_owned()),
+                    // Lazy continuation of the last item.
+                    None if !line.trim().is_empty() && line.starts_with(' ') => {
+                        let last = items.last_mut().unwrap();
+                        last.push('\n');
+                        last.push_str(line.trim());
+                    },
+                    _ => break,
+                }
+                i += 1;
+            }
+            let tag = if ordered { "ol" } else { "ul" };
+            buf.push_str(&format!("<{}>\n", tag));
+            for item in items {
+                buf.push_str(&format!("<li>{}</li>\n", inline(&item)));
+            }
+            buf.push_str(&format!("</{}>\n", tag));
+        } else {
+            // Paragraph, until a blank line or the beginning of another block.
+            let mut para = Vec::new();
+            while i < lines.len() {
+                let line = lines[i];
+                let trimmed = line.trim();
+                let interrupted = trimmed.is_empty()
+                    || trimmed.starts_with("```")
+                    || trimmed.starts_with('>')
+                    || heading(trimmed).is_some()
+                    || is_thematic_break(trimmed)
+                    || (!para.is_empty() && list_item(trimmed).is_some());
+                if !para.is_empty() && interrupted {
+                    break;
+                }
+                // Line ending with two spaces is a hard line break.
+                para.push(match line.ends_with("  ") {
+                    true => inline(trimmed) + "<br />",
+                    false => inline(trimmed),
+                });
+                i += 1;
+            }
+            buf.push_str(&format!("<p>{}</p>\n", para.join("\n")));
+        }
+    }
+    buf
+}
+
+/// Parse ATX heading like "## TITLE", returns (level, title).
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let title = &line[level..];
+    match (1..=6).contains(&level) && (title.is_empty() || title.starts_with(' ')) {
+        true => Some((level, title.trim().trim_end_matches('#').trim_end())),
+        false => None,
+    }
+}
+
+fn is_thematic_break(line: &str) -> bool {
+    let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    line.len() >= 3 && ["-", "*", "_"].iter().any(|x| line.chars().all(|c| c.to_string() == *x))
+}
+
+/// Parse list item like "- ITEM" or "1. ITEM", returns (ordered, item).
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = line.strip_prefix(bullet) {
+            return Some((false, item.trim()));
+        }
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = &line[digits..];
+    match digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ")) {
+        true => Some((true, rest[2..].trim())),
+        false => None,
+    }
+}
+
+/// Render inline elements: code spans, links, strong and emphasis.
+fn inline(text: &str) -> String {
+    let mut buf = String::new();
+    let mut rest = text;
+    let mut prev: Option<char> = None;
+    while let Some(c) = rest.chars().next() {
+        let rendered = match c {
+            '`' => rest[1..].find('`').map(|j| {
+                (format!("<code>{}</code>", escape(&rest[1..j + 1])), j + 2)
+            }),
+            '[' => link(rest),
+            // Intraword underscores are not emphasis like "snake_case".
+            '*' | '_' if c == '*' || !prev.is_some_and(char::is_alphanumeric) => {
+                let strong = if c == '*' { "**" } else { "__" };
+                let delim = if rest.starts_with(strong) { strong } else { &rest[..1] };
+                let tag = if delim.len() == 2 { "strong" } else { "em" };
+                let inner = &rest[delim.len()..];
+                inner.find(delim)
+                    .filter(|&j| j > 0 && !inner.starts_with(' '))
+                    .map(|j| (format!("<{tag}>{}</{tag}>", inline(&inner[..j])), delim.len() * 2 + j))
+            },
+            _ => None,
+        };
+        match rendered {
+            Some((html, len)) => {
+                buf.push_str(&html);
+                prev = rest[..len].chars().next_back();
+                rest = &rest[len..];
+            },
+            None => {
+                buf.push_str(&escape(&rest[..c.len_utf8()]));
+                prev = Some(c);
+                rest = &rest[c.len_utf8()..];
+            },
+        }
+    }
+    buf
+}
+
+/// Render link like "[TEXT](URL)", returns (html, length of markdown).
+fn link(s: &str) -> Option<(String, usize)> {
+    let text_end = s.find("](")?;
+    let url_end = text_end + 2 + s[text_end + 2..].find(')')?;
+    let text = &s[1..text_end];
+    let url = s[text_end + 2..url_end].trim();
+    Some((format!("<a href=\"{}\">{}</a>", escape(url), inline(text)), url_end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_html() {
+        assert_eq!(
+            to_html("\
+# 生日快乐
+
+今天是 **你的** 生日，
+我们去 [公园](https://example.com/?a=1&b=2) 吧。
+
+- 蛋糕
+- *鲜花*
+  和礼物
+
+1. 第一
+2. 第二
+
+> 引用 `代码 <b>`
+
+```
+fn main() {}
+```
+---
+a < b  
+c"),
+            "\
+<h1>生日快乐</h1>
+<p>今天是 <strong>你的</strong> 生日，
+我们去 <a href=\"https://example.com/?a=1&amp;b=2\">公园</a> 吧。</p>
+<ul>
+<li>蛋糕</li>
+<li><em>鲜花</em>
+和礼物</li>
+</ul>
+<ol>
+<li>第一</li>
+<li>第二</li>
+</ol>
+<blockquote>
+<p>引用 <code>代码 &lt;b&gt;</code></p>
+</blockquote>
+<pre><code>fn main() {}
+</code></pre>
+<hr />
+<p>a &lt; b<br />
+c</p>
+"
+        );
+        assert_eq!(to_html("2 * 3 * 4 and snake_case_name"), "<p>2 * 3 * 4 and snake_case_name</p>\n");
+    }
+}
@@ -0,0 +1,131 @@
+//! Read-only HTTP server for previewing letters locally, enabled by the
+//! `serve` feature. Pages are rendered from letter files on every request, the
+//! stored HTML content is used as it is.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+
+use crate::html::escape;
+use crate::letter::{Archive, LoveLetter};
+
+const LETTERS_PREFIX: &str = "/letters/";
+
+/// Serve letters of archive on address like "127.0.0.1:8000", never returns
+/// unless binding fails.
+pub fn serve(archive: &Archive, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving letters at http://{}/ ...", listener.local_addr()?);
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|s| handle(archive, s)) {
+            warn!("failed to handle request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle(archive: &Archive, mut stream: TcpStream) -> io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    debug!("request: {}", request_line.trim_end());
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(archive, path),
+        _ => (405, page("Method Not Allowed", "<p>Only GET is supported.</p>")),
+    };
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Returns (status code, HTML).
+fn route(archive: &Archive, path: &str) -> (u16, String) {
+    let letters = match archive.iter_letters() {
+        Ok(x) => x.filter_map(|(_, letter)| letter.ok()),
+        Err(e) => return (500, page("Error", &format!("<p>{}</p>", escape(&format!("{:#}", e))))),
+    };
+    if path == "/" {
+        let items: String = letters
+            .map(|x| format!("<li><a href=\"{}{}\">{}</a></li>\n", LETTERS_PREFIX, x.anchor(), escape(&heading(&x))))
+            .collect();
+        return (200, page("💌 Love Letters", &format!("<ul>\n{}</ul>", items)));
+    }
+    let found = path
+        .strip_prefix(LETTERS_PREFIX)
+        .and_then(|anchor| letters.into_iter().find(|x| x.anchor() == anchor));
+    match found {
+        Some(x) => (200, page(&heading(&x), &format!(
+            "<p>{}</p>\n<div>\n{}\n</div>\n<p><a href=\"/\">← All letters</a></p>",
+            escape(x.author()),
+            x.content()
+        ))),
+        None => (404, page("Not Found", "<p>No such letter.</p>")),
+    }
+}
+
+fn heading(letter: &LoveLetter) -> String {
+    match letter.title() {
+        Some(title) => format!("{}: {}", letter.date(), title),
+        None => letter.date().to_string(),
+    }
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n{1}\n</body>\n</html>\n",
+        escape(title),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use tempfile::tempdir;
+    use crate::cfg::Cfg;
+
+    #[test]
+    fn test_serve() {
+        let mut cfg = Cfg::load("./test_data/config.toml").unwrap().archive;
+        let tmp_letter_dir = tempdir().unwrap();
+        let tmp_rstdoc_dir = tempdir().unwrap();
+        fs::copy("./test_data/2025-04-03.toml", tmp_letter_dir.path().join("2025-04-03.toml")).unwrap();
+        cfg.letter_dir = tmp_letter_dir.path().to_str().unwrap().to_owned();
+        cfg.rstdoc_dir = tmp_rstdoc_dir.path().to_str().unwrap().to_owned();
+        let archive = Archive::load_read_only(cfg).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            handle(&archive, listener.accept().unwrap().0).unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            resp
+        };
+
+        let index = get("/");
+        assert!(index.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(index.contains("<a href=\"/letters/loveletter-2025-04-03-882b1b23\">2025-04-03: 测试数据</a>"));
+        let letter = get("/letters/loveletter-2025-04-03-882b1b23");
+        assert!(letter.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(letter.contains("<div>张同学 我们这个 I 人交朋友的项目还有效咩</div>"));
+        assert!(get("/letters/loveletter-1998-01-28").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,112 @@
+//! Transforms of letter content, composed into an ordered pipeline by
+//! `content_transforms` in configuration.
+
+use anyhow::{bail, Result};
+use log::trace;
+
+use crate::html;
+
+/// Transform of HTML content of letter.
+pub trait ContentTransform {
+    /// Name used in configuration.
+    fn name(&self) -> &str;
+    fn apply(&self, content: &str) -> String;
+}
+
+/// Remove scripts, styles, embedded objects and event handlers.
+pub struct Sanitize;
+
+impl ContentTransform for Sanitize {
+    fn name(&self) -> &str { "sanitize" }
+    fn apply(&self, content: &str) -> String { html::sanitize(content) }
+}
+
+/// Remove quoted text of replies.
+pub struct StripQuotes;
+
+impl ContentTransform for StripQuotes {
+    fn name(&self) -> &str { "strip_quotes" }
+    fn apply(&self, content: &str) -> String { html::remove_elements(content, &["blockquote"]) }
+}
+
+/// Trim leading and trailing empty elements, see [`html::trim_empty_blocks`].
+pub struct TrimEmptyHtml;
+
+impl ContentTransform for TrimEmptyHtml {
+    fn name(&self) -> &str { "trim_empty_html" }
+    fn apply(&self, content: &str) -> String { html::trim_empty_blocks(content) }
+}
+
+/// Wrap content in a single `<div>`, so that it is always one block.
+pub struct Wrap;
+
+impl ContentTransform for Wrap {
+    fn name(&self) -> &str { "wrap" }
+    fn apply(&self, content: &str) -> String { format!("<div>{}</div>", content) }
+}
+
+/// Built-in transform of name.
+pub fn builtin(name: &str) -> Option<Box<dyn ContentTransform>> {
+    let transforms: [Box<dyn ContentTransform>; 4] = [
+        Box::new(Sanitize),
+        Box::new(StripQuotes),
+        Box::new(TrimEmptyHtml),
+        Box::new(Wrap),
+    ];
+    transforms.into_iter().find(|x| x.name() == name)
+}
+
+/// Transforms applied in order.
+#[derive(Default)]
+pub struct Pipeline(Vec<Box<dyn ContentTransform>>);
+
+impl Pipeline {
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<Pipeline> {
+        let mut pipeline = Pipeline::default();
+        for name in names.iter().map(AsRef::as_ref) {
+            match builtin(name) {
+                Some(x) => pipeline.push(x),
+                None => bail!("unknown content transform: {}", name),
+            }
+        }
+        Ok(pipeline)
+    }
+
+    pub fn push(&mut self, transform: Box<dyn ContentTransform>) {
+        self.0.push(transform)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|x| x.name() == name)
+    }
+
+    pub fn apply(&self, content: &str) -> String {
+        self.0.iter().fold(content.to_owned(), |content, x| {
+            let transformed = x.apply(&content);
+            trace!("applied content transform {} ({} -> {} bytes)", x.name(), content.len(), transformed.len());
+            transformed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        let content = "<div><br></div><script>evil()</script><div onclick=\"evil()\">foo</div>\n<blockquote>bar</blockquote><div> </div>";
+        assert_eq!(
+            Pipeline::from_names(&["sanitize", "trim_empty_html", "wrap"]).unwrap().apply(content),
+            "<div><div>foo</div>\n<blockquote>bar</blockquote></div>"
+        );
+        // Order matters: the newline before quote is trimmed only after the
+        // quote is stripped.
+        let pipeline = Pipeline::from_names(&["sanitize", "trim_empty_html", "strip_quotes"]).unwrap();
+        assert_eq!(pipeline.apply(content), "<div>foo</div>\n");
+        let pipeline = Pipeline::from_names(&["sanitize", "strip_quotes", "trim_empty_html"]).unwrap();
+        assert_eq!(pipeline.apply(content), "<div>foo</div>");
+        assert_eq!(Pipeline::default().apply(content), content);
+        assert!(Pipeline::from_names(&["sanitize", "rot13"]).is_err());
+    }
+}
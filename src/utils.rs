@@ -6,15 +6,47 @@ pub mod logger {
 
     static mut LEVEL: Level = Level::Info;
 
+    /// Whether to colorize terminal output.
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub enum Color {
+        Always,
+        #[default]
+        Auto,
+        Never,
+    }
+
+    impl std::str::FromStr for Color {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Color, String> {
+            match s {
+                "always" => Ok(Color::Always),
+                "auto" => Ok(Color::Auto),
+                "never" => Ok(Color::Never),
+                _ => Err(format!("invalid color choice {:?}, expect always, auto or never", s)),
+            }
+        }
+    }
+
+    impl From<Color> for ColorChoice {
+        fn from(color: Color) -> ColorChoice {
+            match color {
+                Color::Always => ColorChoice::Always,
+                Color::Auto => ColorChoice::Auto,
+                Color::Never => ColorChoice::Never,
+            }
+        }
+    }
+
     // Priv: args > env.
-    pub fn init(level: Option<Level>) -> Result<()> {
+    pub fn init(level: Option<Level>, color: Color) -> Result<()> {
         let level = level.unwrap_or(Level::Info);
         CombinedLogger::init(
             vec![TermLogger::new(
                 level.to_level_filter(),
                 Config::default(),
                 TerminalMode::Mixed,
-                ColorChoice::Auto,
+                color.into(),
             )],
         )?;
 
@@ -28,6 +60,17 @@ pub mod logger {
     pub fn level() -> Level {
         unsafe { LEVEL }
     }
+
+    /// Map count of `-v` and `-q` flags to log level, info by default.
+    pub fn verbosity_to_level(verbose: u8, quiet: u8) -> Level {
+        match 2 + verbose as i32 - quiet as i32 {
+            i32::MIN..=0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
 }
 use core::fmt;
 use log::error;
@@ -51,11 +94,21 @@ pub fn exit<T, E: fmt::Display+fmt::Debug>(r: Result<T, E>) -> ExitCode {
     }
 }
 
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
 use std::iter::IntoIterator;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use log::warn;
+use unicode_width::UnicodeWidthChar;
 use email_address::EmailAddress;
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EmailAddressList(Vec<EmailAddress>);
 
 impl EmailAddressList {
@@ -82,6 +135,199 @@ impl IntoIterator for EmailAddressList {
     }
 }
 
+impl FromIterator<EmailAddress> for EmailAddressList {
+    fn from_iter<I: IntoIterator<Item = EmailAddress>>(iter: I) -> Self {
+        EmailAddressList(iter.into_iter().collect())
+    }
+}
+
+impl From<Vec<EmailAddress>> for EmailAddressList {
+    fn from(addrs: Vec<EmailAddress>) -> Self {
+        EmailAddressList(addrs)
+    }
+}
+
+/// Write file atomically: the contents are written to a temporary file in the
+/// same directory first and then renamed to the destination, so a partially
+/// written file is never observed even if we crash in the middle.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    write_atomic_with(path, |f| f.write_all(contents.as_ref()))
+}
+
+fn write_atomic_with<P, F>(path: P, write: F) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    let path = path.as_ref();
+    let filename = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no filename"))?;
+    let mut tmp_filename = OsString::from(".");
+    tmp_filename.push(filename);
+    tmp_filename.push(format!(".{}.tmp", process::id()));
+    let tmp_path = path.with_file_name(tmp_filename);
+
+    let res = fs::File::create(&tmp_path).and_then(|mut f| {
+        write(&mut f)?;
+        f.sync_all()
+    });
+    match res.and_then(|_| fs::rename(&tmp_path, path)) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(e)
+        },
+    }
+}
+
+/// Run filesystem operation at most `retry` times with exponential backoff
+/// (100ms, 200ms, 400ms...), network filesystems may fail transiently.
+/// Permanent errors such as ENOSPC and EACCES are returned immediately.
+pub fn retry_fs<T, F: FnMut() -> io::Result<T>>(retry: i32, mut op: F) -> io::Result<T> {
+    let mut i = 0;
+    loop {
+        i += 1;
+        match op() {
+            Err(e) if i < retry && is_transient(&e) => {
+                let backoff = Duration::from_millis(100 << (i - 1).min(6));
+                warn!("filesystem operation failed: {} ({}/{}), retry after {:?}", e, i, retry, backoff);
+                thread::sleep(backoff);
+            },
+            res => return res,
+        }
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::Deadlock
+    )
+}
+
+/// Stable 64-bit FNV-1a hash, unlike [`std::hash::DefaultHasher`], its output
+/// never changes between Rust releases so it is safe to be persisted.
+pub fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+/// Lowercase the string and replace runs of non-alphanumeric chars with a single `-`.
+pub fn slugify(s: &str) -> String {
+    let mut slug = String::new();
+    for c in s.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+/// Make filename safe to be saved: directories are dropped, runs of chars
+/// other than alphanumerics, '-' and '_' in stem are replaced with a single
+/// `_`, and the extension is kept in lowercase.
+pub fn sanitize_filename(s: &str) -> String {
+    let name = s.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() && ext.chars().all(char::is_alphanumeric) => (stem, Some(ext)),
+        _ => (name, None),
+    };
+    let mut safe = String::new();
+    for c in stem.chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            safe.push(c);
+        } else if !safe.is_empty() && !safe.ends_with('_') {
+            safe.push('_');
+        }
+    }
+    let mut safe = safe.trim_end_matches('_').to_owned();
+    if safe.is_empty() {
+        safe.push_str("untitled");
+    }
+    match ext {
+        Some(ext) => format!("{}.{}", safe, ext.to_lowercase()),
+        None => safe,
+    }
+}
+
+/// Suffix filename with "-2", "-3"... before its extension until it is not
+/// in `taken`, the result is added to `taken`.
+pub fn unique_filename(name: &str, taken: &mut HashSet<String>) -> String {
+    let mut unique = name.to_owned();
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem, format!(".{}", ext)),
+        None => (name, String::new()),
+    };
+    let mut i = 1;
+    while taken.contains(&unique) {
+        i += 1;
+        unique = format!("{}-{}{}", stem, i, ext);
+    }
+    taken.insert(unique.clone());
+    unique
+}
+
+/// Estimate width of text as docutils does when checking title underline:
+/// East Asian wide chars count 2, combining marks count 0 and all other chars
+/// count 1 -- including ZWJ and variation selectors, which are zero width
+/// for `unicode-width`.
+pub fn docutils_width(s: &str) -> usize {
+    s.chars()
+        .map(|c| match c.width() {
+            Some(0) | None if is_combining(c) => 0,
+            Some(0) | None => 1,
+            Some(w) => w,
+        })
+        .sum()
+}
+
+/// Char has nonzero canonical combining class, enclosing marks like U+20E3
+/// (keycap) don't.
+fn is_combining(c: char) -> bool {
+    matches!(c,
+        '\u{300}'..='\u{36f}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{20d0}'..='\u{20dc}'
+        | '\u{20e1}'
+        | '\u{20e5}'..='\u{20f0}'
+        | '\u{3099}'..='\u{309a}'
+        | '\u{fe20}'..='\u{fe2f}')
+}
+
+/// Randomize interval by ±ratio of it, so that daemons restarted at the same
+/// time don't reconnect at the same time.
+pub fn jitter(secs: u64, ratio: f64) -> Duration {
+    let offset = secs as f64 * ratio * (fastrand::f64() * 2.0 - 1.0);
+    Duration::from_secs_f64((secs as f64 + offset).max(0.0))
+}
+
+/// Format size in bytes like "509 B" or "2.0 KiB".
+pub fn human_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB"] {
+        if size < 1024.0 {
+            return match unit {
+                "B" => format!("{} B", bytes),
+                _ => format!("{:.1} {}", size, unit),
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} GiB", size)
+}
+
 #[cfg(test)]
 mod test_main {
     use super::*;
@@ -91,6 +337,146 @@ mod test_main {
 
     #[ctor]
     fn global_init() {
-        logger::init(Some(Level::Debug));
+        logger::init(Some(Level::Debug), logger::Color::Auto);
+    }
+
+    #[test]
+    fn test_verbosity_to_level() {
+        assert_eq!(logger::verbosity_to_level(0, 0), Level::Info);
+        assert_eq!(logger::verbosity_to_level(1, 0), Level::Debug);
+        assert_eq!(logger::verbosity_to_level(3, 0), Level::Trace);
+        assert_eq!(logger::verbosity_to_level(0, 1), Level::Warn);
+        assert_eq!(logger::verbosity_to_level(0, 5), Level::Error);
+        assert_eq!(logger::verbosity_to_level(1, 1), Level::Info);
+    }
+
+    #[test]
+    fn test_email_address_list() {
+        let addr = |s: &str| s.parse::<EmailAddress>().unwrap();
+        let list: EmailAddressList = ["哥哥 <gege@example.com>", "妹妹 <meimei@example.com>"]
+            .into_iter()
+            .map(addr)
+            .collect();
+        assert_eq!(list.find(&addr("meimei@example.com")).unwrap().display_part(), "妹妹");
+        assert!(list.find(&addr("didi@example.com")).is_none());
+
+        let list = EmailAddressList::from(vec![addr("gege@example.com")]);
+        assert!(list.find(&addr("Gege <gege@example.com>")).is_some());
+        assert!(EmailAddressList::default().find(&addr("gege@example.com")).is_none());
+    }
+
+    #[test]
+    fn test_color() {
+        use simplelog::ColorChoice;
+
+        assert_eq!("always".parse::<logger::Color>().map(ColorChoice::from), Ok(ColorChoice::Always));
+        assert_eq!("auto".parse::<logger::Color>().map(ColorChoice::from), Ok(ColorChoice::Auto));
+        assert_eq!("never".parse::<logger::Color>().map(ColorChoice::from), Ok(ColorChoice::Never));
+        assert!("sometimes".parse::<logger::Color>().is_err());
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("letter.toml");
+        write_atomic(&path, "foo").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "foo");
+
+        // Simulate a failure in the middle of writing.
+        let res = write_atomic_with(&path, |f| {
+            f.write_all(b"ba")?;
+            Err(io::Error::other("disk is full"))
+        });
+        assert!(res.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "foo");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1); // no leftover temporary file
+    }
+
+    #[test]
+    fn test_retry_fs() {
+        // Fail transiently twice.
+        let mut n = 0;
+        let res = retry_fs(3, || {
+            n += 1;
+            match n {
+                1 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                2 => Err(io::Error::from(io::ErrorKind::ResourceBusy)),
+                _ => Ok(n),
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        // Give up after retry times.
+        let mut n = 0;
+        let res: io::Result<()> = retry_fs(2, || {
+            n += 1;
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        });
+        assert!(res.is_err());
+        assert_eq!(n, 2);
+
+        // Permanent error is never retried.
+        let mut n = 0;
+        let res: io::Result<()> = retry_fs(3, || {
+            n += 1;
+            Err(io::Error::from(io::ErrorKind::StorageFull))
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(slugify("  妹妹 生日快乐 "), "妹妹-生日快乐");
+        assert_eq!(slugify("!?"), "");
+    }
+
+    #[test]
+    fn test_docutils_width() {
+        assert_eq!(docutils_width("2025/04/03: hello"), 17);
+        assert_eq!(docutils_width("测试数据"), 8);
+        assert_eq!(docutils_width("cafe\u{301}"), 4);
+        assert_eq!(docutils_width("\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}"), 8);
+        assert_eq!(docutils_width("\u{2764}\u{fe0f}"), 2);
+        assert_eq!(docutils_width("1\u{fe0f}\u{20e3}"), 3);
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("photo.png"), "photo.png");
+        assert_eq!(sanitize_filename("我的 照片 (1).JPG"), "我的_照片_1.jpg");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\tmp\\a?b*.txt"), "a_b.txt");
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("???.pdf"), "untitled.pdf");
+        assert_eq!(sanitize_filename(""), "untitled");
+
+        let mut taken = HashSet::new();
+        assert_eq!(unique_filename("笔记.pdf", &mut taken), "笔记.pdf");
+        assert_eq!(unique_filename("笔记.pdf", &mut taken), "笔记-2.pdf");
+        assert_eq!(unique_filename("笔记.pdf", &mut taken), "笔记-3.pdf");
+        assert_eq!(unique_filename("untitled", &mut taken), "untitled");
+        assert_eq!(unique_filename("untitled", &mut taken), "untitled-2");
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(509), "509 B");
+        assert_eq!(human_size(2056), "2.0 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 + 1), "5.0 MiB");
+        assert_eq!(human_size(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_jitter() {
+        assert_eq!(jitter(60, 0.0), Duration::from_secs(60));
+        for _ in 0..1000 {
+            let d = jitter(60, 0.1);
+            assert!(Duration::from_secs(54) <= d && d <= Duration::from_secs(66), "{:?}", d);
+            assert!(jitter(10, 1.0) <= Duration::from_secs(20));
+        }
+        assert_eq!(jitter(0, 0.5), Duration::ZERO);
     }
 }
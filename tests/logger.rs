@@ -0,0 +1,14 @@
+//! Logger is global and initialized once per test binary, unit tests
+//! initialize it at debug level, so trace is tested here.
+
+use log::{Level, LevelFilter};
+use loveletter::utils::logger;
+
+#[test]
+fn test_trace_level() {
+    let level: Level = "trace".parse().unwrap(); // as --log-level does
+    logger::init(Some(level), logger::Color::Never).unwrap();
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+    assert!(log::log_enabled!(Level::Trace));
+    assert_eq!(logger::level(), Level::Trace);
+}